@@ -0,0 +1,84 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use read_blk::bitcoin::block::Header;
+use read_blk::bitcoin::constants::genesis_block;
+use read_blk::bitcoin::Network;
+use read_blk::block_cache::{BlockCache, DEFAULT_DEPTH_THRESHOLD};
+use std::hint::black_box;
+
+// add_block() + remove_block_if_ready() throughput of a BlockCache<Header>, for chains of N blocks
+// with stale branches at a few fork rates. cargo bench --bench staging [--features fast-hash]
+
+// Headers of a chain of n blocks off genesis in the order they are added, with a stale branch of
+// 1 or 2 blocks forking off the previous block at a fork_rate fraction of the heights
+fn synthetic_chain(n: usize, fork_rate: f64) -> Vec<Header> {
+    // xorshift, so that every run adds the same tree
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    let mut next_random = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state >> 11) as f64 / (1u64 << 53) as f64
+    };
+    let mut prev_header = genesis_block(Network::Bitcoin).header;
+    let mut headers = vec![prev_header];
+    for height in 1..n {
+        let mut header = prev_header;
+        header.prev_blockhash = prev_header.block_hash();
+        header.time = prev_header.time + 600;
+        if next_random() < fork_rate {
+            let mut stale_header = header;
+            for stale_len in 0..1 + (next_random() < 0.5) as u32 {
+                stale_header.nonce = u32::MAX - height as u32;
+                stale_header.time += stale_len;
+                headers.push(stale_header);
+                stale_header.prev_blockhash = stale_header.block_hash();
+            }
+        }
+        headers.push(header);
+        prev_header = header;
+    }
+    headers
+}
+
+// adds the headers in order, migrating a block whenever one is ready, then drains the rest.
+// Returns the number of blocks migrated
+fn stage(headers: Vec<Header>) -> usize {
+    let mut block_cache: BlockCache<Header> = BlockCache::new();
+    let mut migrated_cnt = 0;
+    for header in headers {
+        block_cache.add_block(header);
+        migrated_cnt += block_cache
+            .remove_block_if_ready(DEFAULT_DEPTH_THRESHOLD)
+            .is_some() as usize;
+    }
+    migrated_cnt + block_cache.drain_ready(0).len()
+}
+
+fn bench_staging(c: &mut Criterion) {
+    let mut group = c.benchmark_group("staging");
+    // an iteration of the largest chain takes a while
+    group.sample_size(10);
+    for fork_rate in [0.0, 0.01, 0.1] {
+        for n in [1_000, 10_000, 100_000] {
+            let headers = synthetic_chain(n, fork_rate);
+            // the stale blocks are dropped, the chain migrates
+            assert_eq!(stage(headers.clone()), n);
+            group.throughput(Throughput::Elements(headers.len() as u64));
+            group.bench_with_input(
+                BenchmarkId::new(format!("fork rate {}", fork_rate), n),
+                &headers,
+                |b, headers| {
+                    b.iter_batched(
+                        || headers.clone(),
+                        |headers| black_box(stage(headers)),
+                        BatchSize::LargeInput,
+                    )
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_staging);
+criterion_main!(benches);
@@ -0,0 +1,32 @@
+#![no_main]
+
+/*
+A blk file of any bytes, read from a slice and from a stream with every framing the importer has, and the blocks
+that import summarized. The read may fail but must not panic or allocate a corrupt frame length. Run it with
+cargo fuzz run read_frames, the seeds in corpus/read_frames are a short mainnet blk file with zero padding,
+a truncated frame, garbage between frames and a coinbase without inputs.
+*/
+
+use libfuzzer_sys::fuzz_target;
+use read_blk::prelude::{Importer, Network};
+
+fuzz_target!(|file_bytes: &[u8]| {
+    for (skip_corrupt, lazy_decode) in [(false, false), (true, false), (true, true)] {
+        let mut importer = Importer::new("", 0);
+        importer.set_reorg_depth(1);
+        importer.set_skip_corrupt(skip_corrupt);
+        importer.set_lazy_decode(lazy_decode);
+        importer.set_summaries(true);
+        let _ = importer.read_blocks_slice(file_bytes);
+        let _ = importer.drain_ready(0);
+
+        let mut importer = Importer::new("", 0);
+        importer.set_network(Network::Bitcoin);
+        importer.set_skip_corrupt(skip_corrupt);
+        importer.set_lazy_decode(lazy_decode);
+        importer.set_summaries(true);
+        let _ = importer.read_blocks_slice(file_bytes);
+        let _ = importer.read_blocks_streaming(file_bytes);
+        let _ = importer.drain_ready(0);
+    }
+});
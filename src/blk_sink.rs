@@ -0,0 +1,131 @@
+use crate::logging::{error, info};
+use crate::sink::{BlockMeta, BlockSink};
+use bitcoin::consensus::serialize;
+use bitcoin::{Block, Network};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// bitcoin core's MAX_BLOCKFILE_SIZE, a blk file is rolled over before it would grow past it
+pub const MAX_BLK_FILE_SIZE: u64 = 128 * 1024 * 1024;
+
+// Writes the imported blocks to blk?????.dat files in dir, framed like bitcoin core's: the network magic,
+// the block length as a u32 little endian, then the block. The blocks are in chain order and losing blocks
+// are never imported, so the files are a de-forked copy of the chain that the importer reads back in order.
+// Unlike bitcoin core's, the files are not preallocated, so they have no zero padding.
+pub struct BlkFileSink {
+    dir: PathBuf,
+    network: Network,
+    max_file_size: u64,
+    // number of the file being written, None before the first block
+    file_num: Option<u32>,
+    writer: Option<BufWriter<File>>,
+    // bytes written to the current file
+    file_size: u64,
+}
+
+impl BlkFileSink {
+    // dir is created if it doesn't exist, the files in it are overwritten as they are written
+    pub fn create(dir: impl AsRef<Path>, network: Network) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir.as_ref())?;
+        Ok(BlkFileSink {
+            dir: dir.as_ref().to_path_buf(),
+            network,
+            max_file_size: MAX_BLK_FILE_SIZE,
+            file_num: None,
+            writer: None,
+            file_size: 0,
+        })
+    }
+
+    // a block bigger than max_file_size still gets a file of its own
+    pub fn set_max_file_size(&mut self, max_file_size: u64) {
+        self.max_file_size = max_file_size;
+    }
+
+    fn write_block(&mut self, block: &Block) -> std::io::Result<()> {
+        let bytes = serialize(block);
+        let frame_len = 8 + bytes.len() as u64;
+        if self.writer.is_none() || self.file_size + frame_len > self.max_file_size {
+            self.next_file()?;
+        }
+        let writer = self.writer.as_mut().expect("writer expected");
+        writer.write_all(&self.network.magic().to_bytes())?;
+        writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+        writer.write_all(&bytes)?;
+        self.file_size += frame_len;
+        Ok(())
+    }
+
+    fn next_file(&mut self) -> std::io::Result<()> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.flush()?;
+        }
+        let file_num = self.file_num.map_or(0, |file_num| file_num + 1);
+        let path = self.dir.join(format!("blk{:05}.dat", file_num));
+        info!("writing blocks to {}", path.display());
+        self.writer = Some(BufWriter::new(File::create(path)?));
+        self.file_num = Some(file_num);
+        self.file_size = 0;
+        Ok(())
+    }
+}
+
+impl BlockSink for BlkFileSink {
+    fn on_block(&mut self, block: &Block, _meta: &BlockMeta) {
+        if let Err(err) = self.write_block(block) {
+            error!("blk: can't write block {:?}: {}", block.block_hash(), err);
+        }
+    }
+}
+
+impl Drop for BlkFileSink {
+    fn drop(&mut self) {
+        if let Some(writer) = &mut self.writer {
+            if let Err(err) = writer.flush() {
+                error!("blk: can't flush: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::importer::Importer;
+    use bitcoin::constants::genesis_block;
+
+    #[test]
+    fn test_blk_file_sink() {
+        let genesis = genesis_block(Network::Testnet);
+        let mut next_block = genesis.clone();
+        next_block.header.prev_blockhash = genesis.block_hash();
+        let dir = std::env::temp_dir().join(format!("read-blk-blk-sink-{}", std::process::id()));
+        let mut sink = BlkFileSink::create(&dir, Network::Testnet).unwrap();
+        // the genesis block is 285 bytes, a file only has room for one frame
+        sink.set_max_file_size(300);
+        let meta = BlockMeta {
+            height: 0,
+            file_num: 0,
+            summary: None,
+            op_returns: Vec::new(),
+        };
+        sink.on_block(&genesis, &meta);
+        sink.on_block(&next_block, &meta);
+        drop(sink);
+
+        let file_bytes = std::fs::read(dir.join("blk00000.dat")).unwrap();
+        assert_eq!(file_bytes.len(), 8 + 285);
+        assert_eq!(file_bytes[..4], Network::Testnet.magic().to_bytes());
+        assert_eq!(file_bytes[4..8], 285u32.to_le_bytes());
+        assert_eq!(file_bytes[8..], serialize(&genesis));
+
+        // the importer reads the files back
+        let mut importer = Importer::new(&dir, 0);
+        importer.read_files().unwrap();
+        importer.drain_ready(0).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(importer.network(), Some(Network::Testnet));
+        assert_eq!(importer.tip(), Some((next_block.block_hash(), 1)));
+    }
+}
@@ -1,361 +1,1959 @@
-use bitcoin::BlockHash;
-use std::collections::{HashMap, HashSet};
-use std::str::FromStr;
+use crate::logging::{debug, error, info, trace, warn};
+use bitcoin::block::Header;
+use bitcoin::consensus::{deserialize, encode, serialize, Decodable};
+use bitcoin::constants::genesis_block;
+use bitcoin::hashes::Hash;
+use bitcoin::{BlockHash, Network, Work};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+#[cfg(feature = "serde")]
+use std::fs::File;
+#[cfg(feature = "serde")]
+use std::io::{BufReader, BufWriter, Write};
+#[cfg(feature = "serde")]
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 /*
 Before a bitcoin::block::Block can be added to the main chain, the block is added to BlockCache with add_block() method.
 While in BlockCache, the block is kept in pending_full_blocks map.
-If the block is not out of order, BlockInfo for the block is staged in staged_blocks 'sliding' tree structure.
-if the block is out of order, BlockInfo for the block is kept in out_of_order_blocks until the block with hash==prev_hash is staged.
+The tree only needs the BlockInfo of a block, so BlockCache<T> keeps any BlockPayload: a full block, the default,
+just a header, or a PendingBlock, which is how the importer keeps blocks. add_block() takes a T and
+remove_block_if_ready() returns it.
+A block added with add_raw_block() to a BlockCache<PendingBlock> is kept in its consensus encoding,
+only its header is decoded until it migrates, so losing blocks are never fully decoded.
+BlockInfo for the block is staged in staged_blocks, a forest of 'sliding' trees. The main tree is the one whose root migrates,
+a block whose parent isn't staged in any tree is out of order: it becomes the root of a detached tree waiting for the block
+with hash==prev_hash, and further out of order blocks are staged under it as they arrive. When the parent arrives,
+the detached tree is merged under it, so two runs of blocks read in the wrong order connect as soon as the block between them does.
+Detached trees whose parent never arrives can be dropped with evict_orphans_older_than(),
+and blocks far below the root, by their BIP34 height, are not even staged with set_stale_margin().
 Whenever the staged_blocks tree is deep-enough (e.g., 100 levels deep), the block correspending to the root node's BlockInfo can
-migrate to the main chain. Such a block is returned from remove_block_if_ready() method.
+migrate to the main chain; whether the deepest branch or the most work chain has to be that deep is the RemovalPolicy. Such a block is returned from remove_block_if_ready() method.
 When root is removed from the staged_blocks 'slding' tree, potential off-the-root re-org losing branched are purged,
-i.e., branches with less work, which is equivalent to keeping the deepest subtree off-the-root.
+i.e., branches with less work: the off-the-root subtree whose best chain has the most cumulative work is kept,
+which is not necessarily the deepest subtree when the difficulty differs between the branches.
+Until a root migrates, the main tree is the tree with the most work chain; a tree rooted at a genesis block of any network
+is always the main one, with the genesis block at height 0. Once roots migrated, the main tree goes on from them.
+Staged blocks are assigned heights: the first root gets the base height set with set_base_height(), 0 by default,
+and every child its parent's height + 1, so pre-BIP34 blocks get their heights too.
+A BlockCache created with with_limits() spills the bodies of the oldest pending blocks to a temp directory
+when pending_full_blocks grows over the limit, and reads them back when they are removed. BlockInfo stays in memory.
+The whole cache can be saved to a file with save() and restored with load(), e.g. to restart an import where it was.
+Blocks are told apart by their header hash only: the BIP30 blocks whose coinbase txid repeats an earlier one
+(mainnet 91842 and 91880) have their own hashes, so they are staged like any other block.
 */
 
+/// how deep the staged tree has to be before its root migrates, unless set with new_with_threshold()
+pub const DEFAULT_DEPTH_THRESHOLD: u32 = 100;
+
+/// When the root is ready to migrate, see BlockCache::set_removal_policy()
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RemovalPolicy {
+    /// The root migrates once any branch is depth_threshold levels deep, i.e. strict FIFO by confirmations
+    /// of the deepest branch. The lowest latency, but a deep branch with little work, e.g. a run of
+    /// min-difficulty testnet blocks, migrates the fork point while the most work chain may still be short,
+    /// so a chain that would later win can have been purged.
+    #[default]
+    Depth,
+    /// The root migrates once the most work chain from it, rather than the deepest branch, is
+    /// depth_threshold levels deep: the block depth_threshold - 1 below the most work tip migrates.
+    /// A fork point only migrates with depth_threshold confirmations on the chain that wins,
+    /// at the cost of waiting while a deeper branch with less work grows.
+    BestWork,
+}
+
+// The maps and sets keyed by block hash. A block hash is already a digest that nobody picks freely,
+// so with the fast-hash feature they skip SipHash and hash a block hash to its first 8 bytes
+#[cfg(feature = "fast-hash")]
+pub type BlockHashState = std::hash::BuildHasherDefault<BlockHashHasher>;
+#[cfg(not(feature = "fast-hash"))]
+pub type BlockHashState = std::collections::hash_map::RandomState;
+pub type BlockHashMap<V> = HashMap<BlockHash, V, BlockHashState>;
+pub type BlockHashSet = HashSet<BlockHash, BlockHashState>;
+
+// Takes the first 8 bytes of the hash it is fed: the zeros a block hash is mined to start with are its last bytes
+// in the internal byte order, so the first ones are as random as any. The length prefix written before them is mixed in
+#[derive(Default)]
+pub struct BlockHashHasher(u64);
+
+impl std::hash::Hasher for BlockHashHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        match bytes.get(..8) {
+            Some(word) => self.0 ^= u64::from_le_bytes(word.try_into().unwrap()),
+            None => {
+                for byte in bytes {
+                    self.0 = self.0.rotate_left(8) ^ *byte as u64;
+                }
+            }
+        }
+    }
+}
+
+/// What add_block() did with the block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddResult {
+    /// added, and there is room for more
+    Accepted,
+    /// added, but the cache is at its max pending count, see BlockCache::set_max_pending()
+    Full,
+    /// not added
+    Skipped,
+}
+
+impl AddResult {
+    pub fn is_added(self) -> bool {
+        self != AddResult::Skipped
+    }
+}
+
+// distinguishes the spill directories of the caches in this process
+static SPILL_DIR_CNT: AtomicU64 = AtomicU64::new(0);
+
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct BlockInfo {
     pub hash: BlockHash,
     prev_hash: BlockHash,
+    // proof of work of the block's header
+    work: Work,
+    // set when the block is staged
+    pub height: u64,
+}
+
+/// What the cache keeps for every block. The tree only uses block_info(),
+/// the encoding is for spilling the block to disk and for saving the cache.
+pub trait BlockPayload: Clone {
+    fn block_info(&self) -> BlockInfo;
+    // serialized size, counted against the with_limits() limit
+    fn size(&self) -> usize;
+    fn encode(&self) -> Vec<u8>;
+    fn decode(bytes: Vec<u8>) -> Result<Self, encode::Error>;
+    // checked against the stale margin, see BlockCache::set_stale_margin()
+    fn bip34_height(&self) -> Option<u64> {
+        None
+    }
 }
 
 #[derive(Debug)]
-pub struct BlockCache {
-    pending_full_blocks: HashMap<BlockHash, bitcoin::block::Block>,
-    out_of_order_blocks: HashMap<BlockHash, Vec<BlockInfo>>,
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound = "T: BlockPayload"))]
+pub struct BlockCache<T = bitcoin::Block> {
+    // blocks are serialized in the consensus encoding
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "serialize_blocks",
+            deserialize_with = "deserialize_blocks"
+        )
+    )]
+    pending_full_blocks: BlockHashMap<T>,
     staged_blocks: StagedBlocks,
+    // where the pending blocks added with a source were read from
+    sources: BlockHashMap<SourceLoc>,
+    // number of blocks added so far, the sequence number of the next added block
+    add_cnt: u64,
+    // used by remove_block_if_ready_default()
+    depth_threshold: u32,
+    // losing blocks purged by the last root removal
+    #[cfg_attr(feature = "serde", serde(skip))]
+    last_reorg: Option<ReorgEvent>,
+    // the last rollback_depth removed roots with copies of their blocks, oldest first
+    #[cfg_attr(feature = "serde", serde(skip))]
+    removed_roots: VecDeque<(TreeNode, T)>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    rollback_depth: usize,
+    // a reorg deeper than this is logged as a warning
+    #[cfg_attr(feature = "serde", serde(skip))]
+    max_reorg_depth: Option<u32>,
+    // since the cache was created or loaded
+    #[cfg_attr(feature = "serde", serde(skip))]
+    deepest_reorg_seen: u32,
+    // a block whose BIP34 height is more than this below the root height is dropped
+    #[cfg_attr(feature = "serde", serde(skip))]
+    stale_margin: Option<u64>,
+    // add_block() reports Full at this many pending blocks
+    #[cfg_attr(feature = "serde", serde(skip))]
+    max_pending_cnt: Option<usize>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    spill: BlockSpill,
+}
+
+/// the payload of the importer's cache, see BlockCache::add_raw_block() and BlockCache::add_header()
+#[derive(Debug, Clone, PartialEq)]
+pub enum PendingBlock {
+    Decoded(bitcoin::Block),
+    // consensus encoding with its decoded header, decoded when the block migrates
+    Raw { header: Header, bytes: Vec<u8> },
+    // migrates as a block without transactions
+    Header(Header),
+}
+
+/// where a block was read from, see BlockCache::add_block_from()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SourceLoc {
+    // number of the blk file
+    pub file_num: u32,
+    // position of the block's frame, i.e. its magic, within the file
+    pub offset: usize,
+}
+
+impl fmt::Display for SourceLoc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "blk{:05}.dat offset {}", self.file_num, self.offset)
+    }
+}
+
+/// snapshot of the cache counts, see BlockCache::stats()
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheStats {
+    pub pending_cnt: usize,
+    pub staged_cnt: usize,
+    pub out_of_order_cnt: usize,
+    pub tree_depth: u32,
+    pub root_removed_cnt: u32,
+    pub root_hash: Option<BlockHash>,
+}
+
+/// losing branches purged when a fork point migrated, see BlockCache::last_reorg()
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReorgEvent {
+    // tip of the losing branch with the most work
+    pub abandoned_tip: BlockHash,
+    // all purged blocks, parents before children
+    pub abandoned_blocks: Vec<BlockHash>,
+    // tip of the most work chain off the new root
+    pub winning_tip: BlockHash,
+    // height of the migrated fork point
+    pub fork_height: u64,
+    // levels from the fork point down to abandoned_tip, the number of blocks the losing chain ran ahead
+    pub depth: u32,
 }
 
 #[derive(Debug, Clone)]
-struct TreeNode {
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TreeNode {
     block_info: BlockInfo,
     parent: Option<BlockHash>,
-    children: HashSet<BlockHash>,
+    children: BlockHashSet,
     // orig_level stars from 1 for the first node added to the tree.
     // new node's orig_level is parent node's orig_level+1.
     // new node's depth is calculated as: orig_level - root_removed_cnt.
     orig_level: u32,
+    // levels in the subtree under and including this node, 1 for a leaf.
+    // maintained every time a new node is added under this node.
+    subtree_depth: u32,
+    // cumulative work of the chain from the first root ever added down to this node
+    chain_work: Work,
+    // maximum chain_work of the nodes in the subtree under and including this node.
+    // maintained every time a new node is added under this node.
+    best_subtree_work: Work,
+    // add_cnt when the block was added
+    added_seq: u64,
+}
+
+#[derive(Debug, Default)]
+struct BlockSpill {
+    max_pending_bytes: Option<usize>,
+    // sum of the serialized sizes of the blocks in pending_full_blocks
+    pending_bytes: usize,
+    // hashes in the order their blocks were added to pending_full_blocks, the oldest are spilled first.
+    // blocks removed from pending_full_blocks are skipped when spilling.
+    added_order: VecDeque<BlockHash>,
+    // created on the first spill
+    dir: Option<PathBuf>,
+    spilled_blocks: BlockHashSet,
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 struct StagedBlocks {
+    // root of the main tree, the next block to migrate
     tree_root: Option<BlockHash>,
-    nodes: HashMap<BlockHash, TreeNode>,
-    // maintained every time a new node is added to the tree
+    // nodes of the main and the detached trees
+    nodes: BlockHashMap<TreeNode>,
+    // roots of the detached trees, by the prev_hash they wait for
+    detached_roots: BlockHashMap<BlockHashSet>,
+    // subtree depth of the main root, 0 for an empty tree
     tree_depth: u32,
     // incremented every time a root node is removed
     root_removed_cnt: u32,
+    // height of the next block that becomes the root of an empty tree
+    next_root_height: u64,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    removal_policy: RemovalPolicy,
+    // parent of the first block, see BlockCache::set_anchor()
+    #[cfg_attr(feature = "serde", serde(skip))]
+    anchor_hash: Option<BlockHash>,
+    // the nodes by their height, rebuilt when the cache is loaded
+    #[cfg_attr(feature = "serde", serde(skip))]
+    by_height: BTreeMap<u64, BlockHashSet>,
 }
 
 impl BlockInfo {
-    pub fn new(hash: &BlockHash, prev_hash: &BlockHash) -> Self {
+    pub fn new(hash: &BlockHash, prev_hash: &BlockHash, work: Work) -> Self {
         BlockInfo {
-            hash: hash.clone(),
-            prev_hash: prev_hash.clone(),
+            hash: *hash,
+            prev_hash: *prev_hash,
+            work,
+            height: 0,
         }
     }
 }
 
 impl TreeNode {
-    fn new(block_info: BlockInfo) -> Self {
+    fn new(block_info: BlockInfo, added_seq: u64) -> Self {
         TreeNode {
-            block_info,
             parent: None,
-            children: HashSet::new(),
+            children: BlockHashSet::default(),
             orig_level: 0,
+            subtree_depth: 1,
+            chain_work: block_info.work,
+            best_subtree_work: block_info.work,
+            added_seq,
+            block_info,
         }
     }
+
+    pub fn block_info(&self) -> &BlockInfo {
+        &self.block_info
+    }
+
+    // None for the root of the main or of a detached tree
+    pub fn parent(&self) -> Option<&BlockHash> {
+        self.parent.as_ref()
+    }
+
+    pub fn children(&self) -> &BlockHashSet {
+        &self.children
+    }
+}
+
+impl<T: BlockPayload> Default for BlockCache<T> {
+    fn default() -> Self {
+        BlockCache::new()
+    }
 }
 
-impl BlockCache {
+impl<T: BlockPayload> BlockCache<T> {
     pub fn new() -> Self {
         BlockCache {
-            pending_full_blocks: HashMap::new(),
-            out_of_order_blocks: HashMap::new(),
+            pending_full_blocks: BlockHashMap::default(),
+            add_cnt: 0,
             staged_blocks: StagedBlocks::new(),
+            sources: BlockHashMap::default(),
+            depth_threshold: DEFAULT_DEPTH_THRESHOLD,
+            last_reorg: None,
+            removed_roots: VecDeque::new(),
+            rollback_depth: 0,
+            max_reorg_depth: None,
+            deepest_reorg_seen: 0,
+            stale_margin: None,
+            max_pending_cnt: None,
+            spill: BlockSpill::default(),
         }
     }
 
+    pub fn new_with_threshold(depth_threshold: u32) -> Self {
+        let mut block_cache = BlockCache::new();
+        block_cache.depth_threshold = depth_threshold;
+        block_cache
+    }
+
+    pub fn set_threshold(&mut self, depth_threshold: u32) {
+        self.depth_threshold = depth_threshold;
+    }
+
+    pub fn threshold(&self) -> u32 {
+        self.depth_threshold
+    }
+
+    /// RemovalPolicy::Depth unless set; a loaded cache is back to the default
+    pub fn set_removal_policy(&mut self, removal_policy: RemovalPolicy) {
+        self.staged_blocks.removal_policy = removal_policy;
+    }
+
+    pub fn removal_policy(&self) -> RemovalPolicy {
+        self.staged_blocks.removal_policy
+    }
+
+    /// pending blocks over max_pending_bytes, estimated by their serialized size, are spilled to a temp directory
+    pub fn with_limits(max_pending_bytes: usize) -> Self {
+        let mut block_cache = BlockCache::new();
+        block_cache.spill.max_pending_bytes = Some(max_pending_bytes);
+        block_cache
+    }
+
     pub fn pending_cnt(&self) -> usize {
-        self.pending_full_blocks.len()
+        self.pending_full_blocks.len() + self.spill.spilled_blocks.len()
     }
 
+    pub fn spilled_cnt(&self) -> usize {
+        self.spill.spilled_blocks.len()
+    }
+
+    /// blocks in the main tree
     pub fn staged_cnt(&self) -> usize {
-        self.staged_blocks.nodes.len()
+        self.staged_blocks.nodes.len() - self.out_of_order_cnt()
     }
 
+    /// blocks in the detached trees, waiting for a parent
     pub fn out_of_order_cnt(&self) -> usize {
-        self.out_of_order_blocks.len()
+        self.staged_blocks.detached_cnt()
     }
 
-    pub fn add_block(&mut self, block: bitcoin::block::Block) {
-        let block_info = BlockInfo::new(&block.block_hash(), &block.header.prev_blockhash);
-        self.add_block_impl(&block_info, block);
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            pending_cnt: self.pending_cnt(),
+            staged_cnt: self.staged_cnt(),
+            out_of_order_cnt: self.out_of_order_cnt(),
+            tree_depth: self.tree_depth(),
+            root_removed_cnt: self.blocks_migrated(),
+            root_hash: self.current_root(),
+        }
     }
 
-    fn add_block_impl(&mut self, block_info: &BlockInfo, block: bitcoin::block::Block) {
-        self.pending_full_blocks.insert(block_info.hash, block);
-        self.add_block_info(&block_info);
+    /// hash of the main tree's root, the next block to migrate
+    pub fn current_root(&self) -> Option<BlockHash> {
+        self.staged_blocks.tree_root
     }
 
-    fn add_block_info(&mut self, block_info: &BlockInfo) {
-        if self.staged_blocks.tree_root.is_none()
-            || self
-                .staged_blocks
-                .nodes
-                .get(&block_info.prev_hash)
-                .is_some()
-        {
-            self.staged_blocks.add_block_info(&block_info);
-            //dbg!("added {}", &block_info.hash.to_string());
-            self.move_out_of_order_blocks_to_staged(&block_info.hash);
-        } else {
-            self.out_of_order_blocks
-                .entry(block_info.prev_hash.clone())
-                .or_default()
-                .push(block_info.clone());
+    /// depth of the main tree, 0 if it is empty
+    pub fn tree_depth(&self) -> u32 {
+        self.staged_blocks.tree_depth
+    }
+
+    /// root removals so far, less the ones rolled back
+    pub fn blocks_migrated(&self) -> u32 {
+        self.staged_blocks.root_removed_cnt
+    }
+
+    /// Renders the staged trees in Graphviz DOT, e.g. for `dot -Tpng`: nodes are labeled with the last 8 hex digits
+    /// of the hash and orig_level, the main root is filled and the detached roots are dashed.
+    /// Nodes are listed by orig_level, so the output is stable.
+    pub fn to_dot(&self) -> String {
+        let mut nodes: Vec<&TreeNode> = self.staged_blocks.nodes.values().collect();
+        nodes.sort_by_key(|node| (node.orig_level, node.block_info.hash));
+        let mut dot = String::from("digraph staged_blocks {\n");
+        for node in nodes.iter() {
+            let hash = node.block_info.hash.to_string();
+            let style = if Some(node.block_info.hash) == self.staged_blocks.tree_root {
+                ", style=filled"
+            } else if node.parent.is_none() {
+                ", style=dashed"
+            } else {
+                ""
+            };
+            dot += &format!(
+                "  \"{}\" [label=\"{}\\n{}\"{}];\n",
+                hash,
+                &hash[hash.len() - 8..],
+                node.orig_level,
+                style
+            );
+        }
+        for node in nodes.iter() {
+            if let Some(parent) = node.parent {
+                dot += &format!("  \"{}\" -> \"{}\";\n", parent, node.block_info.hash);
+            }
+        }
+        dot += "}\n";
+        dot
+    }
+
+    /// all nodes of the main and the detached trees, in no particular order
+    pub fn staged_iter(&self) -> impl Iterator<Item = (&BlockHash, &TreeNode)> {
+        self.staged_blocks.nodes.iter()
+    }
+
+    /// the hashes of the staged blocks by height, forks are the heights with more than one.
+    /// The heights in a detached tree are provisional until it is merged, see height()
+    pub fn staged_by_height(&self) -> &BTreeMap<u64, BlockHashSet> {
+        &self.staged_blocks.by_height
+    }
+
+    /// all blocks in the cache, in the main or a detached tree, in no particular order; spilled blocks are left out
+    pub fn pending_iter(&self) -> impl Iterator<Item = (&BlockHash, &T)> {
+        self.pending_full_blocks.iter()
+    }
+
+    /// saves the cache to path, written to a temp file first so that an interrupted save leaves the previous file.
+    /// A cache with spilled blocks can't be saved
+    #[cfg(feature = "serde")]
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        if !self.spill.spilled_blocks.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} blocks are spilled to disk", self.spilled_cnt()),
+            ));
         }
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        bincode::serialize_into(&mut writer, self).map_err(std::io::Error::other)?;
+        writer.flush()?;
+        drop(writer);
+        std::fs::rename(&tmp_path, path)
+    }
+
+    /// restores a cache saved with save(); the loaded cache has no memory limit
+    #[cfg(feature = "serde")]
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut block_cache: BlockCache<T> = bincode::deserialize_from(reader)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        block_cache.staged_blocks.index_heights();
+        block_cache.check_invariants().map_err(|msg| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("inconsistent cache: {}", msg),
+            )
+        })?;
+        Ok(block_cache)
     }
 
-    fn move_out_of_order_blocks_to_staged(&mut self, prev_hash: &BlockHash) {
-        if let Some(block_info_vec) = self.out_of_order_blocks.remove(prev_hash) {
-            for block_info in block_info_vec {
-                self.staged_blocks.add_block_info(&block_info);
-                //dbg!("added {}", &block_info.hash.to_string());
-                self.move_out_of_order_blocks_to_staged(&block_info.hash);
+    /// Checks that the tree links are consistent both ways, the main root's orig_level is root_removed_cnt + 1 and
+    /// every child's is its parent's + 1, tree_depth is the depth of the main tree walked from its root,
+    /// every node is reached from a root, no detached tree waits for a staged parent, unless through a prev_hash
+    /// cycle, every staged block is pending and staged_by_height() has every node at its height. Checked after every change in debug builds.
+    pub fn check_invariants(&self) -> Result<(), String> {
+        let staged = &self.staged_blocks;
+        let mut reached_cnt = 0;
+        match &staged.tree_root {
+            Some(root_hash) => {
+                let root_node = staged.nodes.get(root_hash).ok_or("root node missing")?;
+                if root_node.parent.is_some() {
+                    return Err("root node has a parent".to_string());
+                }
+                if root_node.orig_level != staged.root_removed_cnt + 1 {
+                    return Err(format!(
+                        "root orig_level {}, {} roots removed",
+                        root_node.orig_level, staged.root_removed_cnt
+                    ));
+                }
+                let (depth, main_cnt) = staged.walk_depth(root_hash);
+                if depth != staged.tree_depth {
+                    return Err(format!(
+                        "tree depth {}, walked {}",
+                        staged.tree_depth, depth
+                    ));
+                }
+                reached_cnt += main_cnt;
+            }
+            None if staged.tree_depth != 0 => return Err("empty tree has a depth".to_string()),
+            None => {}
+        }
+        for (prev_hash, root_hashes) in staged.detached_roots.iter() {
+            for root_hash in root_hashes.iter() {
+                let root_node = staged
+                    .nodes
+                    .get(root_hash)
+                    .ok_or_else(|| format!("detached root {} missing", root_hash))?;
+                if root_node.parent.is_some() {
+                    return Err(format!("detached root {} has a parent", root_hash));
+                }
+                if &root_node.block_info.prev_hash != prev_hash {
+                    return Err(format!(
+                        "detached root {} waits for {}",
+                        root_hash, prev_hash
+                    ));
+                }
+                reached_cnt += staged.walk_depth(root_hash).1;
+            }
+        }
+        if reached_cnt != staged.nodes.len() {
+            return Err(format!(
+                "{} of {} nodes reached from the roots",
+                reached_cnt,
+                staged.nodes.len()
+            ));
+        }
+        // every node is reached, so the parent links lead to a root
+        for (prev_hash, root_hashes) in staged.detached_roots.iter() {
+            for root_hash in root_hashes.iter() {
+                if staged.nodes.contains_key(prev_hash) && &staged.root_of(prev_hash) != root_hash {
+                    return Err(format!(
+                        "detached root {} not merged under its parent",
+                        root_hash
+                    ));
+                }
+            }
+        }
+        for (hash, node) in staged.nodes.iter() {
+            if &node.block_info.hash != hash {
+                return Err(format!("node {} has hash {}", hash, node.block_info.hash));
+            }
+            if let Some(parent_hash) = &node.parent {
+                let parent_node = staged
+                    .nodes
+                    .get(parent_hash)
+                    .ok_or_else(|| format!("parent of {} missing", hash))?;
+                if !parent_node.children.contains(hash) {
+                    return Err(format!("parent of {} doesn't point back", hash));
+                }
+                if node.orig_level != parent_node.orig_level + 1 {
+                    return Err(format!(
+                        "node {} orig_level {}, parent's {}",
+                        hash, node.orig_level, parent_node.orig_level
+                    ));
+                }
+            } else if Some(hash) != staged.tree_root.as_ref() && !staged.is_detached_root(hash) {
+                return Err(format!("node {} has no parent", hash));
+            }
+            for child_hash in node.children.iter() {
+                let child_node = staged
+                    .nodes
+                    .get(child_hash)
+                    .ok_or_else(|| format!("child of {} missing", hash))?;
+                if child_node.parent.as_ref() != Some(hash) {
+                    return Err(format!("child {} doesn't point back", child_hash));
+                }
+            }
+            if !self.is_pending(hash) {
+                return Err(format!("staged block {} missing", hash));
             }
+            if !staged
+                .by_height
+                .get(&node.block_info.height)
+                .is_some_and(|hashes| hashes.contains(hash))
+            {
+                return Err(format!(
+                    "node {} missing at height {}",
+                    hash, node.block_info.height
+                ));
+            }
+        }
+        let indexed_cnt: usize = staged.by_height.values().map(|hashes| hashes.len()).sum();
+        if indexed_cnt != staged.nodes.len() {
+            return Err(format!(
+                "{} nodes by height, {} nodes",
+                indexed_cnt,
+                staged.nodes.len()
+            ));
         }
+        Ok(())
     }
 
-    /// when the depth in the whole tree reaches threshold, the root block_info in the tree can migrate to the main chain
-    pub fn remove_block_if_ready(&mut self, depth_threshold: u32) -> Option<bitcoin::Block> {
-        let (_, block_opt) = self.remove_block_if_ready_impl(depth_threshold);
-        block_opt
+    /// drops all blocks; the cache is left as if it was just created
+    pub fn clear(&mut self) {
+        self.pending_full_blocks.clear();
+        self.sources.clear();
+        self.add_cnt = 0;
+        self.last_reorg = None;
+        self.removed_roots.clear();
+        self.staged_blocks.clear();
+        self.spill.clear();
+        debug_assert_eq!(self.check_invariants(), Ok(()));
     }
 
-    fn remove_block_if_ready_impl(
-        &mut self,
-        depth_threshold: u32,
-    ) -> (Option<BlockInfo>, Option<bitcoin::Block>) {
-        let (block_info_opt, losing_children_opt) = self
-            .staged_blocks
-            .remove_block_info_if_ready(depth_threshold);
-        if let Some(block_info) = block_info_opt {
-            if let Some(losing_children) = losing_children_opt {
-                self.purge_losing_blocks(&losing_children);
+    // in memory or spilled
+    fn is_pending(&self, hash: &BlockHash) -> bool {
+        self.pending_full_blocks.contains_key(hash) || self.spill.spilled_blocks.contains(hash)
+    }
+
+    /// true if the block is pending or staged in the main or a detached tree
+    pub fn contains_block(&self, hash: &BlockHash) -> bool {
+        self.is_pending(hash) || self.staged_blocks.nodes.contains_key(hash)
+    }
+
+    /// the block stays in the cache, unlike with remove_block_if_ready(). None if the block was spilled
+    pub fn get_block(&self, hash: &BlockHash) -> Option<&T> {
+        self.pending_full_blocks.get(hash)
+    }
+
+    /// Skipped if the block is not added: it is already in the cache, is its own prev_hash, or is stale.
+    /// Full if it is added but the cache is full, see set_max_pending()
+    pub fn add_block(&mut self, block: T) -> AddResult {
+        let block_info = block.block_info();
+        if let (Some(stale_margin), Some(height), Some(root_height)) =
+            (self.stale_margin, block.bip34_height(), self.root_height())
+        {
+            if height + stale_margin < root_height {
+                warn!(
+                    "--- skipping stale block {:?}: BIP34 height {}, root height {}",
+                    block_info.hash, height, root_height
+                );
+                return AddResult::Skipped;
             }
-            let block_opt = self.pending_full_blocks.remove(&block_info.hash);
-            (Some(block_info), block_opt)
+        }
+        if !self.add_block_impl(&block_info, block) {
+            AddResult::Skipped
+        } else if self.is_full() {
+            AddResult::Full
         } else {
-            (None, None)
+            AddResult::Accepted
         }
     }
 
-    // Staging tree's nodes from the losing off-the-removed-root subtrees are removed from the nodes map and
-    // the corresponding blocks are removed from the pending blocks map
-    fn purge_losing_blocks(&mut self, block_hashes: &HashSet<BlockHash>) {
-        for hash in block_hashes.iter() {
-            let block = self
-                .pending_full_blocks
-                .remove(hash)
-                .expect("full block expected");
-            let node = self
-                .staged_blocks
-                .nodes
-                .remove(hash)
-                .expect("node expected");
-            //TODO change to logger
-            println!(
-                "xxx purged losing block {:?} {} header: work {} prev_hash {:?}",
-                hash,
-                block.bip34_block_height().unwrap_or(0),
-                block.header.work(),
-                block.header.prev_blockhash
+    /// like add_block(), the cache keeps where the block was read from until it leaves the cache
+    pub fn add_block_from(&mut self, block: T, source: SourceLoc) -> AddResult {
+        let hash = block.block_info().hash;
+        let added = self.add_block(block);
+        if added.is_added() {
+            self.sources.insert(hash, source);
+        }
+        added
+    }
+
+    /// Blocks are still added to a full cache, add_block() returns Full so that the caller can stop adding
+    /// and let blocks migrate until there is room. None, the default, never reports Full. Spilled blocks count too:
+    /// the byte limit of with_limits() bounds the memory the blocks take, this bounds the tree
+    pub fn set_max_pending(&mut self, max_pending_cnt: Option<usize>) {
+        self.max_pending_cnt = max_pending_cnt;
+    }
+
+    /// at or over the max pending count
+    pub fn is_full(&self) -> bool {
+        self.max_pending_cnt
+            .is_some_and(|max_pending_cnt| self.pending_cnt() >= max_pending_cnt)
+    }
+
+    /// where a pending block was read from, if it was added with add_block_from() or add_raw_block_from()
+    pub fn source_of(&self, hash: &BlockHash) -> Option<SourceLoc> {
+        self.sources.get(hash).copied()
+    }
+
+    fn add_block_impl(&mut self, block_info: &BlockInfo, block: T) -> bool {
+        if self.contains_block(&block_info.hash) {
+            return false;
+        }
+        // only the first root may be its own parent, as in tests
+        if block_info.hash == block_info.prev_hash && self.staged_blocks.tree_root.is_some() {
+            warn!("block {:?} is its own prev_hash, skipped", block_info.hash);
+            return false;
+        }
+        if self.staged_blocks.root_removed_cnt > 0 && is_genesis(block_info) {
+            warn!(
+                "genesis block {:?} arrived after blocks migrated, skipped",
+                block_info.hash
             );
-            self.purge_losing_blocks(&node.children);
+            return false;
         }
+        self.insert_pending_block(block_info.hash, block);
+        self.staged_blocks.add_block_info(block_info, self.add_cnt);
+        trace!("added block {:?}", block_info.hash);
+        self.add_cnt += 1;
+        debug_assert_eq!(self.check_invariants(), Ok(()));
+        true
     }
-}
 
-impl StagedBlocks {
-    fn new() -> Self {
-        StagedBlocks {
-            tree_root: None,
-            nodes: HashMap::new(),
-            tree_depth: 0,
-            root_removed_cnt: 0,
+    /// Removes the staged block and all the blocks descending from it, together with their full blocks,
+    /// e.g. when the block failed a check the cache can't do. Returns the hashes of the removed blocks,
+    /// empty if the block isn't staged. Blocks that would descend from them are staged as detached trees.
+    pub fn remove_subtree(&mut self, hash: &BlockHash) -> BlockHashSet {
+        let Some(node) = self.staged_blocks.nodes.get(hash) else {
+            return BlockHashSet::default();
+        };
+        let parent = node.parent;
+        let is_main_root = self.staged_blocks.tree_root == Some(*hash);
+        if parent.is_none() && !is_main_root {
+            self.staged_blocks.remove_detached_root(hash);
+        }
+        let removed_hashes = self.staged_blocks.subtree_hashes([*hash]);
+        self.purge_losing_blocks(&removed_hashes);
+        match parent {
+            Some(parent_hash) => {
+                self.staged_blocks
+                    .nodes
+                    .get_mut(&parent_hash)
+                    .expect("parent node expected")
+                    .children
+                    .remove(hash);
+                self.staged_blocks.recalculate_subtree_stats(&parent_hash);
+            }
+            None if is_main_root => {
+                self.staged_blocks.tree_root = None;
+                self.staged_blocks.tree_depth = 0;
+            }
+            None => {}
         }
+        debug_assert_eq!(self.check_invariants(), Ok(()));
+        removed_hashes.into_iter().collect()
     }
 
-    // A new tree node is created for the provided block_info. If the tree is empty, the new new becomes the root.
-    // Otherwise, the new node becomes the child of the node with hash equal to block_info.prev_hash.
-    // Tree depth is adjusted if the addition of the new node makese the tree deeper.
-    fn add_block_info(&mut self, block_info: &BlockInfo) {
-        let mut new_node = TreeNode::new(block_info.clone());
-        if self.tree_root.is_none() {
-            // if this the tree is empty, this is the first root node
-            new_node.orig_level = 1;
-            self.tree_root = Some(block_info.hash.clone());
-            self.nodes.insert(block_info.hash.clone(), new_node);
-        } else {
-            let parent_node = self
-                .nodes
-                .get_mut(&new_node.block_info.prev_hash)
-                .expect("parent node expected");
-            new_node.orig_level = parent_node.orig_level + 1;
-            new_node.parent = Some(parent_node.block_info.hash.clone());
-            if parent_node.children.len() >= 1 {
-                //TODO change to logger
-                println!(
-                    "+++ fork: new block hash {:?} prev_hash {:?}; sibling block hashes {:?}",
-                    block_info.hash,
-                    block_info.prev_hash,
-                    parent_node.children
+    /// Drops the detached trees whose root hasn't got its parent within max_age blocks added since the root was added,
+    /// together with their full blocks. Returns the number of blocks dropped.
+    pub fn evict_orphans_older_than(&mut self, max_age: u64) -> usize {
+        let add_cnt = self.add_cnt;
+        let staged = &self.staged_blocks;
+        let old_roots: Vec<BlockHash> = staged
+            .detached_roots
+            .values()
+            .flatten()
+            .filter(|root_hash| add_cnt - staged.nodes[*root_hash].added_seq > max_age)
+            .copied()
+            .collect();
+        let mut evicted_cnt = 0;
+        for root_hash in old_roots {
+            self.staged_blocks.remove_detached_root(&root_hash);
+            for hash in self.staged_blocks.subtree_hashes([root_hash]) {
+                self.discard_pending_block(&hash);
+                let node = self
+                    .staged_blocks
+                    .remove_node(&hash)
+                    .expect("node expected");
+                warn!(
+                    "xxx evicted out of order block {:?} prev_hash {:?}",
+                    hash, node.block_info.prev_hash
                 );
-            }
-            parent_node.children.insert(block_info.hash.clone());
-            let depth = new_node.orig_level - self.root_removed_cnt;
-            self.nodes.insert(block_info.hash.clone(), new_node);
-            if self.tree_depth < depth {
-                self.tree_depth = depth;
+                evicted_cnt += 1;
             }
         }
+        debug_assert_eq!(self.check_invariants(), Ok(()));
+        evicted_cnt
     }
 
-    // When the depth in the whole tree reaches threshold, the root of the tree is removed and the tree shifts up.
-    // The root's child node that has the deepest subtree becomes new root.
-    // The block correspnding to the removed root can migrate to the main chain.
-    // If the root is removed, returns BlockInfo of the removed root and HashSet of block hashes of the losing children under the root.
-    fn remove_block_info_if_ready(
+    /// when the depth in the whole tree reaches threshold, the root block_info in the tree can migrate to the main chain.
+    /// A spilled block that can't be read back is logged and dropped, see remove_block_with_height_if_ready()
+    pub fn remove_block_if_ready(&mut self, depth_threshold: u32) -> Option<T> {
+        let (_, block_opt) = self.remove_block_if_ready_impl(depth_threshold);
+        log_spill_error(block_opt)
+    }
+
+    /// like remove_block_if_ready(), with the height of the migrated block.
+    /// An error if the root migrated but its spilled block can't be read back, the block is lost
+    pub fn remove_block_with_height_if_ready(
         &mut self,
         depth_threshold: u32,
-    ) -> (Option<BlockInfo>, Option<HashSet<BlockHash>>) {
-        if self.tree_depth < depth_threshold || self.tree_depth == 0 {
-            return (None, None);
+    ) -> std::io::Result<Option<(T, u64)>> {
+        match self.remove_block_if_ready_impl(depth_threshold) {
+            (Some(block_info), block_opt) => Ok(block_opt?.map(|block| (block, block_info.height))),
+            (None, _) => Ok(None),
         }
+    }
 
-        let root_hash = self.tree_root.as_ref().expect("root hash expected");
-        let root_node = self.nodes.remove(root_hash).expect("root node expected");
-        let mut new_root_node_opt = None;
-        let mut losing_children_opt = None;
-        let child_cnt = root_node.children.len();
-        if child_cnt > 1 {
-            // if the root has more than one child, leave only the child that has the deepest subtree under it
-            let mut child_hash_with_deepest_subtree = None;
-            let mut max_subtree_depth = 0;
-            for child_hash in root_node.children.iter() {
-                let depth = self.calculate_depth_from_node(child_hash);
-                if depth > max_subtree_depth {
-                    max_subtree_depth = depth;
-                    child_hash_with_deepest_subtree = Some(child_hash);
-                }
-            }
-            let winning_child_hash = child_hash_with_deepest_subtree.expect("child hash expected");
-            new_root_node_opt = self.nodes.get_mut(winning_child_hash);
-
-            let mut losing_children = root_node.children.clone();
-            losing_children.remove(winning_child_hash);
-            losing_children_opt = Some(losing_children);
-        } else if child_cnt == 1 {
-            let child_hash = root_node
-                .children
-                .iter()
-                .last()
-                .expect("child hash expected");
-            new_root_node_opt = self.nodes.get_mut(child_hash);
+    /// Removes roots until the tree is no longer ready at depth_threshold and returns their blocks in
+    /// migration order; 0 flushes the whole staged chain.
+    pub fn drain_ready(&mut self, depth_threshold: u32) -> Vec<T> {
+        let mut blocks = Vec::new();
+        while self.staged_blocks.is_ready(depth_threshold) {
+            let (_, block_opt) = self.remove_block_if_ready_impl(depth_threshold);
+            blocks.extend(log_spill_error(block_opt));
         }
+        blocks
+    }
 
-        self.tree_depth -= 1;
-        self.root_removed_cnt += 1;
+    /// A reorg whose losing chain ran more than max_depth blocks past the fork point is logged as a warning.
+    /// Near the depth threshold, it means a competing chain almost migrated.
+    pub fn set_max_reorg_depth(&mut self, max_depth: u32) {
+        self.max_reorg_depth = Some(max_depth);
+    }
 
-        if let Some(new_root_node) = new_root_node_opt {
-            new_root_node.parent = None;
-            self.tree_root = Some(new_root_node.block_info.hash.clone());
-        } else {
-            self.tree_root = None;
-        }
+    /// the depth of the deepest reorg since the cache was created or loaded, see ReorgEvent::depth
+    pub fn deepest_reorg_seen(&self) -> u32 {
+        self.deepest_reorg_seen
+    }
 
-        (Some(root_node.block_info), losing_children_opt)
+    /// How many of the last root removals rollback_last_removed() can undo, 0 (none) by default.
+    /// A copy of every block is kept until depth more blocks have migrated.
+    pub fn set_rollback_depth(&mut self, depth: usize) {
+        self.rollback_depth = depth;
+        while self.removed_roots.len() > depth {
+            self.removed_roots.pop_front();
+        }
     }
 
-    fn calculate_depth_from_node(&self, block_hash: &BlockHash) -> u32 {
-        let mut max_depth = 0;
-        let node = self.nodes.get(block_hash).expect("node expected");
-        for child_hash in node.children.iter() {
-            let depth = self.calculate_depth_from_node(child_hash);
-            if depth > max_depth {
-                max_depth = depth;
+    /// Undoes the last root removal not undone yet, up to the rollback depth: the removed block is staged again
+    /// as the root, and will migrate again. Losing branches purged by the removal are not restored.
+    /// Returns the hash of the restored root, None if there is no removal to undo,
+    /// or the current root is not a child of the removed one, as after the tree was emptied and started over.
+    pub fn rollback_last_removed(&mut self) -> Option<BlockHash> {
+        let (mut node, block) = self.removed_roots.pop_back()?;
+        let hash = node.block_info.hash;
+        node.children.clear();
+        node.subtree_depth = 1;
+        node.best_subtree_work = node.chain_work;
+        if let Some(root_hash) = self.staged_blocks.tree_root {
+            let root_node = self
+                .staged_blocks
+                .nodes
+                .get_mut(&root_hash)
+                .expect("root node expected");
+            if root_node.block_info.prev_hash != hash {
+                self.removed_roots.push_back((node, block));
+                return None;
             }
+            root_node.parent = Some(hash);
+            node.children.insert(root_hash);
+            node.subtree_depth = root_node.subtree_depth + 1;
+            node.best_subtree_work = root_node.best_subtree_work;
         }
-        max_depth + 1
+        self.staged_blocks.tree_root = Some(hash);
+        self.staged_blocks.root_removed_cnt -= 1;
+        self.staged_blocks.next_root_height = node.block_info.height;
+        self.staged_blocks.insert_node(node);
+        // blocks that arrived after the root migrated wait for it in detached trees
+        self.staged_blocks.merge_detached_trees(&hash);
+        self.staged_blocks.update_tree_depth();
+        self.insert_pending_block(hash, block);
+        self.last_reorg = None;
+        debug_assert_eq!(self.check_invariants(), Ok(()));
+        Some(hash)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use bitcoin::consensus::encode::deserialize;
-    use hex_lit::hex;
+    /// height of the block the tree is started with when it is empty, e.g. because the first blocks are not read
+    pub fn set_base_height(&mut self, height: u64) {
+        self.staged_blocks.next_root_height = height;
+    }
 
-    fn create_block_hash(hash: &str) -> BlockHash {
-        BlockHash::from_str(&hash.repeat(64)).unwrap()
+    /// Anchors a cache that doesn't start at genesis, e.g. on the blk files of a pruned node: a tree rooted at
+    /// the child of base_hash is the main tree, as one rooted at genesis would be, and starts at base_height + 1.
+    /// A loaded cache is not anchored
+    pub fn set_anchor(&mut self, base_hash: BlockHash, base_height: u64) {
+        self.staged_blocks.anchor_hash = Some(base_hash);
+        self.staged_blocks.next_root_height = base_height + 1;
     }
 
-    fn create_block_info(hash: &str, prev_hash: &str) -> BlockInfo {
-        BlockInfo {
-            hash: create_block_hash(&hash),
-            prev_hash: create_block_hash(&prev_hash),
+    /// A block whose BIP34 height is more than margin below the root's height can never connect,
+    /// its parent migrated long ago, so add_block() drops it instead of staging it as out of order.
+    /// Only for caches whose heights match the BIP34 heights: started from genesis or with set_base_height().
+    /// Payloads without a BIP34 height, e.g. headers, raw blocks and pre-BIP34 blocks, are not checked.
+    pub fn set_stale_margin(&mut self, margin: u64) {
+        self.stale_margin = Some(margin);
+    }
+
+    // height of the staged root, or of the next one if the tree emptied after blocks migrated
+    fn root_height(&self) -> Option<u64> {
+        match &self.staged_blocks.tree_root {
+            Some(root_hash) => Some(self.staged_blocks.nodes[root_hash].block_info.height),
+            None => (self.staged_blocks.root_removed_cnt > 0)
+                .then_some(self.staged_blocks.next_root_height),
         }
     }
 
-    #[test]
-    fn test() {
-        let mut block_cache = BlockCache::new();
+    /// height of a block in the main tree, None if it is not staged there.
+    /// The blocks of a detached tree get their heights when it is merged
+    pub fn height(&self, hash: &BlockHash) -> Option<u64> {
+        let node = self.staged_blocks.nodes.get(hash)?;
+        (Some(self.staged_blocks.root_of(hash)) == self.staged_blocks.tree_root)
+            .then_some(node.block_info.height)
+    }
 
-        // Create an unbalanced tree with branches and a deepest branch of 10 levels
-        /*
-                              0
-                             / \
-                            1   2
-                           /   / \
-                          3   4   5
-                         /   /     \
-                        6   7       8
-                       /     \
-                      9       A
-                               \
-                                B
-                                 \
-                                  C
-        */
-        let blocks = vec![
-            create_block_info("0", "0"), // Level 0
-            create_block_info("8", "5"), // Level 4, out of order
-            create_block_info("4", "2"), // Level 2, out of order
-            create_block_info("5", "2"), // Level 2, out of order
-            create_block_info("1", "0"), // Level 1
-            create_block_info("2", "0"), // Level 1
-            create_block_info("A", "7"), // Level 4, out of order
-            create_block_info("7", "4"), // Level 3, out of order
-            create_block_info("9", "6"), // Level 4, out of order
-            create_block_info("3", "1"), // Level 2
-            create_block_info("6", "3"), // Level 3
-            create_block_info("B", "A"), // Level 5
-            create_block_info("C", "B"), // Level 6
-        ];
-        const BLOCK_HEX: &str = "0200000035ab154183570282ce9afc0b494c9fc6a3cfea05aa8c1add2ecc56490000000038ba3d78e4500a5a7570dbe61960398add4410d278b21cd9708e6d9743f374d544fc055227f1001c29c1ea3b0101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff3703a08601000427f1001c046a510100522cfabe6d6d0000000000000000000068692066726f6d20706f6f6c7365727665726aac1eeeed88ffffffff0100f2052a010000001976a914912e2b234f941f30b18afbb4fa46171214bf66c888ac00000000";
-        let dummy_block: bitcoin::Block = deserialize(&hex!(BLOCK_HEX)).unwrap();
+    /// hashes of the losing blocks purged when the last root was removed
+    pub fn last_purged(&self) -> &[BlockHash] {
+        self.last_reorg
+            .as_ref()
+            .map_or(&[], |reorg| &reorg.abandoned_blocks)
+    }
 
-        // Add blocks to the tree
-        for block_info in &blocks {
-            block_cache.add_block_impl(block_info, dummy_block.clone());
-        }
-        //dbg!(&block_cache);
-        assert_eq!(block_cache.staged_blocks.tree_depth, 7);
-        assert_eq!(block_cache.staged_cnt(), 13);
-        assert_eq!(block_cache.out_of_order_cnt(), 0);
+    /// set if the last root removal purged losing branches
+    pub fn last_reorg(&self) -> Option<&ReorgEvent> {
+        self.last_reorg.as_ref()
+    }
 
-        let expected_roots = vec!["0", "2", "4"];
+    /// remove_block_if_ready() at the threshold set for the cache
+    pub fn remove_block_if_ready_default(&mut self) -> Option<T> {
+        self.remove_block_if_ready(self.depth_threshold)
+    }
+
+    /// BlockInfo of the root that remove_block_if_ready() would migrate at depth_threshold; the tree is not changed
+    pub fn peek_ready(&self, depth_threshold: u32) -> Option<&BlockInfo> {
+        if !self.staged_blocks.is_ready(depth_threshold) {
+            return None;
+        }
+        let root_hash = self.staged_blocks.tree_root.as_ref()?;
+        self.staged_blocks
+            .nodes
+            .get(root_hash)
+            .map(|node| &node.block_info)
+    }
+
+    fn remove_block_if_ready_impl(
+        &mut self,
+        depth_threshold: u32,
+    ) -> (Option<BlockInfo>, std::io::Result<Option<T>>) {
+        let (root_node_opt, losing_children_opt) =
+            self.staged_blocks.remove_root_if_ready(depth_threshold);
+        if let Some(root_node) = root_node_opt {
+            let block_info = root_node.block_info.clone();
+            let block_opt = self.remove_pending_block(&block_info.hash);
+            let last_reorg = losing_children_opt.map(|losing_children| {
+                self.staged_blocks.reorg_event(&losing_children, &root_node)
+            });
+            if let Some(reorg) = &last_reorg {
+                self.check_reorg_depth(reorg);
+                self.purge_losing_blocks(&reorg.abandoned_blocks);
+            }
+            if let (Ok(Some(block)), true) = (&block_opt, self.rollback_depth > 0) {
+                self.removed_roots.push_back((root_node, block.clone()));
+                if self.removed_roots.len() > self.rollback_depth {
+                    self.removed_roots.pop_front();
+                }
+            }
+            self.last_reorg = last_reorg;
+            debug_assert_eq!(self.check_invariants(), Ok(()));
+            (Some(block_info), block_opt)
+        } else {
+            (None, Ok(None))
+        }
+    }
+
+    fn check_reorg_depth(&mut self, reorg: &ReorgEvent) {
+        self.deepest_reorg_seen = self.deepest_reorg_seen.max(reorg.depth);
+        if self
+            .max_reorg_depth
+            .is_some_and(|max_depth| reorg.depth > max_depth)
+        {
+            warn!(
+                "+++ deep reorg: {} blocks abandoned at fork height {}, abandoned tip {:?}, winning tip {:?}",
+                reorg.depth, reorg.fork_height, reorg.abandoned_tip, reorg.winning_tip
+            );
+        }
+    }
+
+    fn insert_pending_block(&mut self, hash: BlockHash, block: T) {
+        if self.spill.max_pending_bytes.is_none() {
+            self.pending_full_blocks.insert(hash, block);
+            return;
+        }
+        self.spill.pending_bytes += block.size();
+        self.spill.added_order.push_back(hash);
+        if let Some(old_block) = self.pending_full_blocks.insert(hash, block) {
+            self.spill.pending_bytes -= old_block.size();
+        }
+        if self.spill.spilled_blocks.remove(&hash) {
+            let _ = std::fs::remove_file(self.spill.block_path(&hash));
+        }
+        self.spill_pending_blocks();
+    }
+
+    // A spilled block is read back from its file, an error if the file can't be read or decoded,
+    // e.g. after a temp dir cleaner deleted it. Either way the block is no longer pending
+    fn remove_pending_block(&mut self, hash: &BlockHash) -> std::io::Result<Option<T>> {
+        if !self.spill.spilled_blocks.remove(hash) {
+            return Ok(self.discard_pending_block(hash));
+        }
+        self.sources.remove(hash);
+        let path = self.spill.block_path(hash);
+        let spill_error = |err: &dyn fmt::Display| {
+            std::io::Error::other(format!(
+                "spilled block {} {}: {}",
+                hash,
+                path.display(),
+                err
+            ))
+        };
+        let read = std::fs::read(&path);
+        let _ = std::fs::remove_file(&path);
+        let bytes = read.map_err(|err| spill_error(&err))?;
+        T::decode(bytes).map(Some).map_err(|err| spill_error(&err))
+    }
+
+    // Like remove_pending_block(), but a spilled block's file is only deleted, not read
+    fn discard_pending_block(&mut self, hash: &BlockHash) -> Option<T> {
+        self.sources.remove(hash);
+        if let Some(block) = self.pending_full_blocks.remove(hash) {
+            if self.spill.max_pending_bytes.is_some() {
+                self.spill.pending_bytes -= block.size();
+                if self.spill.added_order.len() > 2 * self.pending_full_blocks.len() + 16 {
+                    let pending_full_blocks = &self.pending_full_blocks;
+                    self.spill
+                        .added_order
+                        .retain(|hash| pending_full_blocks.contains_key(hash));
+                }
+            }
+            Some(block)
+        } else if self.spill.spilled_blocks.remove(hash) {
+            let _ = std::fs::remove_file(self.spill.block_path(hash));
+            None
+        } else {
+            None
+        }
+    }
+
+    // the oldest blocks are spilled until pending_full_blocks is under the limit
+    fn spill_pending_blocks(&mut self) {
+        let Some(max_pending_bytes) = self.spill.max_pending_bytes else {
+            return;
+        };
+        while self.spill.pending_bytes > max_pending_bytes {
+            let Some(hash) = self.spill.added_order.pop_front() else {
+                break;
+            };
+            let Some(block) = self.pending_full_blocks.remove(&hash) else {
+                continue;
+            };
+            if let Err(err) = self.spill.write_block(&hash, &block) {
+                warn!("can't spill block {}: {}", hash, err);
+                self.pending_full_blocks.insert(hash, block);
+                self.spill.added_order.push_front(hash);
+                break;
+            }
+            self.spill.pending_bytes -= block.size();
+            self.spill.spilled_blocks.insert(hash);
+        }
+    }
+
+    // Staging tree's nodes from the losing off-the-removed-root subtrees are removed from the nodes map and
+    // the corresponding blocks are removed from the pending blocks map
+    fn purge_losing_blocks(&mut self, block_hashes: &[BlockHash]) {
+        for hash in block_hashes.iter() {
+            // dropped as is, a raw block is never decoded
+            self.discard_pending_block(hash);
+            let node = self.staged_blocks.remove_node(hash).expect("node expected");
+            warn!(
+                "xxx purged losing block {:?} {} header: work {} prev_hash {:?}",
+                hash, node.block_info.height, node.block_info.work, node.block_info.prev_hash
+            );
+        }
+    }
+}
+
+fn log_spill_error<T>(block_opt: std::io::Result<Option<T>>) -> Option<T> {
+    block_opt
+        .map_err(|err| error!("can't read back {}, dropped", err))
+        .ok()
+        .flatten()
+}
+
+// Saturating, Work's + panics on overflow in debug builds, and the bits of a corrupt header can claim 2^256 work
+pub(crate) fn add_work(a: Work, b: Work) -> Work {
+    let (a, b) = (a.to_le_bytes(), b.to_le_bytes());
+    let mut sum = [0; 32];
+    let mut carry = 0;
+    for i in 0..32 {
+        let digit_sum = a[i] as u16 + b[i] as u16 + carry;
+        sum[i] = digit_sum as u8;
+        carry = digit_sum >> 8;
+    }
+    if carry > 0 {
+        return Work::from_le_bytes([0xff; 32]);
+    }
+    Work::from_le_bytes(sum)
+}
+
+// the cache doesn't know the network, a block is a genesis block if it is the genesis block of any network
+fn is_genesis(block_info: &BlockInfo) -> bool {
+    block_info.prev_hash == BlockHash::all_zeros()
+        && [
+            Network::Bitcoin,
+            Network::Testnet,
+            Network::Testnet4,
+            Network::Signet,
+            Network::Regtest,
+        ]
+        .iter()
+        .any(|network| genesis_block(*network).block_hash() == block_info.hash)
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "pending {} staged {} out of order {} tree depth {} roots removed {} root ",
+            self.pending_cnt,
+            self.staged_cnt,
+            self.out_of_order_cnt,
+            self.tree_depth,
+            self.root_removed_cnt
+        )?;
+        match &self.root_hash {
+            Some(root_hash) => write!(f, "{}", root_hash),
+            None => write!(f, "none"),
+        }
+    }
+}
+
+impl BlockCache<PendingBlock> {
+    /// Adds just the header of a block, e.g. to follow the headers chain. It migrates as a block without
+    /// transactions.
+    pub fn add_header(&mut self, header: Header) -> AddResult {
+        self.add_block(PendingBlock::Header(header))
+    }
+
+    /// like add_header(), with where the block was read from
+    pub fn add_header_from(&mut self, header: Header, source: SourceLoc) -> AddResult {
+        self.add_block_from(PendingBlock::Header(header), source)
+    }
+
+    /// Adds a block in its consensus encoding, only the header is decoded. The block is decoded when it migrates,
+    /// see PendingBlock::into_block(). Errors if the header doesn't decode.
+    pub fn add_raw_block(&mut self, bytes: Vec<u8>) -> crate::error::Result<AddResult> {
+        Ok(self.add_block(PendingBlock::raw(bytes)?))
+    }
+
+    /// like add_raw_block(), with where the block was read from
+    pub fn add_raw_block_from(
+        &mut self,
+        bytes: Vec<u8>,
+        source: SourceLoc,
+    ) -> crate::error::Result<AddResult> {
+        Ok(self.add_block_from(PendingBlock::raw(bytes)?, source))
+    }
+}
+
+impl BlockPayload for bitcoin::Block {
+    fn block_info(&self) -> BlockInfo {
+        self.header.block_info()
+    }
+
+    fn size(&self) -> usize {
+        self.total_size()
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        serialize(self)
+    }
+
+    fn decode(bytes: Vec<u8>) -> Result<Self, encode::Error> {
+        deserialize(&bytes)
+    }
+
+    fn bip34_height(&self) -> Option<u64> {
+        self.bip34_block_height().ok()
+    }
+}
+
+impl BlockPayload for Header {
+    fn block_info(&self) -> BlockInfo {
+        BlockInfo::new(&self.block_hash(), &self.prev_blockhash, self.work())
+    }
+
+    fn size(&self) -> usize {
+        Header::SIZE
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        serialize(self)
+    }
+
+    fn decode(bytes: Vec<u8>) -> Result<Self, encode::Error> {
+        deserialize(&bytes)
+    }
+}
+
+impl PendingBlock {
+    /// errors if the header doesn't decode, the rest of the block is decoded by into_block()
+    pub fn raw(bytes: Vec<u8>) -> Result<Self, encode::Error> {
+        let header = Header::consensus_decode(&mut bytes.as_slice())?;
+        Ok(PendingBlock::Raw { header, bytes })
+    }
+
+    pub fn header(&self) -> &Header {
+        match self {
+            PendingBlock::Decoded(block) => &block.header,
+            PendingBlock::Raw { header, .. } | PendingBlock::Header(header) => header,
+        }
+    }
+
+    /// None unless the block is decoded
+    pub fn block(&self) -> Option<&bitcoin::Block> {
+        match self {
+            PendingBlock::Decoded(block) => Some(block),
+            _ => None,
+        }
+    }
+
+    /// a header becomes a block without transactions
+    pub fn into_block(self) -> Result<bitcoin::Block, encode::Error> {
+        match self {
+            PendingBlock::Decoded(block) => Ok(block),
+            PendingBlock::Raw { bytes, .. } => deserialize(&bytes),
+            PendingBlock::Header(header) => Ok(bitcoin::Block {
+                header,
+                txdata: Vec::new(),
+            }),
+        }
+    }
+}
+
+// a spilled or loaded block is read back raw
+impl BlockPayload for PendingBlock {
+    fn block_info(&self) -> BlockInfo {
+        self.header().block_info()
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            PendingBlock::Decoded(block) => block.total_size(),
+            PendingBlock::Raw { bytes, .. } => bytes.len(),
+            PendingBlock::Header(_) => Header::SIZE,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            PendingBlock::Decoded(block) => serialize(block),
+            PendingBlock::Raw { bytes, .. } => bytes.clone(),
+            PendingBlock::Header(header) => serialize(&bitcoin::Block {
+                header: *header,
+                txdata: Vec::new(),
+            }),
+        }
+    }
+
+    fn decode(bytes: Vec<u8>) -> Result<Self, encode::Error> {
+        PendingBlock::raw(bytes)
+    }
+
+    fn bip34_height(&self) -> Option<u64> {
+        self.block().and_then(BlockPayload::bip34_height)
+    }
+}
+
+impl From<bitcoin::Block> for PendingBlock {
+    fn from(block: bitcoin::Block) -> Self {
+        PendingBlock::Decoded(block)
+    }
+}
+
+// decoded when loaded
+#[cfg(feature = "serde")]
+fn serialize_blocks<S: Serializer, T: BlockPayload>(
+    blocks: &BlockHashMap<T>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(blocks.iter().map(|(hash, block)| (hash, block.encode())))
+}
+
+#[cfg(feature = "serde")]
+fn deserialize_blocks<'de, D: Deserializer<'de>, T: BlockPayload>(
+    deserializer: D,
+) -> Result<BlockHashMap<T>, D::Error> {
+    let encoded_blocks = BlockHashMap::<Vec<u8>>::deserialize(deserializer)?;
+    encoded_blocks
+        .into_iter()
+        .map(|(hash, bytes)| {
+            let block = T::decode(bytes).map_err(serde::de::Error::custom)?;
+            Ok((hash, block))
+        })
+        .collect()
+}
+
+impl<T> Drop for BlockCache<T> {
+    fn drop(&mut self) {
+        self.spill.clear();
+    }
+}
+
+impl BlockSpill {
+    fn block_path(&self, hash: &BlockHash) -> PathBuf {
+        self.dir
+            .as_ref()
+            .expect("spill dir expected")
+            .join(format!("{}.blk", hash))
+    }
+
+    fn write_block(&mut self, hash: &BlockHash, block: &impl BlockPayload) -> std::io::Result<()> {
+        if self.dir.is_none() {
+            let dir = std::env::temp_dir().join(format!(
+                "read-blk-spill-{}-{}",
+                std::process::id(),
+                SPILL_DIR_CNT.fetch_add(1, Ordering::Relaxed)
+            ));
+            std::fs::create_dir_all(&dir)?;
+            self.dir = Some(dir);
+        }
+        std::fs::write(self.block_path(hash), block.encode())
+    }
+
+    // the limit is kept
+    fn clear(&mut self) {
+        if let Some(dir) = self.dir.take() {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+        self.pending_bytes = 0;
+        self.added_order.clear();
+        self.spilled_blocks.clear();
+    }
+}
+
+impl StagedBlocks {
+    fn new() -> Self {
+        StagedBlocks {
+            tree_root: None,
+            nodes: BlockHashMap::default(),
+            detached_roots: BlockHashMap::default(),
+            tree_depth: 0,
+            root_removed_cnt: 0,
+            next_root_height: 0,
+            removal_policy: RemovalPolicy::default(),
+            anchor_hash: None,
+            by_height: BTreeMap::new(),
+        }
+    }
+
+    fn insert_node(&mut self, node: TreeNode) {
+        let hash = node.block_info.hash;
+        self.by_height
+            .entry(node.block_info.height)
+            .or_default()
+            .insert(hash);
+        self.nodes.insert(hash, node);
+    }
+
+    fn remove_node(&mut self, hash: &BlockHash) -> Option<TreeNode> {
+        let node = self.nodes.remove(hash)?;
+        self.remove_from_height(&node.block_info);
+        Some(node)
+    }
+
+    fn remove_from_height(&mut self, block_info: &BlockInfo) {
+        if let Some(hashes) = self.by_height.get_mut(&block_info.height) {
+            hashes.remove(&block_info.hash);
+            if hashes.is_empty() {
+                self.by_height.remove(&block_info.height);
+            }
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    fn index_heights(&mut self) {
+        self.by_height.clear();
+        for (hash, node) in self.nodes.iter() {
+            self.by_height
+                .entry(node.block_info.height)
+                .or_default()
+                .insert(*hash);
+        }
+    }
+
+    // A new tree node is created for the provided block_info. If the parent is staged in any of the trees,
+    // the new node becomes the child of the node with hash equal to block_info.prev_hash.
+    // Otherwise the new node becomes the root of the main tree if there is none, or of a detached tree.
+    // Then the detached trees waiting for the new node are merged under it and the main tree is chosen again.
+    // Tree depth is adjusted if the addition of the new node makes the main root's subtree deeper.
+    fn add_block_info(&mut self, block_info: &BlockInfo, added_seq: u64) {
+        let mut new_node = TreeNode::new(block_info.clone(), added_seq);
+        if let Some(parent_node) = self.nodes.get_mut(&block_info.prev_hash) {
+            new_node.orig_level = parent_node.orig_level + 1;
+            new_node.block_info.height = parent_node.block_info.height + 1;
+            new_node.parent = Some(parent_node.block_info.hash);
+            new_node.chain_work = add_work(parent_node.chain_work, block_info.work);
+            new_node.best_subtree_work = new_node.chain_work;
+            if !parent_node.children.is_empty() {
+                info!(
+                    "+++ fork: new block hash {:?} prev_hash {:?}; sibling block hashes {:?}",
+                    block_info.hash, block_info.prev_hash, parent_node.children
+                );
+            }
+            parent_node.children.insert(block_info.hash);
+            self.insert_node(new_node);
+            let chain_work = self.nodes[&block_info.hash].chain_work;
+            self.update_subtree_stats(&block_info.prev_hash, 2, chain_work);
+        } else {
+            // A tree started over after roots were removed goes on counting the levels.
+            // The heights of a detached tree are provisional until it is merged or becomes the main tree
+            new_node.orig_level = self.root_removed_cnt + 1;
+            new_node.block_info.height = if is_genesis(block_info) {
+                0
+            } else {
+                self.next_root_height
+            };
+            self.insert_node(new_node);
+            if self.tree_root.is_none() {
+                self.tree_root = Some(block_info.hash);
+            } else {
+                debug!(
+                    "out of order block {:?}, prev_hash {:?} not staged",
+                    block_info.hash, block_info.prev_hash
+                );
+                self.detached_roots
+                    .entry(block_info.prev_hash)
+                    .or_default()
+                    .insert(block_info.hash);
+            }
+        }
+        self.merge_detached_trees(&block_info.hash);
+        self.choose_main_tree();
+        self.update_tree_depth();
+    }
+
+    // The detached trees waiting for block_hash are merged under its node. So is the main tree if no root
+    // migrated yet, and the merged tree becomes the main tree. A tree that block_hash is in itself can only
+    // wait for it through a prev_hash cycle, it is skipped.
+    fn merge_detached_trees(&mut self, block_hash: &BlockHash) {
+        let own_root = self.root_of(block_hash);
+        let mut child_roots: Vec<BlockHash> = self
+            .detached_roots
+            .remove(block_hash)
+            .map_or_else(Vec::new, |root_hashes| root_hashes.into_iter().collect());
+        child_roots.sort();
+        // last, so that a skipped detached root is back in detached_roots before own_root is taken out of them
+        if let Some(main_root) = self.tree_root.filter(|root_hash| {
+            self.root_removed_cnt == 0
+                && root_hash != block_hash
+                && &self.nodes[root_hash].block_info.prev_hash == block_hash
+        }) {
+            child_roots.push(main_root);
+        }
+        for child_root in child_roots {
+            let is_main_root = Some(child_root) == self.tree_root;
+            if child_root == own_root {
+                warn!("prev_hash cycle at block {:?}, skipped", child_root);
+                if !is_main_root {
+                    self.detached_roots
+                        .entry(*block_hash)
+                        .or_default()
+                        .insert(child_root);
+                }
+                continue;
+            }
+            if is_main_root {
+                self.remove_detached_root(&own_root);
+                self.tree_root = Some(own_root);
+            }
+            trace!("merged tree {:?} under {:?}", child_root, block_hash);
+            self.attach_tree(block_hash, &child_root);
+        }
+    }
+
+    // Hangs the tree rooted at root_hash under parent_hash: the levels, heights and chain work in the tree
+    // go on from the parent's, then the subtree stats of the parent and its ancestors are updated
+    fn attach_tree(&mut self, parent_hash: &BlockHash, root_hash: &BlockHash) {
+        let parent_node = &self.nodes[parent_hash];
+        let root_node = &self.nodes[root_hash];
+        let level_shift = parent_node.orig_level as i64 + 1 - root_node.orig_level as i64;
+        let height_shift =
+            parent_node.block_info.height as i64 + 1 - root_node.block_info.height as i64;
+        let parent_work = parent_node.chain_work;
+        self.shift_tree(root_hash, level_shift, height_shift, Some(parent_work));
+        let parent_node = self
+            .nodes
+            .get_mut(parent_hash)
+            .expect("parent node expected");
+        if !parent_node.children.is_empty() {
+            info!(
+                "+++ fork: merged block hash {:?} prev_hash {:?}; sibling block hashes {:?}",
+                root_hash, parent_hash, parent_node.children
+            );
+        }
+        parent_node.children.insert(*root_hash);
+        let root_node = self.nodes.get_mut(root_hash).expect("root node expected");
+        root_node.parent = Some(*parent_hash);
+        let (subtree_depth, best_subtree_work) =
+            (root_node.subtree_depth + 1, root_node.best_subtree_work);
+        self.update_subtree_stats(parent_hash, subtree_depth, best_subtree_work);
+    }
+
+    // shifts orig_level and height of every node in the tree, and adds base_work to their chain work
+    fn shift_tree(
+        &mut self,
+        root_hash: &BlockHash,
+        level_shift: i64,
+        height_shift: i64,
+        base_work: Option<Work>,
+    ) {
+        for hash in self.subtree_hashes([*root_hash]) {
+            let mut node = self.remove_node(&hash).expect("node expected");
+            node.orig_level = (node.orig_level as i64 + level_shift) as u32;
+            node.block_info.height = (node.block_info.height as i64 + height_shift) as u64;
+            if let Some(base_work) = base_work {
+                node.chain_work = add_work(base_work, node.chain_work);
+                node.best_subtree_work = add_work(base_work, node.best_subtree_work);
+            }
+            self.insert_node(node);
+        }
+    }
+
+    // Until a root migrates, the main tree is the one with the most work chain, and a tree rooted at a genesis
+    // block or at the anchor's child always is. After that the main tree goes on from the migrated roots: the blocks of a detached tree can't
+    // tell whether they descend from them, so a detached tree only becomes the main tree when there is none.
+    // On equal work the main tree stays, between detached trees the root with the smaller hash wins.
+    fn choose_main_tree(&mut self) {
+        let main_root = match self.tree_root {
+            Some(root_hash)
+                if self.root_removed_cnt > 0
+                    || self.is_first_block(&self.nodes[&root_hash].block_info) =>
+            {
+                return
+            }
+            main_root => main_root,
+        };
+        let rank = |root_hash: &BlockHash| {
+            let root_node = &self.nodes[root_hash];
+            (
+                self.is_first_block(&root_node.block_info),
+                root_node.best_subtree_work,
+            )
+        };
+        let Some(best_root) = self
+            .detached_roots
+            .values()
+            .flatten()
+            .max_by(|a, b| rank(a).cmp(&rank(b)).then(b.cmp(a)))
+            .copied()
+        else {
+            return;
+        };
+        if main_root.is_some_and(|main_root| rank(&main_root) >= rank(&best_root)) {
+            return;
+        }
+        info!(
+            "+++ main tree switched from root {:?} to root {:?}",
+            main_root, best_root
+        );
+        self.remove_detached_root(&best_root);
+        if let Some(main_root) = main_root {
+            let prev_hash = self.nodes[&main_root].block_info.prev_hash;
+            self.detached_roots
+                .entry(prev_hash)
+                .or_default()
+                .insert(main_root);
+        }
+        self.tree_root = Some(best_root);
+        // the main root's level and height go on from the removed roots
+        let root_node = &self.nodes[&best_root];
+        let level_shift = self.root_removed_cnt as i64 + 1 - root_node.orig_level as i64;
+        let root_height = if is_genesis(&root_node.block_info) {
+            0
+        } else {
+            self.next_root_height
+        };
+        let height_shift = root_height as i64 - root_node.block_info.height as i64;
+        self.shift_tree(&best_root, level_shift, height_shift, None);
+    }
+
+    // the chain starts at this block, a genesis block or the anchor's child
+    fn is_first_block(&self, block_info: &BlockInfo) -> bool {
+        is_genesis(block_info) || Some(block_info.prev_hash) == self.anchor_hash
+    }
+
+    fn remove_detached_root(&mut self, root_hash: &BlockHash) {
+        let prev_hash = self.nodes[root_hash].block_info.prev_hash;
+        if let Some(root_hashes) = self.detached_roots.get_mut(&prev_hash) {
+            root_hashes.remove(root_hash);
+            if root_hashes.is_empty() {
+                self.detached_roots.remove(&prev_hash);
+            }
+        }
+    }
+
+    fn is_detached_root(&self, hash: &BlockHash) -> bool {
+        self.nodes.get(hash).is_some_and(|node| {
+            self.detached_roots
+                .get(&node.block_info.prev_hash)
+                .is_some_and(|root_hashes| root_hashes.contains(hash))
+        })
+    }
+
+    // root of the tree the staged block is in
+    fn root_of(&self, block_hash: &BlockHash) -> BlockHash {
+        let mut hash = *block_hash;
+        while let Some(parent_hash) = self.nodes[&hash].parent {
+            hash = parent_hash;
+        }
+        hash
+    }
+
+    // number of nodes in the detached trees
+    fn detached_cnt(&self) -> usize {
+        self.subtree_hashes(self.detached_roots.values().flatten().copied())
+            .len()
+    }
+
+    fn update_tree_depth(&mut self) {
+        self.tree_depth = self
+            .tree_root
+            .map_or(0, |root_hash| self.nodes[&root_hash].subtree_depth);
+    }
+
+    // Bubbles up the subtree depth and best work from a new node's parent,
+    // until an ancestor's subtree was already as deep and had as much work
+    fn update_subtree_stats(
+        &mut self,
+        block_hash: &BlockHash,
+        subtree_depth: u32,
+        best_subtree_work: Work,
+    ) {
+        let mut hash_opt = Some(*block_hash);
+        let mut subtree_depth = subtree_depth;
+        while let Some(hash) = hash_opt {
+            let node = self.nodes.get_mut(&hash).expect("node expected");
+            if node.subtree_depth >= subtree_depth && node.best_subtree_work >= best_subtree_work {
+                break;
+            }
+            node.subtree_depth = node.subtree_depth.max(subtree_depth);
+            node.best_subtree_work = node.best_subtree_work.max(best_subtree_work);
+            subtree_depth = node.subtree_depth + 1;
+            hash_opt = node.parent;
+        }
+    }
+
+    // When the depth in the whole tree reaches threshold, the root of the tree is removed and the tree shifts up.
+    // The root's child node whose subtree has the most work chain becomes new root.
+    // Removing the root and the losing subtrees doesn't change the subtree stats of the remaining nodes.
+    // The block correspnding to the removed root can migrate to the main chain.
+    // returns the removed root node and its losing children
+    fn remove_root_if_ready(
+        &mut self,
+        depth_threshold: u32,
+    ) -> (Option<TreeNode>, Option<BlockHashSet>) {
+        if !self.is_ready(depth_threshold) {
+            return (None, None);
+        }
+
+        let root_hash = self.tree_root.expect("root hash expected");
+        let root_node = self.remove_node(&root_hash).expect("root node expected");
+        let mut new_root_node_opt = None;
+        let mut losing_children_opt = None;
+        let child_cnt = root_node.children.len();
+        if child_cnt > 1 {
+            // if the root has more than one child, leave only the child whose subtree has the most work chain
+            let winning_child_hash = self
+                .best_child(&root_node.children)
+                .expect("child hash expected");
+            new_root_node_opt = self.nodes.get_mut(winning_child_hash);
+
+            let mut losing_children = root_node.children.clone();
+            losing_children.remove(winning_child_hash);
+            losing_children_opt = Some(losing_children);
+        } else if child_cnt == 1 {
+            let child_hash = root_node
+                .children
+                .iter()
+                .last()
+                .expect("child hash expected");
+            new_root_node_opt = self.nodes.get_mut(child_hash);
+        }
+
+        self.root_removed_cnt += 1;
+        self.next_root_height = root_node.block_info.height + 1;
+
+        // a losing subtree may have been deeper than the winning one,
+        // so the tree can get shallower by more than the removed root
+        if let Some(new_root_node) = new_root_node_opt {
+            new_root_node.parent = None;
+            self.tree_root = Some(new_root_node.block_info.hash);
+            self.tree_depth = new_root_node.subtree_depth;
+        } else {
+            self.tree_root = None;
+            self.tree_depth = 0;
+        }
+
+        (Some(root_node), losing_children_opt)
+    }
+
+    // the child whose subtree has the most work chain.
+    // on equal work the child with the smaller hash (compared as bytes) wins, whatever the HashSet order
+    fn best_child<'a>(&self, children: &'a BlockHashSet) -> Option<&'a BlockHash> {
+        let mut child_hash_with_best_subtree = None;
+        let mut max_subtree_work = None;
+        for child_hash in children.iter() {
+            let work = self
+                .nodes
+                .get(child_hash)
+                .expect("node expected")
+                .best_subtree_work;
+            if Some(work) > max_subtree_work
+                || (Some(work) == max_subtree_work
+                    && Some(child_hash) < child_hash_with_best_subtree)
+            {
+                max_subtree_work = Some(work);
+                child_hash_with_best_subtree = Some(child_hash);
+            }
+        }
+        child_hash_with_best_subtree
+    }
+
+    // follows the most work chain down from hash
+    fn best_tip(&self, hash: &BlockHash) -> BlockHash {
+        let mut tip = *hash;
+        while let Some(child_hash) =
+            self.best_child(&self.nodes.get(&tip).expect("node expected").children)
+        {
+            tip = *child_hash;
+        }
+        tip
+    }
+
+    // called with the losing children of the removed root, before their subtrees are purged
+    fn reorg_event(&self, losing_children: &BlockHashSet, fork_node: &TreeNode) -> ReorgEvent {
+        let abandoned_tip = self.best_tip(
+            self.best_child(losing_children)
+                .expect("losing child expected"),
+        );
+        let winning_tip = self.best_tip(self.tree_root.as_ref().expect("root hash expected"));
+        ReorgEvent {
+            abandoned_tip,
+            abandoned_blocks: self.subtree_hashes(losing_children.iter().copied()),
+            winning_tip,
+            fork_height: fork_node.block_info.height,
+            depth: self.nodes[&abandoned_tip].orig_level - fork_node.orig_level,
+        }
+    }
+
+    // the subtree roots and all their descendants, breadth first so that parents come before children
+    fn subtree_hashes(&self, roots: impl IntoIterator<Item = BlockHash>) -> Vec<BlockHash> {
+        let mut hashes = Vec::new();
+        let mut queue: VecDeque<BlockHash> = roots.into_iter().collect();
+        while let Some(hash) = queue.pop_front() {
+            hashes.push(hash);
+            queue.extend(
+                self.nodes
+                    .get(&hash)
+                    .expect("node expected")
+                    .children
+                    .iter()
+                    .copied(),
+            );
+        }
+        hashes
+    }
+
+    // After a child subtree was removed, the stats of the node and its ancestors may shrink,
+    // so unlike update_subtree_stats() they are recalculated from the children, up to the root of its tree
+    fn recalculate_subtree_stats(&mut self, block_hash: &BlockHash) {
+        let mut hash_opt = Some(*block_hash);
+        while let Some(hash) = hash_opt {
+            let node = &self.nodes[&hash];
+            let (subtree_depth, best_subtree_work) = node.children.iter().fold(
+                (1, node.chain_work),
+                |(subtree_depth, best_subtree_work), child_hash| {
+                    let child = &self.nodes[child_hash];
+                    (
+                        subtree_depth.max(child.subtree_depth + 1),
+                        best_subtree_work.max(child.best_subtree_work),
+                    )
+                },
+            );
+            let node = self.nodes.get_mut(&hash).expect("node expected");
+            node.subtree_depth = subtree_depth;
+            node.best_subtree_work = best_subtree_work;
+            hash_opt = node.parent;
+        }
+        self.update_tree_depth();
+    }
+
+    fn clear(&mut self) {
+        self.tree_root = None;
+        self.nodes.clear();
+        self.by_height.clear();
+        self.detached_roots.clear();
+        self.tree_depth = 0;
+        self.root_removed_cnt = 0;
+        self.next_root_height = 0;
+    }
+
+    fn is_ready(&self, depth_threshold: u32) -> bool {
+        let Some(root_hash) = &self.tree_root else {
+            return false;
+        };
+        match self.removal_policy {
+            RemovalPolicy::Depth => self.tree_depth >= depth_threshold,
+            RemovalPolicy::BestWork => {
+                let best_tip = self.best_tip(root_hash);
+                self.nodes[&best_tip].orig_level - self.nodes[root_hash].orig_level + 1
+                    >= depth_threshold
+            }
+        }
+    }
+
+    // walks the whole subtree, subtree_depth is checked against it in tests
+    #[cfg(test)]
+    fn calculate_depth_from_node(&self, block_hash: &BlockHash) -> u32 {
+        self.walk_depth(block_hash).0
+    }
+
+    // Depth of the subtree and the number of nodes in it, walked from block_hash.
+    // Iterative with a visited set, so that it terminates even if the links have a cycle,
+    // a missing child is not counted
+    fn walk_depth(&self, block_hash: &BlockHash) -> (u32, usize) {
+        let mut max_depth = 0;
+        let mut visited = BlockHashSet::default();
+        let mut stack = vec![(*block_hash, 1)];
+        while let Some((hash, depth)) = stack.pop() {
+            let Some(node) = self.nodes.get(&hash) else {
+                continue;
+            };
+            if !visited.insert(hash) {
+                continue;
+            }
+            max_depth = max_depth.max(depth);
+            for child_hash in node.children.iter() {
+                stack.push((*child_hash, depth + 1));
+            }
+        }
+        (max_depth, visited.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex_lit::hex;
+    use proptest::prelude::*;
+    use std::str::FromStr;
+
+    fn create_block_hash(hash: &str) -> BlockHash {
+        BlockHash::from_str(&hash.repeat(64)).unwrap()
+    }
+
+    fn create_block_info(hash: &str, prev_hash: &str) -> BlockInfo {
+        create_block_info_with_work(hash, prev_hash, 1)
+    }
+
+    fn create_block_info_with_work(hash: &str, prev_hash: &str, work: u8) -> BlockInfo {
+        let mut work_bytes = [0; 32];
+        work_bytes[31] = work;
+        BlockInfo {
+            hash: create_block_hash(hash),
+            prev_hash: create_block_hash(prev_hash),
+            work: Work::from_be_bytes(work_bytes),
+            height: 0,
+        }
+    }
+
+    fn create_dummy_block() -> bitcoin::Block {
+        const BLOCK_HEX: &str = "0200000035ab154183570282ce9afc0b494c9fc6a3cfea05aa8c1add2ecc56490000000038ba3d78e4500a5a7570dbe61960398add4410d278b21cd9708e6d9743f374d544fc055227f1001c29c1ea3b0101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff3703a08601000427f1001c046a510100522cfabe6d6d0000000000000000000068692066726f6d20706f6f6c7365727665726aac1eeeed88ffffffff0100f2052a010000001976a914912e2b234f941f30b18afbb4fa46171214bf66c888ac00000000";
+        deserialize(&hex!(BLOCK_HEX)).unwrap()
+    }
+
+    #[test]
+    fn test() {
+        let mut block_cache = BlockCache::new();
+
+        // Create an unbalanced tree with branches and a deepest branch of 10 levels
+        /*
+                              0
+                             / \
+                            1   2
+                           /   / \
+                          3   4   5
+                         /   /     \
+                        6   7       8
+                       /     \
+                      9       A
+                               \
+                                B
+                                 \
+                                  C
+        */
+        let blocks = vec![
+            create_block_info("0", "0"), // Level 0
+            create_block_info("8", "5"), // Level 4, out of order
+            create_block_info("4", "2"), // Level 2, out of order
+            create_block_info("5", "2"), // Level 2, out of order
+            create_block_info("1", "0"), // Level 1
+            create_block_info("2", "0"), // Level 1
+            create_block_info("A", "7"), // Level 4, out of order
+            create_block_info("7", "4"), // Level 3, out of order
+            create_block_info("9", "6"), // Level 4, out of order
+            create_block_info("3", "1"), // Level 2
+            create_block_info("6", "3"), // Level 3
+            create_block_info("B", "A"), // Level 5
+            create_block_info("C", "B"), // Level 6
+        ];
+        let dummy_block = create_dummy_block();
+
+        // Add blocks to the tree
+        for block_info in &blocks {
+            block_cache.add_block_impl(block_info, dummy_block.clone());
+        }
+        //dbg!(&block_cache);
+        assert_eq!(block_cache.staged_blocks.tree_depth, 7);
+        assert_eq!(block_cache.staged_cnt(), 13);
+        assert_eq!(block_cache.out_of_order_cnt(), 0);
+
+        let expected_roots = vec!["0", "2", "4"];
         for expected_root in expected_roots {
             let (block_info_opt, _block_opt) = block_cache.remove_block_if_ready_impl(4);
             let block_info = block_info_opt.expect("root removal expected");
@@ -367,32 +1965,1290 @@ mod tests {
                 block_info.hash
             );
         }
-        assert_eq!(block_cache.staged_blocks.tree_depth, 4);
-        assert_eq!(block_cache.staged_blocks.root_removed_cnt, 3);
-        let root_hash = block_cache
-            .staged_blocks
-            .tree_root
-            .as_ref()
-            .expect("root hash expected");
+        assert_eq!(block_cache.staged_blocks.tree_depth, 4);
+        assert_eq!(block_cache.staged_blocks.root_removed_cnt, 3);
+        let root_hash = block_cache
+            .staged_blocks
+            .tree_root
+            .as_ref()
+            .expect("root hash expected");
+        assert_eq!(
+            root_hash,
+            &create_block_hash("7"),
+            "expected root: 7, but got: {:?}",
+            root_hash
+        );
+        let node = block_cache
+            .staged_blocks
+            .nodes
+            .get(&create_block_hash("A"))
+            .expect("node expected");
+        assert_eq!(node.orig_level, 5);
+        assert_eq!(
+            node.parent.as_ref().expect("parent expected"),
+            &create_block_hash("7")
+        );
+        let mut children = BlockHashSet::default();
+        children.insert(create_block_hash("B"));
+        assert_eq!(&node.children, &children);
+        //dbg!(&block_cache);
+    }
+
+    #[test]
+    fn test_subtree_depth() {
+        let mut block_cache = BlockCache::new();
+        let dummy_block = create_dummy_block();
+        let blocks = [
+            ("0", "0"),
+            ("8", "5"),
+            ("4", "2"),
+            ("5", "2"),
+            ("1", "0"),
+            ("2", "0"),
+            ("A", "7"),
+            ("7", "4"),
+            ("9", "6"),
+            ("3", "1"),
+            ("6", "3"),
+            ("B", "A"),
+            ("C", "B"),
+        ];
+        let check_subtree_depths = |block_cache: &BlockCache| {
+            let staged_blocks = &block_cache.staged_blocks;
+            for (hash, node) in staged_blocks.nodes.iter() {
+                assert_eq!(
+                    node.subtree_depth,
+                    staged_blocks.calculate_depth_from_node(hash),
+                    "subtree depth of {:?}",
+                    hash
+                );
+            }
+        };
+        for (hash, prev_hash) in blocks {
+            block_cache.add_block_impl(&create_block_info(hash, prev_hash), dummy_block.clone());
+            check_subtree_depths(&block_cache);
+        }
+        let root_node = block_cache
+            .staged_blocks
+            .nodes
+            .get(&create_block_hash("0"))
+            .expect("root node expected");
+        assert_eq!(root_node.subtree_depth, 7);
+        while block_cache.remove_block_if_ready_impl(4).0.is_some() {
+            check_subtree_depths(&block_cache);
+        }
+    }
+
+    #[test]
+    fn test_most_work_wins() {
+        let mut block_cache = BlockCache::new();
+        let dummy_block = create_dummy_block();
+        /*
+                 0
+                / \
+               1   4    1-2-3 is deeper, but 4-5 has more work
+               |   |
+               2   5
+               |
+               3
+        */
+        let blocks = [
+            ("0", "0", 1),
+            ("1", "0", 1),
+            ("2", "1", 1),
+            ("3", "2", 1),
+            ("4", "0", 2),
+            ("5", "4", 2),
+        ];
+        for (hash, prev_hash, work) in blocks {
+            block_cache.add_block_impl(
+                &create_block_info_with_work(hash, prev_hash, work),
+                dummy_block.clone(),
+            );
+        }
+        let (block_info_opt, _block_opt) = block_cache.remove_block_if_ready_impl(4);
+        assert_eq!(
+            block_info_opt.expect("root removal expected").hash,
+            create_block_hash("0")
+        );
+        assert_eq!(
+            block_cache.staged_blocks.tree_root,
+            Some(create_block_hash("4"))
+        );
+        assert_eq!(block_cache.staged_cnt(), 2);
+        assert!(!block_cache.contains_block(&create_block_hash("3")));
+        let mut purged = block_cache.last_purged().to_vec();
+        purged.sort();
+        let mut expected = vec![
+            create_block_hash("1"),
+            create_block_hash("2"),
+            create_block_hash("3"),
+        ];
+        expected.sort();
+        assert_eq!(purged, expected);
+        let reorg = block_cache.last_reorg().expect("reorg expected");
+        assert_eq!(reorg.abandoned_tip, create_block_hash("3"));
+        assert_eq!(reorg.winning_tip, create_block_hash("5"));
+        assert_eq!(reorg.abandoned_blocks[0], create_block_hash("1"));
+        assert_eq!(reorg.fork_height, 0);
+        assert_eq!(reorg.depth, 3);
+        assert_eq!(block_cache.deepest_reorg_seen(), 3);
+        // the purged losing branch was deeper than the new root's subtree
+        assert_eq!(block_cache.staged_blocks.tree_depth, 2);
+        assert!(block_cache.peek_ready(3).is_none());
+    }
+
+    #[test]
+    fn test_single_block_tree() {
+        let mut block_cache = BlockCache::new();
+        block_cache.add_block_impl(&create_block_info("0", "0"), create_dummy_block());
+        assert_eq!(block_cache.staged_blocks.tree_depth, 1);
+        assert!(block_cache.remove_block_if_ready(2).is_none());
+        assert!(block_cache.remove_block_if_ready(0).is_some());
+        assert_eq!(block_cache.staged_blocks.tree_depth, 0);
+        assert_eq!(block_cache.staged_cnt(), 0);
+    }
+
+    #[test]
+    fn test_tie_break() {
+        let dummy_block = create_dummy_block();
+        // 0-1-3 and 0-2-4 have equal depth and work, in whatever order they are added
+        let blocks = [("1", "0"), ("2", "0"), ("3", "1"), ("4", "2")];
+        for order in [[0, 1, 2, 3], [1, 0, 3, 2], [3, 2, 1, 0], [1, 3, 0, 2]] {
+            for _ in 0..10 {
+                let mut block_cache = BlockCache::new();
+                block_cache.add_block_impl(&create_block_info("0", "0"), dummy_block.clone());
+                for i in order {
+                    let (hash, prev_hash) = blocks[i];
+                    block_cache
+                        .add_block_impl(&create_block_info(hash, prev_hash), dummy_block.clone());
+                }
+                block_cache.remove_block_if_ready(3);
+                assert_eq!(
+                    block_cache.staged_blocks.tree_root,
+                    Some(create_block_hash("1"))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_duplicate_block() {
+        let mut block_cache = BlockCache::new();
+        let genesis = bitcoin::constants::genesis_block(bitcoin::Network::Bitcoin);
+        assert!(block_cache.add_block(genesis.clone()).is_added());
+        assert!(!block_cache.add_block(genesis).is_added());
+        assert_eq!(block_cache.pending_cnt(), 1);
+        assert_eq!(block_cache.staged_cnt(), 1);
+
+        // a duplicate of a staged child or of an out of order block is rejected too
+        let mut block_cache = BlockCache::new();
+        let dummy_block = create_dummy_block();
+        for (hash, prev_hash) in [("0", "0"), ("1", "0"), ("3", "2")] {
+            assert!(block_cache
+                .add_block_impl(&create_block_info(hash, prev_hash), dummy_block.clone()));
+            assert!(!block_cache
+                .add_block_impl(&create_block_info(hash, prev_hash), dummy_block.clone()));
+        }
+        let node = block_cache
+            .staged_blocks
+            .nodes
+            .get(&create_block_hash("1"))
+            .expect("node expected");
+        assert_eq!(node.orig_level, 2);
+        assert_eq!(
+            block_cache.staged_blocks.detached_roots[&create_block_hash("2")].len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_prev_hash_cycle() {
+        let dummy_block = create_dummy_block();
+
+        // A->B->A never connects to the main tree, which has as much work
+        let mut block_cache = BlockCache::new();
+        block_cache.add_block_impl(&create_block_info("0", "0"), dummy_block.clone());
+        block_cache.add_block_impl(&create_block_info("1", "0"), dummy_block.clone());
+        block_cache.add_block_impl(&create_block_info("A", "B"), dummy_block.clone());
+        block_cache.add_block_impl(&create_block_info("B", "A"), dummy_block.clone());
+        assert_eq!(block_cache.staged_cnt(), 2);
+        assert_eq!(block_cache.out_of_order_cnt(), 2);
+        assert!(!block_cache.add_block_impl(&create_block_info("C", "C"), dummy_block.clone()));
+
+        // A becomes the first root and B its child, the cycle ends at the root
+        let mut block_cache = BlockCache::new();
+        block_cache.add_block_impl(&create_block_info("A", "B"), dummy_block.clone());
+        block_cache.add_block_impl(&create_block_info("B", "A"), dummy_block.clone());
+        assert_eq!(block_cache.staged_cnt(), 2);
+        assert_eq!(block_cache.out_of_order_cnt(), 0);
+        assert_eq!(
+            block_cache
+                .staged_blocks
+                .calculate_depth_from_node(&create_block_hash("A")),
+            2
+        );
+    }
+
+    #[test]
+    fn test_evict_orphans() {
+        let mut block_cache = BlockCache::new();
+        let dummy_block = create_dummy_block();
+        for (hash, prev_hash) in [("0", "0"), ("3", "2"), ("1", "0"), ("5", "4"), ("6", "1")] {
+            block_cache.add_block_impl(&create_block_info(hash, prev_hash), dummy_block.clone());
+        }
+        // 3 was added 4 blocks ago and 5 2 blocks ago
+        assert_eq!(block_cache.evict_orphans_older_than(4), 0);
+        assert_eq!(block_cache.evict_orphans_older_than(3), 1);
+        assert!(!block_cache.contains_block(&create_block_hash("3")));
+        assert_eq!(block_cache.out_of_order_cnt(), 1);
+        assert_eq!(block_cache.pending_cnt(), 4);
+
+        // the evicted block can connect when it is added again
+        block_cache.add_block_impl(&create_block_info("2", "1"), dummy_block.clone());
+        block_cache.add_block_impl(&create_block_info("3", "2"), dummy_block.clone());
+        assert_eq!(block_cache.staged_cnt(), 5);
+        assert_eq!(block_cache.evict_orphans_older_than(0), 1);
+        assert_eq!(block_cache.out_of_order_cnt(), 0);
+        assert_eq!(block_cache.pending_cnt(), 5);
+    }
+
+    #[test]
+    fn test_contains_block() {
+        let mut block_cache = BlockCache::new();
+        assert!(!block_cache.contains_block(&create_block_hash("0")));
+
+        // pending only
+        block_cache
+            .pending_full_blocks
+            .insert(create_block_hash("0"), create_dummy_block());
+        assert!(block_cache.contains_block(&create_block_hash("0")));
+
+        // staged only
+        block_cache
+            .staged_blocks
+            .add_block_info(&create_block_info("1", "0"), 0);
+        assert!(block_cache.contains_block(&create_block_hash("1")));
+
+        // in a detached tree only
+        block_cache
+            .staged_blocks
+            .add_block_info(&create_block_info("3", "2"), 0);
+        assert!(block_cache.contains_block(&create_block_hash("3")));
+        // the detached roots are keyed by prev_hash, which is not in the cache
+        assert!(!block_cache.contains_block(&create_block_hash("2")));
+    }
+
+    #[test]
+    fn test_get_block() {
+        let mut block_cache = BlockCache::new();
+        let dummy_block = create_dummy_block();
+        block_cache.add_block_impl(&create_block_info("0", "0"), dummy_block.clone());
+        block_cache.add_block_impl(&create_block_info("1", "0"), dummy_block.clone());
+
+        assert_eq!(
+            block_cache.get_block(&create_block_hash("1")),
+            Some(&dummy_block)
+        );
+        assert_eq!(block_cache.get_block(&create_block_hash("2")), None);
+        assert_eq!(block_cache.staged_cnt(), 2);
+        assert_eq!(block_cache.pending_cnt(), 2);
+        assert_eq!(block_cache.staged_blocks.root_removed_cnt, 0);
+    }
+
+    #[test]
+    fn test_peek_ready() {
+        let mut block_cache = BlockCache::new();
+        let dummy_block = create_dummy_block();
+        for (hash, prev_hash) in [("0", "0"), ("1", "0"), ("2", "1")] {
+            block_cache.add_block_impl(&create_block_info(hash, prev_hash), dummy_block.clone());
+        }
+        assert!(block_cache.peek_ready(4).is_none());
+        let block_info = block_cache.peek_ready(2).expect("ready root expected");
+        assert_eq!(block_info.hash, create_block_hash("0"));
+        assert_eq!(block_cache.staged_blocks.tree_depth, 3);
+        assert_eq!(block_cache.staged_blocks.root_removed_cnt, 0);
+        assert_eq!(
+            block_cache.staged_blocks.tree_root,
+            Some(create_block_hash("0"))
+        );
+
+        let (removed_opt, _block_opt) = block_cache.remove_block_if_ready_impl(2);
+        assert_eq!(
+            removed_opt.expect("root removal expected").hash,
+            create_block_hash("0")
+        );
+        assert_eq!(
+            block_cache.peek_ready(2).map(|block_info| block_info.hash),
+            Some(create_block_hash("1"))
+        );
+    }
+
+    #[test]
+    fn test_staged_iter() {
+        let mut block_cache = BlockCache::new();
+        let dummy_block = create_dummy_block();
+        for (hash, prev_hash) in [("0", "0"), ("1", "0"), ("2", "0"), ("4", "3")] {
+            block_cache.add_block_impl(&create_block_info(hash, prev_hash), dummy_block.clone());
+        }
+
+        let mut staged: Vec<_> = block_cache
+            .staged_iter()
+            .map(|(hash, node)| (*hash, node.parent().copied(), node.children().len()))
+            .collect();
+        staged.sort();
+        let root_hash = create_block_hash("0");
+        let mut expected = vec![
+            (root_hash, None, 2),
+            (create_block_hash("1"), Some(root_hash), 0),
+            (create_block_hash("2"), Some(root_hash), 0),
+            // the out of order block is the root of a detached tree
+            (create_block_hash("4"), None, 0),
+        ];
+        expected.sort();
+        assert_eq!(staged, expected);
+        assert_eq!(block_cache.pending_iter().count(), 4);
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut block_cache = BlockCache::new();
+        let dummy_block = create_dummy_block();
+        for (hash, prev_hash) in [("0", "0"), ("1", "0"), ("2", "1"), ("4", "3")] {
+            block_cache.add_block_impl(&create_block_info(hash, prev_hash), dummy_block.clone());
+        }
+        block_cache.remove_block_if_ready(2);
+
+        block_cache.clear();
+        assert_eq!(block_cache.pending_cnt(), 0);
+        assert_eq!(block_cache.staged_cnt(), 0);
+        assert_eq!(block_cache.out_of_order_cnt(), 0);
+        assert_eq!(block_cache.staged_blocks.tree_root, None);
+        assert_eq!(block_cache.staged_blocks.tree_depth, 0);
+        assert_eq!(block_cache.staged_blocks.root_removed_cnt, 0);
+
+        // the next block becomes the first root, even though its parent was never added
+        block_cache.add_block_impl(&create_block_info("5", "4"), dummy_block.clone());
+        assert_eq!(
+            block_cache.staged_blocks.tree_root,
+            Some(create_block_hash("5"))
+        );
+        let node = block_cache
+            .staged_blocks
+            .nodes
+            .get(&create_block_hash("5"))
+            .expect("node expected");
+        assert_eq!(node.orig_level, 1);
+        assert_eq!(block_cache.out_of_order_cnt(), 0);
+    }
+
+    #[test]
+    fn test_spill() {
+        let dummy_block = create_dummy_block();
+        // room for two blocks
+        let mut block_cache = BlockCache::with_limits(2 * dummy_block.total_size());
+        for (hash, prev_hash) in [("0", "0"), ("1", "0"), ("2", "1"), ("3", "2"), ("5", "4")] {
+            block_cache.add_block_impl(&create_block_info(hash, prev_hash), dummy_block.clone());
+        }
+        assert_eq!(block_cache.pending_cnt(), 5);
+        assert_eq!(block_cache.spilled_cnt(), 3);
+        assert_eq!(block_cache.pending_full_blocks.len(), 2);
+        // the oldest blocks are spilled
+        assert!(block_cache.get_block(&create_block_hash("0")).is_none());
+        assert!(block_cache.get_block(&create_block_hash("5")).is_some());
+        assert!(block_cache.contains_block(&create_block_hash("0")));
+        let spill_dir = block_cache.spill.dir.clone().expect("spill dir expected");
+        assert_eq!(std::fs::read_dir(&spill_dir).unwrap().count(), 3);
+
+        let block = block_cache
+            .remove_block_if_ready(2)
+            .expect("root removal expected");
+        assert_eq!(block, dummy_block);
+        assert_eq!(block_cache.spilled_cnt(), 2);
+        assert_eq!(std::fs::read_dir(&spill_dir).unwrap().count(), 2);
+
+        drop(block_cache);
+        assert!(!spill_dir.exists());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_save_load() {
+        let mut block_cache = BlockCache::new();
+        let dummy_block = create_dummy_block();
+        for (hash, prev_hash) in [("0", "0"), ("1", "0"), ("2", "1"), ("3", "1"), ("5", "4")] {
+            block_cache.add_block_impl(&create_block_info(hash, prev_hash), dummy_block.clone());
+        }
+        block_cache.remove_block_if_ready(2);
+
+        let path = std::env::temp_dir().join(format!("read-blk-cache-{}", std::process::id()));
+        block_cache.save(&path).unwrap();
+        let loaded_cache = BlockCache::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded_cache.pending_cnt(), block_cache.pending_cnt());
+        assert_eq!(loaded_cache.staged_cnt(), block_cache.staged_cnt());
+        assert_eq!(
+            loaded_cache.out_of_order_cnt(),
+            block_cache.out_of_order_cnt()
+        );
+        assert_eq!(
+            loaded_cache.staged_blocks.tree_root,
+            Some(create_block_hash("1"))
+        );
+        assert_eq!(
+            loaded_cache.staged_blocks.tree_depth,
+            block_cache.staged_blocks.tree_depth
+        );
+        assert_eq!(loaded_cache.staged_blocks.root_removed_cnt, 1);
+        assert_eq!(
+            loaded_cache.get_block(&create_block_hash("5")),
+            Some(&dummy_block)
+        );
+    }
+
+    #[test]
+    fn test_threshold() {
+        let mut block_cache = BlockCache::new_with_threshold(3);
+        let dummy_block = create_dummy_block();
+        for (hash, prev_hash) in [("0", "0"), ("1", "0"), ("2", "1")] {
+            block_cache.add_block_impl(&create_block_info(hash, prev_hash), dummy_block.clone());
+        }
+        assert!(block_cache.remove_block_if_ready_default().is_some());
+        assert!(block_cache.remove_block_if_ready_default().is_none());
+        block_cache.set_threshold(2);
+        assert!(block_cache.remove_block_if_ready_default().is_some());
+        // draining ignores the cache threshold
+        assert!(block_cache.remove_block_if_ready(0).is_some());
+        assert_eq!(block_cache.staged_cnt(), 0);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut block_cache = BlockCache::new();
         assert_eq!(
-            root_hash,
-            &create_block_hash("7"),
-            "expected root: 7, but got: {:?}",
-            root_hash
+            block_cache.stats().to_string(),
+            "pending 0 staged 0 out of order 0 tree depth 0 roots removed 0 root none"
         );
-        let node = block_cache
-            .staged_blocks
+        let dummy_block = create_dummy_block();
+        for (hash, prev_hash) in [("0", "0"), ("1", "0"), ("2", "1"), ("5", "4")] {
+            block_cache.add_block_impl(&create_block_info(hash, prev_hash), dummy_block.clone());
+        }
+        block_cache.remove_block_if_ready(2);
+        assert_eq!(
+            block_cache.stats(),
+            CacheStats {
+                pending_cnt: 3,
+                staged_cnt: 2,
+                out_of_order_cnt: 1,
+                tree_depth: 2,
+                root_removed_cnt: 1,
+                root_hash: Some(create_block_hash("1")),
+            }
+        );
+    }
+
+    #[test]
+    fn test_heights() {
+        let mut block_cache = BlockCache::new();
+        block_cache.set_base_height(10);
+        let dummy_block = create_dummy_block();
+        // 3 arrives before its parent 2
+        for (hash, prev_hash) in [("0", "0"), ("1", "0"), ("3", "2"), ("2", "1"), ("4", "1")] {
+            block_cache.add_block_impl(&create_block_info(hash, prev_hash), dummy_block.clone());
+        }
+        let heights: Vec<_> = ["0", "1", "2", "3", "4"]
+            .iter()
+            .map(|hash| block_cache.height(&create_block_hash(hash)))
+            .collect();
+        assert_eq!(
+            heights,
+            vec![Some(10), Some(11), Some(12), Some(13), Some(12)]
+        );
+
+        // 4 is purged when 1 migrates
+        let mut migrated = Vec::new();
+        let mut fork_heights = Vec::new();
+        while let Some((_, height)) = block_cache.remove_block_with_height_if_ready(0).unwrap() {
+            migrated.push(height);
+            fork_heights.extend(block_cache.last_reorg().map(|reorg| reorg.fork_height));
+        }
+        assert_eq!(migrated, vec![10, 11, 12, 13]);
+        assert_eq!(fork_heights, vec![11]);
+
+        // a new tree continues from the last migrated block
+        block_cache.add_block_impl(&create_block_info("5", "3"), dummy_block);
+        assert_eq!(block_cache.height(&create_block_hash("5")), Some(14));
+    }
+
+    #[test]
+    fn test_staged_by_height() {
+        let mut block_cache = BlockCache::new();
+        block_cache.set_base_height(10);
+        let dummy_block = create_dummy_block();
+        for (hash, prev_hash) in [("0", "0"), ("1", "0"), ("3", "2"), ("2", "1"), ("4", "1")] {
+            block_cache.add_block_impl(&create_block_info(hash, prev_hash), dummy_block.clone());
+        }
+        let by_height = |block_cache: &BlockCache| {
+            block_cache
+                .staged_by_height()
+                .iter()
+                .map(|(height, hashes)| {
+                    let mut hashes: Vec<_> = hashes.iter().copied().collect();
+                    hashes.sort();
+                    (*height, hashes)
+                })
+                .collect::<Vec<_>>()
+        };
+        let h = create_block_hash;
+        // 3 was staged at a provisional height until 2 arrived
+        assert_eq!(
+            by_height(&block_cache),
+            vec![
+                (10, vec![h("0")]),
+                (11, vec![h("1")]),
+                (12, vec![h("2"), h("4")]),
+                (13, vec![h("3")]),
+            ]
+        );
+        assert_eq!(block_cache.staged_by_height()[&12].len(), 2);
+
+        // 4 is purged when 1 migrates
+        block_cache.remove_block_if_ready(0);
+        block_cache.remove_block_if_ready(0);
+        assert_eq!(
+            by_height(&block_cache),
+            vec![(12, vec![h("2")]), (13, vec![h("3")])]
+        );
+        block_cache.clear();
+        assert!(block_cache.staged_by_height().is_empty());
+    }
+
+    #[test]
+    fn test_genesis_root() {
+        let mut block_cache = BlockCache::new();
+        let genesis = genesis_block(Network::Bitcoin);
+        let genesis_hash =
+            BlockHash::from_str("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f")
+                .unwrap();
+        assert_eq!(genesis.block_hash(), genesis_hash);
+        let mut block1 = genesis.clone();
+        block1.header.prev_blockhash = genesis_hash;
+        let mut block2 = genesis.clone();
+        block2.header.prev_blockhash = block1.block_hash();
+        // an orphan with the same all-zero prev_hash arrives first and becomes the root
+        let mut orphan = genesis.clone();
+        orphan.header.nonce += 1;
+
+        for block in [&orphan, &block1, &block2, &genesis] {
+            assert!(block_cache.add_block(block.clone()).is_added());
+        }
+        assert_eq!(block_cache.staged_blocks.tree_root, Some(genesis_hash));
+        assert_eq!(block_cache.staged_cnt(), 3);
+        assert_eq!(block_cache.out_of_order_cnt(), 1);
+        assert_eq!(block_cache.height(&block2.block_hash()), Some(2));
+        assert_eq!(block_cache.staged_blocks.tree_depth, 3);
+
+        assert_eq!(block_cache.remove_block_if_ready(0), Some(genesis.clone()));
+        // genesis can't come back once blocks migrated
+        assert!(!block_cache.add_block(genesis).is_added());
+    }
+
+    #[test]
+    fn test_rollback() {
+        let mut block_cache = BlockCache::new();
+        block_cache.set_rollback_depth(2);
+        let dummy_block = create_dummy_block();
+        for (hash, prev_hash) in [("0", "0"), ("1", "0"), ("2", "1"), ("3", "2")] {
+            block_cache.add_block_impl(&create_block_info(hash, prev_hash), dummy_block.clone());
+        }
+        for _ in 0..3 {
+            assert!(block_cache.remove_block_if_ready(0).is_some());
+        }
+        assert_eq!(block_cache.stats().root_removed_cnt, 3);
+
+        assert_eq!(
+            block_cache.rollback_last_removed(),
+            Some(create_block_hash("2"))
+        );
+        assert_eq!(
+            block_cache.rollback_last_removed(),
+            Some(create_block_hash("1"))
+        );
+        // only the last 2 removals are kept
+        assert_eq!(block_cache.rollback_last_removed(), None);
+        let stats = block_cache.stats();
+        assert_eq!(stats.root_hash, Some(create_block_hash("1")));
+        assert_eq!(stats.tree_depth, 3);
+        assert_eq!(stats.root_removed_cnt, 1);
+        assert_eq!(stats.pending_cnt, 3);
+        assert!(block_cache.peek_ready(3).is_some());
+
+        let (_, height) = block_cache
+            .remove_block_with_height_if_ready(3)
+            .unwrap()
+            .unwrap();
+        assert_eq!(height, 1);
+    }
+
+    #[test]
+    fn test_getters() {
+        let mut block_cache = BlockCache::new();
+        assert_eq!(block_cache.current_root(), None);
+        assert_eq!(block_cache.tree_depth(), 0);
+        for (hash, prev_hash) in [("0", "0"), ("1", "0"), ("2", "1")] {
+            block_cache.add_block_impl(&create_block_info(hash, prev_hash), create_dummy_block());
+        }
+        assert_eq!(block_cache.current_root(), Some(create_block_hash("0")));
+        assert_eq!(block_cache.tree_depth(), 3);
+        block_cache.remove_block_if_ready(3);
+        assert_eq!(block_cache.current_root(), Some(create_block_hash("1")));
+        assert_eq!(block_cache.tree_depth(), 2);
+        assert_eq!(block_cache.blocks_migrated(), 1);
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let mut block_cache = BlockCache::new();
+        for (hash, prev_hash) in [("0", "0"), ("1", "0"), ("2", "0")] {
+            block_cache.add_block_impl(&create_block_info(hash, prev_hash), create_dummy_block());
+        }
+        let (hash0, hash1, hash2) = (
+            create_block_hash("0"),
+            create_block_hash("1"),
+            create_block_hash("2"),
+        );
+        assert_eq!(
+            block_cache.to_dot(),
+            format!(
+                "digraph staged_blocks {{\n\
+                 \x20 \"{hash0}\" [label=\"00000000\\n1\", style=filled];\n\
+                 \x20 \"{hash1}\" [label=\"11111111\\n2\"];\n\
+                 \x20 \"{hash2}\" [label=\"22222222\\n2\"];\n\
+                 \x20 \"{hash0}\" -> \"{hash1}\";\n\
+                 \x20 \"{hash0}\" -> \"{hash2}\";\n\
+                 }}\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_raw_block() {
+        let mut block_cache = BlockCache::new();
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut child = genesis.clone();
+        child.header.prev_blockhash = genesis.block_hash();
+        assert!(block_cache
+            .add_raw_block(serialize(&genesis))
+            .unwrap()
+            .is_added());
+        // the body doesn't decode
+        let mut child_bytes = serialize(&child.header);
+        child_bytes.push(0xff);
+        assert!(block_cache.add_raw_block(child_bytes).unwrap().is_added());
+        assert!(block_cache.add_raw_block(vec![0; 10]).is_err());
+
+        assert_eq!(block_cache.staged_cnt(), 2);
+        assert!(block_cache
+            .get_block(&genesis.block_hash())
+            .and_then(PendingBlock::block)
+            .is_none());
+        let block = block_cache.remove_block_if_ready(0).unwrap();
+        assert_eq!(block.into_block().unwrap(), genesis);
+        let block = block_cache.remove_block_if_ready(0).unwrap();
+        assert!(block.into_block().is_err());
+        assert_eq!(block_cache.pending_cnt(), 0);
+    }
+
+    #[test]
+    fn test_remove_subtree() {
+        /*
+                0
+                |
+                1
+               / \
+              2   4
+              |
+              3
+        */
+        let mut block_cache = BlockCache::new();
+        let dummy_block = create_dummy_block();
+        for (hash, prev_hash) in [("0", "0"), ("1", "0"), ("2", "1"), ("3", "2"), ("4", "1")] {
+            block_cache.add_block_impl(&create_block_info(hash, prev_hash), dummy_block.clone());
+        }
+        assert_eq!(block_cache.tree_depth(), 4);
+
+        let removed = block_cache.remove_subtree(&create_block_hash("2"));
+        assert_eq!(
+            removed,
+            BlockHashSet::from_iter([create_block_hash("2"), create_block_hash("3")])
+        );
+        assert!(!block_cache.contains_block(&create_block_hash("2")));
+        assert!(!block_cache.contains_block(&create_block_hash("3")));
+        assert_eq!(block_cache.pending_cnt(), 3);
+        assert_eq!(block_cache.staged_cnt(), 3);
+        let node_1 = &block_cache.staged_blocks.nodes[&create_block_hash("1")];
+        assert_eq!(
+            node_1.children,
+            BlockHashSet::from_iter([create_block_hash("4")])
+        );
+        assert_eq!(node_1.subtree_depth, 2);
+        assert_eq!(block_cache.tree_depth(), 3);
+        assert_eq!(
+            block_cache
+                .staged_blocks
+                .calculate_depth_from_node(&create_block_hash("0")),
+            3
+        );
+
+        assert!(block_cache
+            .remove_subtree(&create_block_hash("3"))
+            .is_empty());
+        assert_eq!(block_cache.remove_subtree(&create_block_hash("0")).len(), 3);
+        assert_eq!(block_cache.tree_depth(), 0);
+        assert_eq!(block_cache.current_root(), None);
+        assert_eq!(block_cache.pending_cnt(), 0);
+    }
+
+    #[test]
+    fn test_drain_ready() {
+        let mut block_cache = BlockCache::new();
+        let dummy_block = create_dummy_block();
+        for (hash, prev_hash) in [("0", "0"), ("1", "0"), ("2", "1"), ("3", "2"), ("4", "1")] {
+            block_cache.add_block_impl(&create_block_info(hash, prev_hash), dummy_block.clone());
+        }
+        assert_eq!(block_cache.drain_ready(3).len(), 2);
+        assert_eq!(block_cache.current_root(), Some(create_block_hash("2")));
+        assert!(block_cache.drain_ready(3).is_empty());
+
+        assert_eq!(block_cache.drain_ready(0).len(), 2);
+        assert_eq!(block_cache.staged_cnt(), 0);
+        assert_eq!(block_cache.pending_cnt(), 0);
+        assert_eq!(block_cache.blocks_migrated(), 4);
+    }
+
+    #[test]
+    fn test_missing_parent() {
+        let mut block_cache = BlockCache::new();
+        let dummy_block = create_dummy_block();
+        block_cache.add_block_impl(&create_block_info("0", "0"), dummy_block.clone());
+        block_cache.add_block_impl(&create_block_info("2", "1"), dummy_block.clone());
+        assert_eq!(block_cache.staged_cnt(), 1);
+        assert_eq!(block_cache.out_of_order_cnt(), 1);
+        assert_eq!(
+            block_cache.staged_blocks.detached_roots[&create_block_hash("1")],
+            BlockHashSet::from_iter([create_block_hash("2")])
+        );
+        assert_eq!(
+            block_cache.staged_blocks.nodes[&create_block_hash("2")].added_seq,
+            1
+        );
+        // not in the main tree yet, so no height
+        assert_eq!(block_cache.height(&create_block_hash("2")), None);
+
+        block_cache.add_block_impl(&create_block_info("1", "0"), dummy_block.clone());
+        assert_eq!(block_cache.staged_cnt(), 3);
+        assert_eq!(block_cache.out_of_order_cnt(), 0);
+        assert_eq!(block_cache.height(&create_block_hash("2")), Some(2));
+    }
+
+    #[test]
+    fn test_out_of_order_duplicate() {
+        let mut block_cache = BlockCache::new();
+        let dummy_block = create_dummy_block();
+        block_cache.add_block_impl(&create_block_info("0", "0"), dummy_block.clone());
+        assert!(block_cache.add_block_impl(&create_block_info("9", "1"), dummy_block.clone()));
+        assert!(!block_cache.add_block_impl(&create_block_info("9", "1"), dummy_block.clone()));
+        assert_eq!(block_cache.out_of_order_cnt(), 1);
+
+        block_cache.add_block_impl(&create_block_info("1", "0"), dummy_block.clone());
+        assert_eq!(block_cache.out_of_order_cnt(), 0);
+        assert_eq!(block_cache.staged_cnt(), 3);
+        let node_1 = &block_cache.staged_blocks.nodes[&create_block_hash("1")];
+        assert_eq!(
+            node_1.children,
+            BlockHashSet::from_iter([create_block_hash("9")])
+        );
+        assert_eq!(
+            block_cache.staged_blocks.nodes[&create_block_hash("9")].orig_level,
+            3
+        );
+        assert_eq!(block_cache.tree_depth(), 3);
+    }
+
+    #[test]
+    fn test_stale_margin() {
+        // created with BIP34 height 100000
+        let dummy_block = create_dummy_block();
+        let mut block_cache = BlockCache::new();
+        block_cache.set_base_height(100050);
+        block_cache.add_block_impl(&create_block_info("0", "0"), dummy_block.clone());
+        assert_eq!(block_cache.root_height(), Some(100050));
+
+        block_cache.set_stale_margin(10);
+        assert!(!block_cache.add_block(dummy_block.clone()).is_added());
+        assert_eq!(block_cache.pending_cnt(), 1);
+
+        block_cache.set_stale_margin(50);
+        assert!(block_cache.add_block(dummy_block).is_added());
+        assert_eq!(block_cache.out_of_order_cnt(), 1);
+    }
+
+    #[test]
+    fn test_source_of() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut next_block = genesis.clone();
+        next_block.header.prev_blockhash = genesis.block_hash();
+        let mut block_cache = BlockCache::new();
+        let source = SourceLoc {
+            file_num: 3,
+            offset: 293,
+        };
+        assert!(block_cache
+            .add_block_from(genesis.clone().into(), source)
+            .is_added());
+        assert!(block_cache
+            .add_raw_block_from(
+                serialize(&next_block),
+                SourceLoc {
+                    file_num: 4,
+                    offset: 0
+                }
+            )
+            .unwrap()
+            .is_added());
+        assert!(!block_cache
+            .add_block_from(
+                genesis.clone().into(),
+                SourceLoc {
+                    file_num: 5,
+                    offset: 0
+                }
+            )
+            .is_added());
+        assert_eq!(block_cache.source_of(&genesis.block_hash()), Some(source));
+        assert_eq!(
+            block_cache
+                .source_of(&next_block.block_hash())
+                .unwrap()
+                .file_num,
+            4
+        );
+
+        block_cache.remove_block_if_ready(0);
+        assert_eq!(block_cache.source_of(&genesis.block_hash()), None);
+        block_cache.add_block(genesis.into());
+        assert_eq!(
+            block_cache
+                .source_of(&next_block.block_hash())
+                .unwrap()
+                .offset,
+            0
+        );
+    }
+
+    #[test]
+    fn test_add_header() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut block_cache = BlockCache::new();
+        assert!(block_cache.add_header(genesis.header).is_added());
+        assert!(!block_cache.add_block(genesis.clone().into()).is_added());
+        assert_eq!(
+            block_cache.get_block(&genesis.block_hash()),
+            Some(&PendingBlock::Header(genesis.header))
+        );
+
+        let block = block_cache
+            .remove_block_if_ready(0)
+            .unwrap()
+            .into_block()
+            .unwrap();
+        assert_eq!(block.header, genesis.header);
+        assert!(block.txdata.is_empty());
+    }
+
+    #[test]
+    fn test_header_payload() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut next_header = genesis.header;
+        next_header.prev_blockhash = genesis.block_hash();
+        let mut block_cache: BlockCache<Header> = BlockCache::with_limits(Header::SIZE);
+        assert!(block_cache.add_block(genesis.header).is_added());
+        assert!(block_cache.add_block(next_header).is_added());
+        assert_eq!(block_cache.spilled_cnt(), 1);
+        assert_eq!(block_cache.remove_block_if_ready(0), Some(genesis.header));
+        assert_eq!(
+            block_cache.remove_block_with_height_if_ready(0).unwrap(),
+            Some((next_header, 1))
+        );
+        assert_eq!(block_cache.pending_cnt(), 0);
+    }
+
+    // Block infos of a random tree of up to 60 blocks: every block extends one of the last few blocks, mostly
+    // the last one, with a work of 1 to 3. In a shuffled add order, so that some blocks arrive before their parents.
+    fn random_tree() -> impl Strategy<Value = Vec<BlockInfo>> {
+        // per block: whether it extends the last block, how far back it forks otherwise, its work,
+        // and whether it swaps places with the block before it
+        let block = (
+            prop::bool::weighted(0.75),
+            0..5usize,
+            1..=3u8,
+            prop::bool::weighted(0.2),
+        );
+        prop::collection::vec(block, 1..=60).prop_map(|blocks| {
+            let mut block_infos: Vec<BlockInfo> = Vec::new();
+            for (i, &(extends_last, fork_back, work, _)) in blocks.iter().enumerate() {
+                let mut hash_bytes = [0xff; 32];
+                hash_bytes[..8].copy_from_slice(&(i as u64).to_le_bytes());
+                let prev_hash = match i {
+                    0 => BlockHash::all_zeros(),
+                    _ if extends_last => block_infos[i - 1].hash,
+                    _ => block_infos[i - 1 - fork_back % i.min(5)].hash,
+                };
+                let mut work_bytes = [0; 32];
+                work_bytes[31] = work;
+                block_infos.push(BlockInfo::new(
+                    &BlockHash::from_byte_array(hash_bytes),
+                    &prev_hash,
+                    Work::from_be_bytes(work_bytes),
+                ));
+            }
+            for (i, &(_, _, _, swap)) in blocks.iter().enumerate().skip(1) {
+                if swap {
+                    block_infos.swap(i - 1, i);
+                }
+            }
+            block_infos
+        })
+    }
+
+    // A random tree, and after each of its blocks is added either nothing or a root removal at a threshold of 1 to 4
+    fn random_tree_and_removals() -> impl Strategy<Value = (Vec<BlockInfo>, Vec<Option<u32>>)> {
+        random_tree().prop_flat_map(|block_infos| {
+            let removals = prop::collection::vec(prop::option::of(1..=4u32), block_infos.len());
+            (Just(block_infos), removals)
+        })
+    }
+
+    fn assert_tree_invariants(block_cache: &BlockCache<Header>) {
+        assert_eq!(block_cache.check_invariants(), Ok(()));
+        let staged = &block_cache.staged_blocks;
+        let roots: HashSet<&BlockHash> = staged
             .nodes
-            .get(&create_block_hash("A"))
-            .expect("node expected");
-        assert_eq!(node.orig_level, 5);
+            .iter()
+            .filter(|(_, node)| node.parent.is_none())
+            .map(|(hash, _)| hash)
+            .collect();
+        let mut expected_roots: HashSet<&BlockHash> =
+            staged.detached_roots.values().flatten().collect();
+        match &staged.tree_root {
+            Some(root_hash) => {
+                expected_roots.insert(root_hash);
+                assert_eq!(staged.tree_depth, staged.nodes[root_hash].subtree_depth);
+            }
+            None => assert_eq!(staged.tree_depth, 0),
+        }
+        assert_eq!(roots, expected_roots);
+        for (hash, node) in staged.nodes.iter() {
+            assert_eq!(&node.block_info.hash, hash);
+            if let Some(parent_hash) = &node.parent {
+                let parent = &staged.nodes[parent_hash];
+                assert!(parent.children.contains(hash));
+                assert_eq!(node.orig_level, parent.orig_level + 1);
+                assert_eq!(node.block_info.height, parent.block_info.height + 1);
+                assert_eq!(node.chain_work, parent.chain_work + node.block_info.work);
+            }
+            let (subtree_depth, best_subtree_work) = node.children.iter().fold(
+                (1, node.chain_work),
+                |(subtree_depth, best_subtree_work), child_hash| {
+                    let child = &staged.nodes[child_hash];
+                    assert_eq!(child.parent.as_ref(), Some(hash));
+                    (
+                        subtree_depth.max(child.subtree_depth + 1),
+                        best_subtree_work.max(child.best_subtree_work),
+                    )
+                },
+            );
+            assert_eq!(node.subtree_depth, subtree_depth);
+            assert_eq!(node.best_subtree_work, best_subtree_work);
+        }
+        assert_eq!(block_cache.pending_cnt(), staged.nodes.len());
         assert_eq!(
-            node.parent.as_ref().expect("parent expected"),
-            &create_block_hash("7")
+            block_cache.staged_cnt() + block_cache.out_of_order_cnt(),
+            staged.nodes.len()
         );
-        let mut children = HashSet::new();
-        children.insert(create_block_hash("B"));
-        assert_eq!(&node.children, &children);
-        //dbg!(&block_cache);
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(300))]
+
+        // random trees added in random order, interleaved with root removals at random thresholds
+        #[test]
+        fn test_random_trees((block_infos, removals) in random_tree_and_removals()) {
+            let payload = genesis_block(Network::Bitcoin).header;
+            let mut block_cache: BlockCache<Header> = BlockCache::new();
+            for (block_info, removal) in block_infos.iter().zip(removals) {
+                block_cache.add_block_impl(block_info, payload);
+                assert_tree_invariants(&block_cache);
+                let Some(depth_threshold) = removal else {
+                    continue;
+                };
+                let best_work = block_cache
+                    .staged_blocks
+                    .nodes
+                    .values()
+                    .map(|node| node.chain_work)
+                    .max();
+                let (removed_opt, _) = block_cache.remove_block_if_ready_impl(depth_threshold);
+                assert_tree_invariants(&block_cache);
+                if removed_opt.is_none() || block_cache.staged_cnt() == 0 {
+                    continue;
+                }
+                // the most work chain survives a reorg
+                let staged_best_work = block_cache
+                    .staged_blocks
+                    .nodes
+                    .values()
+                    .map(|node| node.chain_work)
+                    .max();
+                prop_assert_eq!(staged_best_work, best_work);
+                if let Some(reorg) = block_cache.last_reorg() {
+                    prop_assert!(block_cache
+                        .staged_blocks
+                        .nodes
+                        .contains_key(&reorg.winning_tip));
+                    prop_assert!(reorg
+                        .abandoned_blocks
+                        .iter()
+                        .all(|hash| !block_cache.contains_block(hash)));
+                }
+            }
+            block_cache.drain_ready(0);
+            assert_tree_invariants(&block_cache);
+            prop_assert_eq!(block_cache.staged_cnt(), 0);
+        }
+    }
+
+    #[test]
+    fn test_add_work() {
+        let work = |work: u8| create_block_info_with_work("0", "0", work).work;
+        assert_eq!(add_work(work(1), work(2)), work(3));
+        let max_work = Work::from_le_bytes([0xff; 32]);
+        assert_eq!(add_work(max_work, work(1)), max_work);
+    }
+
+    #[test]
+    fn test_check_invariants() {
+        let mut block_cache = BlockCache::new();
+        let dummy_block = create_dummy_block();
+        for (hash, prev_hash) in [("0", "0"), ("1", "0"), ("2", "1"), ("3", "1")] {
+            block_cache.add_block_impl(&create_block_info(hash, prev_hash), dummy_block.clone());
+        }
+        assert_eq!(block_cache.check_invariants(), Ok(()));
+
+        block_cache.staged_blocks.tree_depth += 1;
+        assert!(block_cache.check_invariants().is_err());
+        block_cache.staged_blocks.tree_depth -= 1;
+
+        block_cache
+            .pending_full_blocks
+            .remove(&create_block_hash("3"));
+        assert!(block_cache.check_invariants().is_err());
+        block_cache
+            .pending_full_blocks
+            .insert(create_block_hash("3"), dummy_block.clone());
+
+        // a tree started over after the roots were removed counts the levels on
+        block_cache.drain_ready(0);
+        block_cache.add_block_impl(&create_block_info("5", "4"), dummy_block);
+        assert_eq!(
+            block_cache.staged_blocks.nodes[&create_block_hash("5")].orig_level,
+            4
+        );
+        assert_eq!(block_cache.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_forest() {
+        let dummy_block = create_dummy_block();
+
+        // a detached tree is merged under the block it waits for
+        let mut block_cache = BlockCache::new();
+        for (hash, prev_hash) in [("0", "0"), ("1", "0"), ("2", "1"), ("5", "4"), ("6", "5")] {
+            block_cache.add_block_impl(&create_block_info(hash, prev_hash), dummy_block.clone());
+        }
+        assert_eq!(block_cache.staged_cnt(), 3);
+        assert_eq!(block_cache.out_of_order_cnt(), 2);
+        for (hash, prev_hash) in [("3", "2"), ("4", "3")] {
+            block_cache.add_block_impl(&create_block_info(hash, prev_hash), dummy_block.clone());
+        }
+        assert_eq!(block_cache.staged_cnt(), 7);
+        assert_eq!(block_cache.out_of_order_cnt(), 0);
+        assert_eq!(block_cache.tree_depth(), 7);
+        assert_eq!(block_cache.height(&create_block_hash("6")), Some(6));
+        let node = &block_cache.staged_blocks.nodes[&create_block_hash("6")];
+        assert_eq!(node.orig_level, 7);
+        assert_eq!(
+            node.chain_work,
+            create_block_info_with_work("6", "5", 7).work
+        );
+
+        // the main tree descends from a detached tree and is merged under it when they connect
+        let mut block_cache = BlockCache::new();
+        for (hash, prev_hash) in [("3", "2"), ("4", "3"), ("0", "F"), ("1", "0")] {
+            block_cache.add_block_impl(&create_block_info(hash, prev_hash), dummy_block.clone());
+        }
+        // as much work, the main tree stays
+        assert_eq!(block_cache.current_root(), Some(create_block_hash("3")));
+        block_cache.add_block_impl(&create_block_info("2", "1"), dummy_block.clone());
+        assert_eq!(block_cache.current_root(), Some(create_block_hash("0")));
+        assert_eq!(block_cache.staged_cnt(), 5);
+        assert_eq!(block_cache.tree_depth(), 5);
+        assert_eq!(block_cache.height(&create_block_hash("4")), Some(4));
+
+        // a detached tree with more work becomes the main tree until a root migrates
+        let mut block_cache = BlockCache::new();
+        for (hash, prev_hash) in [("0", "0"), ("5", "4"), ("6", "5")] {
+            block_cache.add_block_impl(&create_block_info(hash, prev_hash), dummy_block.clone());
+        }
+        assert_eq!(block_cache.current_root(), Some(create_block_hash("5")));
+        assert_eq!(block_cache.out_of_order_cnt(), 1);
+        assert_eq!(block_cache.height(&create_block_hash("6")), Some(1));
+        assert_eq!(block_cache.height(&create_block_hash("0")), None);
+        assert!(block_cache.remove_block_if_ready(2).is_some());
+        for (hash, prev_hash) in [("1", "0"), ("2", "1")] {
+            block_cache.add_block_impl(&create_block_info(hash, prev_hash), dummy_block.clone());
+        }
+        assert_eq!(block_cache.current_root(), Some(create_block_hash("6")));
+        assert_eq!(block_cache.out_of_order_cnt(), 3);
+    }
+
+    // like mainnet blocks 91812/91842 and 91722/91880, a later block repeats an earlier block's coinbase
+    #[test]
+    fn test_bip30_duplicate_coinbase() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let dummy_block = create_dummy_block();
+        let mut block1 = genesis.clone();
+        block1.header.prev_blockhash = genesis.block_hash();
+        block1.txdata = dummy_block.txdata.clone();
+        let mut block2 = genesis.clone();
+        block2.header.prev_blockhash = block1.block_hash();
+        let mut block3 = block1.clone();
+        block3.header.prev_blockhash = block2.block_hash();
+        assert_eq!(
+            block1.txdata[0].compute_txid(),
+            block3.txdata[0].compute_txid()
+        );
+        assert_ne!(block1.block_hash(), block3.block_hash());
+
+        let mut block_cache = BlockCache::new();
+        for block in [&genesis, &block1, &block2, &block3] {
+            assert!(block_cache.add_block(block.clone()).is_added());
+        }
+        assert!(!block_cache.add_block(block3.clone()).is_added());
+        assert_eq!(block_cache.staged_cnt(), 4);
+        assert_eq!(block_cache.out_of_order_cnt(), 0);
+        assert_eq!(block_cache.height(&block1.block_hash()), Some(1));
+        assert_eq!(block_cache.height(&block3.block_hash()), Some(3));
+
+        let mut migrated = Vec::new();
+        while let Some((block, height)) = block_cache.remove_block_with_height_if_ready(0).unwrap()
+        {
+            migrated.push((block.block_hash(), height));
+        }
+        assert_eq!(
+            migrated,
+            vec![
+                (genesis.block_hash(), 0),
+                (block1.block_hash(), 1),
+                (block2.block_hash(), 2),
+                (block3.block_hash(), 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_removal_policy() {
+        let dummy_block = create_dummy_block();
+        /*
+                 0
+                / \
+               1   4    1-2-3 is deeper, but 4 has more work
+               |
+               2
+               |
+               3
+        */
+        let blocks = [
+            ("0", "0", 1),
+            ("1", "0", 1),
+            ("2", "1", 1),
+            ("3", "2", 1),
+            ("4", "0", 5),
+        ];
+        let mut block_cache = BlockCache::new();
+        for (hash, prev_hash, work) in blocks {
+            block_cache.add_block_impl(
+                &create_block_info_with_work(hash, prev_hash, work),
+                dummy_block.clone(),
+            );
+        }
+        assert_eq!(block_cache.removal_policy(), RemovalPolicy::Depth);
+        assert!(block_cache.peek_ready(3).is_some());
+
+        // the most work chain 0-4 is only 2 deep
+        block_cache.set_removal_policy(RemovalPolicy::BestWork);
+        assert!(block_cache.peek_ready(3).is_none());
+        assert!(block_cache.remove_block_if_ready(3).is_none());
+        assert!(block_cache.peek_ready(2).is_some());
+        block_cache.add_block_impl(&create_block_info("5", "4"), dummy_block.clone());
+        assert!(block_cache.remove_block_if_ready(3).is_some());
+        assert_eq!(block_cache.current_root(), Some(create_block_hash("4")));
+        assert_eq!(block_cache.last_purged().len(), 3);
+    }
+
+    #[test]
+    fn test_max_pending() {
+        let mut block_cache = BlockCache::new();
+        block_cache.set_max_pending(Some(2));
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut next_block = genesis.clone();
+        next_block.header.prev_blockhash = genesis.block_hash();
+        assert_eq!(block_cache.add_block(genesis.clone()), AddResult::Accepted);
+        assert_eq!(block_cache.add_block(next_block.clone()), AddResult::Full);
+        assert_eq!(block_cache.add_block(genesis), AddResult::Skipped);
+        assert!(block_cache.is_full());
+        // room again once a block migrates
+        block_cache.remove_block_if_ready(0);
+        assert!(!block_cache.is_full());
+        block_cache.set_max_pending(None);
+        next_block.header.nonce += 1;
+        assert_eq!(block_cache.add_block(next_block), AddResult::Accepted);
+    }
+
+    #[test]
+    fn test_spill_file_deleted() {
+        let dummy_block = create_dummy_block();
+        // room for one block
+        let mut block_cache = BlockCache::with_limits(dummy_block.total_size());
+        for (hash, prev_hash) in [("0", "0"), ("1", "0"), ("2", "1")] {
+            block_cache.add_block_impl(&create_block_info(hash, prev_hash), dummy_block.clone());
+        }
+        assert_eq!(block_cache.spilled_cnt(), 2);
+        let spill_dir = block_cache.spill.dir.clone().expect("spill dir expected");
+        std::fs::remove_file(spill_dir.join(format!("{}.blk", create_block_hash("0")))).unwrap();
+
+        // the root migrates, but its block is gone
+        let err = block_cache
+            .remove_block_with_height_if_ready(2)
+            .unwrap_err();
+        assert!(err.to_string().starts_with("spilled block "));
+        assert_eq!(block_cache.pending_cnt(), 2);
+        assert_eq!(block_cache.spilled_cnt(), 1);
+        let (block, height) = block_cache
+            .remove_block_with_height_if_ready(1)
+            .unwrap()
+            .expect("root removal expected");
+        assert_eq!((block, height), (dummy_block, 1));
     }
 }
@@ -0,0 +1,259 @@
+use bitcoin::block::Header;
+use bitcoin::consensus::Decodable;
+#[cfg(feature = "leveldb")]
+use bitcoin::hashes::Hash;
+use bitcoin::BlockHash;
+#[cfg(feature = "leveldb")]
+use rusty_leveldb::{LdbIterator, Options, DB};
+#[cfg(feature = "leveldb")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "leveldb")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/*
+Bitcoin Core keeps a LevelDB index of every block it has seen in blocks/index.
+Each block has a record keyed by 'b' + block hash (CDiskBlockIndex in Core's chain.h):
+    VARINT client version, VARINT height, VARINT status, VARINT tx count,
+    VARINT file num (if the block has data or undo), VARINT data pos (if the block has data),
+    VARINT undo pos (if the block has undo), 80-byte block header.
+The data pos is the offset of the serialized block in blk?????.dat, right after the 8-byte magic + length frame header.
+*/
+
+const BLOCK_HAVE_DATA: u64 = 8;
+const BLOCK_HAVE_UNDO: u64 = 16;
+const BLOCK_FAILED_MASK: u64 = 32 | 64;
+
+#[derive(Debug, Clone)]
+pub struct BlockIndexEntry {
+    pub hash: BlockHash,
+    pub height: u64,
+    pub file_num: u32,
+    pub data_pos: u64,
+    pub header: Header,
+}
+
+/// Reads all blocks with data on disk from bitcoin core's blocks/index LevelDB, sorted by height.
+/// Blocks that failed validation are left out. Bitcoin core must not be running, since it holds the LevelDB lock.
+/// Opening a LevelDB replays its log into a new one and may compact it, so the index is copied to a temp
+/// directory and the copy is opened: the node's datadir is never written to. The copy takes as much space
+/// as the index, a few hundred MB for mainnet, and is deleted when the read is done.
+#[cfg(feature = "leveldb")]
+pub fn read_block_index(index_dir: impl AsRef<Path>) -> std::io::Result<Vec<BlockIndexEntry>> {
+    let copy = IndexCopy::new(index_dir.as_ref())?;
+    let options = Options {
+        create_if_missing: false,
+        ..Options::default()
+    };
+    let mut db = DB::open(&copy.0, options).map_err(std::io::Error::other)?;
+    let mut iter = db.new_iter().map_err(std::io::Error::other)?;
+    iter.seek(b"b");
+
+    let mut entries = Vec::new();
+    while let Some((key, value)) = iter.current() {
+        if key.first() != Some(&b'b') {
+            break;
+        }
+        if key.len() == 33 {
+            let hash = BlockHash::from_slice(&key[1..]).expect("32 byte hash");
+            if let Some(entry) = parse_block_index_entry(hash, &value)? {
+                entries.push(entry);
+            }
+        }
+        iter.advance();
+    }
+    entries.sort_by_key(|entry| entry.height);
+    Ok(entries)
+}
+
+/// Parses the value of a 'b' + block hash record, for reading the index with another LevelDB library
+/// when built without the leveldb feature.
+/// Returns None for blocks without data on disk or that failed validation
+pub fn parse_block_index_entry(
+    hash: BlockHash,
+    mut value: &[u8],
+) -> std::io::Result<Option<BlockIndexEntry>> {
+    let _client_version = read_varint(&mut value)?;
+    let height = read_varint(&mut value)?;
+    let status = read_varint(&mut value)?;
+    let _tx_cnt = read_varint(&mut value)?;
+    if status & BLOCK_HAVE_DATA == 0 || status & BLOCK_FAILED_MASK != 0 {
+        return Ok(None);
+    }
+    let file_num = u32::try_from(read_varint(&mut value)?)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+    let data_pos = read_varint(&mut value)?;
+    if status & BLOCK_HAVE_UNDO != 0 {
+        let _undo_pos = read_varint(&mut value)?;
+    }
+    let header = Header::consensus_decode(&mut value)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+    Ok(Some(BlockIndexEntry {
+        hash,
+        height,
+        file_num,
+        data_pos,
+        header,
+    }))
+}
+
+// A copy of the LevelDB files of an index directory, deleted on drop.
+// The LOCK file is left out, the copy has a lock of its own
+#[cfg(feature = "leveldb")]
+struct IndexCopy(PathBuf);
+
+#[cfg(feature = "leveldb")]
+impl IndexCopy {
+    fn new(index_dir: &Path) -> std::io::Result<Self> {
+        static COPY_CNT: AtomicUsize = AtomicUsize::new(0);
+        let copy_dir = std::env::temp_dir().join(format!(
+            "read-blk-core-index-{}-{}",
+            std::process::id(),
+            COPY_CNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&copy_dir)?;
+        let copy = IndexCopy(copy_dir);
+        for dir_entry in std::fs::read_dir(index_dir)? {
+            let dir_entry = dir_entry?;
+            if dir_entry.file_type()?.is_file() && dir_entry.file_name() != "LOCK" {
+                std::fs::copy(dir_entry.path(), copy.0.join(dir_entry.file_name()))?;
+            }
+        }
+        Ok(copy)
+    }
+}
+
+#[cfg(feature = "leveldb")]
+impl Drop for IndexCopy {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// Bitcoin core's VARINT: MSB base-128 where each continuation byte also adds one,
+// so that every number has exactly one encoding.
+fn read_varint(bytes: &mut &[u8]) -> std::io::Result<u64> {
+    let mut n: u64 = 0;
+    loop {
+        let (&byte, rest) = bytes
+            .split_first()
+            .ok_or(std::io::ErrorKind::UnexpectedEof)?;
+        *bytes = rest;
+        n = n.checked_mul(128).ok_or(std::io::ErrorKind::InvalidData)? | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+        n = n.checked_add(1).ok_or(std::io::ErrorKind::InvalidData)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::consensus::serialize;
+    use bitcoin::constants::genesis_block;
+    use bitcoin::Network;
+
+    fn write_varint(mut n: u64, out: &mut Vec<u8>) {
+        let mut tmp = Vec::new();
+        loop {
+            tmp.push((n & 0x7f) as u8 | if tmp.is_empty() { 0 } else { 0x80 });
+            if n <= 0x7f {
+                break;
+            }
+            n = (n >> 7) - 1;
+        }
+        out.extend(tmp.iter().rev());
+    }
+
+    #[test]
+    fn test_parse_block_index_entry() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut value = Vec::new();
+        for n in [259900, 0, 8 | 16 | 5, 1, 0, 8, 0] {
+            write_varint(n, &mut value);
+        }
+        value.extend_from_slice(&serialize(&genesis.header));
+
+        let entry = parse_block_index_entry(genesis.block_hash(), &value)
+            .unwrap()
+            .expect("entry expected");
+        assert_eq!(entry.height, 0);
+        assert_eq!(entry.file_num, 0);
+        assert_eq!(entry.data_pos, 8);
+        assert_eq!(entry.header, genesis.header);
+
+        // a header-only block has no data on disk
+        let mut value = Vec::new();
+        for n in [259900, 1, 1, 0] {
+            write_varint(n, &mut value);
+        }
+        value.extend_from_slice(&serialize(&genesis.header));
+        assert!(parse_block_index_entry(genesis.block_hash(), &value)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_read_varint() {
+        for n in [0, 1, 127, 128, 255, 16383, 16384, 259900, u32::MAX as u64] {
+            let mut bytes = Vec::new();
+            write_varint(n, &mut bytes);
+            assert_eq!(read_varint(&mut bytes.as_slice()).unwrap(), n);
+        }
+        // 0x80 0x00 is 128, not 0
+        assert_eq!(read_varint(&mut [0x80u8, 0x00].as_slice()).unwrap(), 128);
+        // u64::MAX followed by another byte is past a u64
+        let mut bytes = Vec::new();
+        write_varint(u64::MAX, &mut bytes);
+        *bytes.last_mut().unwrap() |= 0x80;
+        bytes.push(0);
+        assert!(read_varint(&mut bytes.as_slice()).is_err());
+    }
+
+    // the index of a node's datadir is opened through a copy, the datadir is left as it was
+    #[cfg(feature = "leveldb")]
+    #[test]
+    fn test_read_block_index() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let index_dir =
+            std::env::temp_dir().join(format!("read-blk-index-dir-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&index_dir);
+        {
+            let options = Options {
+                create_if_missing: true,
+                ..Options::default()
+            };
+            let mut db = DB::open(&index_dir, options).unwrap();
+            let mut value = Vec::new();
+            for n in [259900, 0, 8 | 16 | 5, 1, 0, 8, 0] {
+                write_varint(n, &mut value);
+            }
+            value.extend_from_slice(&serialize(&genesis.header));
+            let mut key = vec![b'b'];
+            key.extend_from_slice(genesis.block_hash().as_byte_array());
+            db.put(&key, &value).unwrap();
+            // left in the log, as a node that was stopped may leave it, for the open to replay
+        }
+        let snapshot = |dir: &Path| {
+            let mut files = std::fs::read_dir(dir)
+                .unwrap()
+                .map(|dir_entry| {
+                    let path = dir_entry.unwrap().path();
+                    (
+                        path.file_name().unwrap().to_owned(),
+                        std::fs::read(&path).unwrap(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        };
+        let before = snapshot(&index_dir);
+
+        let entries = read_block_index(&index_dir).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].hash, genesis.block_hash());
+        assert_eq!(snapshot(&index_dir), before);
+        std::fs::remove_dir_all(&index_dir).unwrap();
+    }
+}
@@ -0,0 +1,113 @@
+use crate::logging::error;
+use crate::sink::{BlockMeta, BlockSink};
+use bitcoin::Block;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+// the file is flushed every FLUSH_INTERVAL imported blocks
+const FLUSH_INTERVAL: usize = 1000;
+
+const HEADER_ROW: &str = "height,hash,prev_hash,time,bits,work,n_tx,size,weight";
+
+// Writes a line of header metadata per imported block.
+// Purged blocks were never imported, so a reorg doesn't change the file.
+pub struct CsvSink<W: Write> {
+    writer: W,
+    // lines written since the last flush
+    unflushed_cnt: usize,
+}
+
+impl CsvSink<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        CsvSink::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> CsvSink<W> {
+    // the header row is written right away
+    pub fn new(mut writer: W) -> std::io::Result<Self> {
+        writeln!(writer, "{}", HEADER_ROW)?;
+        Ok(CsvSink {
+            writer,
+            unflushed_cnt: 0,
+        })
+    }
+
+    fn write_block(&mut self, block: &Block, meta: &BlockMeta) -> std::io::Result<()> {
+        writeln!(
+            self.writer,
+            "{},{},{},{},{:08x},{:x},{},{},{}",
+            meta.height,
+            block.block_hash(),
+            block.header.prev_blockhash,
+            block.header.time,
+            block.header.bits.to_consensus(),
+            block.header.work(),
+            block.txdata.len(),
+            block.total_size(),
+            block.weight().to_wu()
+        )?;
+        self.unflushed_cnt += 1;
+        if self.unflushed_cnt >= FLUSH_INTERVAL {
+            self.writer.flush()?;
+            self.unflushed_cnt = 0;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> BlockSink for CsvSink<W> {
+    fn on_block(&mut self, block: &Block, meta: &BlockMeta) {
+        if let Err(err) = self.write_block(block, meta) {
+            error!("csv: can't write block {:?}: {}", block.block_hash(), err);
+        }
+    }
+}
+
+impl<W: Write> Drop for CsvSink<W> {
+    fn drop(&mut self) {
+        if let Err(err) = self.writer.flush() {
+            error!("csv: can't flush: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::constants::genesis_block;
+    use bitcoin::Network;
+
+    #[test]
+    fn test_csv_sink() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let path = std::env::temp_dir().join(format!("read-blk-csv-{}.csv", std::process::id()));
+        let mut sink = CsvSink::create(&path).unwrap();
+        sink.on_block(
+            &genesis,
+            &BlockMeta {
+                height: 0,
+                file_num: 0,
+                summary: None,
+                op_returns: Vec::new(),
+            },
+        );
+        drop(sink);
+
+        let csv = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], HEADER_ROW);
+        assert_eq!(
+            lines[1],
+            format!(
+                "0,{},{},1231006505,1d00ffff,{:0>64},1,285,1140",
+                genesis.block_hash(),
+                genesis.header.prev_blockhash,
+                "100010001"
+            )
+        );
+    }
+}
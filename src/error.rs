@@ -0,0 +1,132 @@
+use crate::block_cache::SourceLoc;
+use bitcoin::p2p::Magic;
+use bitcoin::BlockHash;
+use std::fmt;
+use std::path::PathBuf;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    // blk file or compressed blk file that can't be opened or read
+    File {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Io(std::io::Error),
+    // frame magic is unknown or doesn't match the network
+    BadMagic {
+        offset: usize,
+        magic: Magic,
+    },
+    // frame header extends past the end of the data
+    ShortFrame {
+        offset: usize,
+    },
+    // frame length over the largest block, a corrupt length would otherwise be allocated as is
+    OversizedFrame {
+        offset: usize,
+        len: usize,
+    },
+    // frame length of zero after a valid magic
+    EmptyFrame {
+        offset: usize,
+    },
+    Decode(bitcoin::consensus::encode::Error),
+    // a block kept raw that fails to decode when it migrates, see Importer::set_lazy_decode()
+    PendingDecode {
+        block_hash: BlockHash,
+        source_loc: Option<SourceLoc>,
+        source: bitcoin::consensus::encode::Error,
+    },
+    Linkage(LinkageError),
+    // a block that failed validation, the importer logs and skips these
+    Invalid {
+        block_hash: BlockHash,
+        reason: &'static str,
+    },
+}
+
+// A block migrated out of the cache that doesn't extend the last imported block
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkageError {
+    pub block_hash: BlockHash,
+    pub prev_hash: BlockHash,
+    // hash of the last imported block
+    pub expected_prev_hash: BlockHash,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::File { path, source } => write!(f, "{}: {}", path.display(), source),
+            Error::Io(err) => write!(f, "read error: {}", err),
+            Error::BadMagic { offset, magic } => {
+                write!(f, "bad magic {} at offset {}", magic, offset)
+            }
+            Error::ShortFrame { offset } => write!(f, "short frame at offset {}", offset),
+            Error::OversizedFrame { offset, len } => {
+                write!(f, "oversized frame at offset {}: {} bytes", offset, len)
+            }
+            Error::EmptyFrame { offset } => write!(f, "empty frame at offset {}", offset),
+            Error::Decode(err) => write!(f, "block decode error: {}", err),
+            Error::PendingDecode {
+                block_hash,
+                source_loc,
+                source,
+            } => {
+                write!(f, "block {}", block_hash)?;
+                if let Some(loc) = source_loc {
+                    write!(f, " at {}", loc)?;
+                }
+                write!(f, ": decode error: {}", source)
+            }
+            Error::Linkage(err) => err.fmt(f),
+            Error::Invalid { block_hash, reason } => {
+                write!(f, "invalid block {}: {}", block_hash, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::File { source, .. } => Some(source),
+            Error::Io(err) => Some(err),
+            Error::Decode(err) => Some(err),
+            Error::PendingDecode { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<bitcoin::consensus::encode::Error> for Error {
+    fn from(err: bitcoin::consensus::encode::Error) -> Self {
+        Error::Decode(err)
+    }
+}
+
+impl From<LinkageError> for Error {
+    fn from(err: LinkageError) -> Self {
+        Error::Linkage(err)
+    }
+}
+
+impl fmt::Display for LinkageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "block {} prev_hash {} doesn't link to the last imported block {}",
+            self.block_hash, self.prev_hash, self.expected_prev_hash
+        )
+    }
+}
+
+impl std::error::Error for LinkageError {}
@@ -0,0 +1,373 @@
+use reqwest::blocking::Client;
+use reqwest::header::{CONTENT_RANGE, RANGE};
+use reqwest::StatusCode;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::time::Duration;
+
+/*
+HttpRangeReader reads a file served over HTTP as a Read + Seek, fetching it chunk by chunk with ranged GETs,
+so the importer can stream blk files from a server without downloading them first, see Importer::read_url_dir().
+The requests go through reqwest's blocking client, over http:// or https:// with rustls and the webpki roots.
+Every chunk is a GET with Range: bytes=start-end, the server has to answer 206 Partial Content. The file length
+comes from the Content-Range of the first response. The client keeps the connection alive for the next chunk.
+*/
+
+// bytes fetched per request, a few blocks
+const CHUNK_LEN: u64 = 4 * 1024 * 1024;
+// for connecting, and for every request including its body
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct HttpRangeReader {
+    client: Client,
+    url: String,
+    len: u64,
+    pos: u64,
+    // the last chunk fetched and the file position it starts at
+    chunk: Vec<u8>,
+    chunk_start: u64,
+}
+
+// the client HttpRangeReader::open() uses, with the timeouts
+pub fn default_client() -> io::Result<Client> {
+    Client::builder()
+        .connect_timeout(TIMEOUT)
+        .timeout(TIMEOUT)
+        .build()
+        .map_err(io::Error::other)
+}
+
+impl HttpRangeReader {
+    // Fetches the first chunk, an error of kind NotFound if the server answers 404
+    pub fn open(url: &str) -> io::Result<Self> {
+        Self::open_with_client(default_client()?, url)
+    }
+
+    // Like open(), with a client of the caller's, e.g. one that trusts a private CA.
+    // A clone of a client shares its connections
+    pub fn open_with_client(client: Client, url: &str) -> io::Result<Self> {
+        let mut reader = HttpRangeReader {
+            client,
+            url: url.to_string(),
+            len: 0,
+            pos: 0,
+            chunk: Vec::new(),
+            chunk_start: 0,
+        };
+        reader.fetch(0)?;
+        Ok(reader)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // replaces the chunk with the one starting at start, and takes the file length from the response
+    fn fetch(&mut self, start: u64) -> io::Result<()> {
+        let mut response = self
+            .client
+            .get(&self.url)
+            .header(RANGE, format!("bytes={}-{}", start, start + CHUNK_LEN - 1))
+            .send()
+            .map_err(io::Error::other)?;
+        // bytes start-end/total, or bytes */total for a range past the end
+        let total_len = response
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit_once('/'))
+            .and_then(|(_, total)| total.parse::<u64>().ok());
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT => {}
+            // an empty file or a range past the end, the length is in the Content-Range
+            StatusCode::RANGE_NOT_SATISFIABLE => {
+                self.len = total_len.unwrap_or(start);
+                self.chunk.clear();
+                self.chunk_start = start;
+                return Ok(());
+            }
+            StatusCode::NOT_FOUND => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} not found", self.url),
+                ))
+            }
+            StatusCode::OK => {
+                return Err(io::Error::other(format!(
+                    "{}: the server doesn't support range requests",
+                    self.url
+                )))
+            }
+            status => return Err(io::Error::other(format!("{}: {}", self.url, status))),
+        }
+        let total_len = total_len
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no Content-Range"))?;
+        self.len = total_len;
+        self.chunk.clear();
+        response
+            .copy_to(&mut self.chunk)
+            .map_err(io::Error::other)?;
+        self.chunk_start = start;
+        Ok(())
+    }
+}
+
+impl Read for HttpRangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let chunk_end = self.chunk_start + self.chunk.len() as u64;
+        if self.pos < self.chunk_start || self.pos >= chunk_end {
+            self.fetch(self.pos)?;
+            if self.chunk.is_empty() {
+                return Ok(0);
+            }
+        }
+        let chunk = &self.chunk[(self.pos - self.chunk_start) as usize..];
+        let read_len = chunk.len().min(buf.len());
+        buf[..read_len].copy_from_slice(&chunk[..read_len]);
+        self.pos += read_len as u64;
+        Ok(read_len)
+    }
+}
+
+impl Seek for HttpRangeReader {
+    // nothing is fetched until the next read, a position past the end reads nothing
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = new_pos
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+    use rustls::{ServerConfig, ServerConnection, StreamOwned};
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    // how the test server answers
+    #[derive(Clone, Copy, Default)]
+    pub(crate) struct ServeOptions {
+        // the 206 bodies with Transfer-Encoding: chunked instead of a Content-Length
+        pub chunked: bool,
+    }
+
+    // Serves the files by path with range requests, on a thread for as long as the test runs.
+    // Returns the base URL
+    pub(crate) fn serve(files: HashMap<String, Vec<u8>>) -> String {
+        serve_counting(files, ServeOptions::default()).0
+    }
+
+    // Like serve(), with the count of connections accepted. A connection is served until the client closes it
+    pub(crate) fn serve_counting(
+        files: HashMap<String, Vec<u8>>,
+        options: ServeOptions,
+    ) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let connection_cnt = Arc::new(AtomicUsize::new(0));
+        let accepted_cnt = connection_cnt.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                accepted_cnt.fetch_add(1, Ordering::Relaxed);
+                let files = files.clone();
+                thread::spawn(move || serve_connection(stream, &files, options));
+            }
+        });
+        (base_url, connection_cnt)
+    }
+
+    // Like serve(), over TLS with a self-signed certificate for localhost.
+    // Returns the https:// base URL and a client that trusts the certificate
+    pub(crate) fn serve_tls(files: HashMap<String, Vec<u8>>) -> (String, Client) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_der = CertificateDer::from(cert.cert.der().to_vec());
+        let key_der = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der()));
+        let config =
+            ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(vec![cert_der.clone()], key_der)
+                .unwrap();
+        let config = Arc::new(config);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!(
+            "https://localhost:{}",
+            listener.local_addr().unwrap().port()
+        );
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let connection = ServerConnection::new(config.clone()).unwrap();
+                let files = files.clone();
+                thread::spawn(move || {
+                    serve_connection(
+                        StreamOwned::new(connection, stream),
+                        &files,
+                        ServeOptions::default(),
+                    )
+                });
+            }
+        });
+        let client = Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_der(&cert_der).unwrap())
+            .build()
+            .unwrap();
+        (base_url, client)
+    }
+
+    fn serve_connection<S: Read + Write>(
+        stream: S,
+        files: &HashMap<String, Vec<u8>>,
+        options: ServeOptions,
+    ) {
+        let mut reader = BufReader::new(stream);
+        loop {
+            let mut request_line = String::new();
+            if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+                return;
+            }
+            let path = request_line.split_whitespace().nth(1).unwrap().to_string();
+            let mut range = None;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 || line.trim().is_empty() {
+                    break;
+                }
+                let Some((name, value)) = line.split_once(':') else {
+                    continue;
+                };
+                if let Some(value) = name
+                    .eq_ignore_ascii_case("range")
+                    .then(|| value.trim().strip_prefix("bytes="))
+                    .flatten()
+                {
+                    let (start, end) = value.split_once('-').unwrap();
+                    range = Some((
+                        start.parse::<usize>().unwrap(),
+                        end.parse::<usize>().unwrap(),
+                    ));
+                }
+            }
+            let response = match (files.get(&path), range) {
+                    (None, _) => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec(),
+                    (Some(file), Some((start, _))) if start >= file.len() => format!(
+                        "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\nContent-Length: 0\r\n\r\n",
+                        file.len()
+                    )
+                    .into_bytes(),
+                    (Some(file), Some((start, end))) => {
+                        let end = end.min(file.len() - 1);
+                        let body = &file[start..=end];
+                        let mut response = format!(
+                            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n",
+                            start,
+                            end,
+                            file.len()
+                        )
+                        .into_bytes();
+                        if options.chunked {
+                            response.extend(b"Transfer-Encoding: chunked\r\n\r\n");
+                            for chunk in body.chunks(1000) {
+                                response.extend(format!("{:x}\r\n", chunk.len()).into_bytes());
+                                response.extend(chunk);
+                                response.extend(b"\r\n");
+                            }
+                            response.extend(b"0\r\n\r\n");
+                        } else {
+                            response.extend(format!("Content-Length: {}\r\n\r\n", body.len()).into_bytes());
+                            response.extend(body);
+                        }
+                        response
+                    }
+                    (Some(file), None) => {
+                        let mut response =
+                            format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", file.len())
+                                .into_bytes();
+                        response.extend(file);
+                        response
+                    }
+                };
+            let stream = reader.get_mut();
+            if stream
+                .write_all(&response)
+                .and_then(|_| stream.flush())
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+
+    // reads the whole file, and back into its first chunk and at its end
+    fn check_file(mut reader: HttpRangeReader, file: &[u8]) {
+        assert_eq!(reader.len(), file.len() as u64);
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents).unwrap();
+        assert!(contents == file);
+        reader.seek(SeekFrom::Start(10)).unwrap();
+        let mut bytes = [0; 3];
+        reader.read_exact(&mut bytes).unwrap();
+        assert_eq!(bytes, [10, 11, 12]);
+        assert_eq!(
+            reader.seek(SeekFrom::End(-1)).unwrap(),
+            file.len() as u64 - 1
+        );
+        reader.read_exact(&mut bytes[..1]).unwrap();
+        assert_eq!(bytes[0], file[file.len() - 1]);
+    }
+
+    #[test]
+    fn test_http_range_reader() {
+        let file: Vec<u8> = (0..CHUNK_LEN as usize + 1000).map(|i| i as u8).collect();
+        let files = HashMap::from([
+            ("/blocks/blk00000.dat".to_string(), file.clone()),
+            ("/blocks/blk00001.dat".to_string(), Vec::new()),
+        ]);
+        let (base_url, connection_cnt) = serve_counting(files.clone(), ServeOptions::default());
+
+        let reader = HttpRangeReader::open(&format!("{}/blocks/blk00000.dat", base_url)).unwrap();
+        check_file(reader, &file);
+        // the four chunks were fetched on one connection
+        assert_eq!(connection_cnt.load(Ordering::Relaxed), 1);
+
+        let mut empty =
+            HttpRangeReader::open(&format!("{}/blocks/blk00001.dat", base_url)).unwrap();
+        assert!(empty.is_empty());
+        assert_eq!(empty.read(&mut [0; 3]).unwrap(), 0);
+        let missing = HttpRangeReader::open(&format!("{}/blocks/blk00002.dat", base_url));
+        assert_eq!(missing.err().unwrap().kind(), io::ErrorKind::NotFound);
+
+        // a body without a Content-Length on a connection that stays open
+        let (base_url, _) = serve_counting(files.clone(), ServeOptions { chunked: true });
+        let reader = HttpRangeReader::open(&format!("{}/blocks/blk00000.dat", base_url)).unwrap();
+        check_file(reader, &file);
+
+        let (base_url, client) = serve_tls(files);
+        let reader =
+            HttpRangeReader::open_with_client(client, &format!("{}/blocks/blk00000.dat", base_url))
+                .unwrap();
+        check_file(reader, &file);
+        // a certificate that isn't trusted
+        assert!(HttpRangeReader::open(&format!("{}/blocks/blk00000.dat", base_url)).is_err());
+    }
+}
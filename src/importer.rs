@@ -0,0 +1,2960 @@
+use crate::block_cache::{
+    add_work, AddResult, BlockCache, PendingBlock, RemovalPolicy, SourceLoc,
+    DEFAULT_DEPTH_THRESHOLD,
+};
+#[cfg(feature = "leveldb")]
+use crate::core_index;
+use crate::core_index::BlockIndexEntry;
+use crate::error::{Error, LinkageError, Result};
+#[cfg(feature = "reqwest")]
+use crate::http::HttpRangeReader;
+use crate::index::{Index, IndexEntry};
+use crate::logging::{debug, error, info, trace, warn};
+#[cfg(feature = "metrics")]
+use crate::metrics::Metrics;
+use crate::sink::{BlockMeta, BlockSink, ChannelSink, LogSink, StdoutSink};
+use crate::summary::{block_summary, op_returns};
+use crate::utxo::UtxoTracker;
+use bitcoin::block::{Block, Header};
+use bitcoin::consensus::{encode, Decodable};
+use bitcoin::p2p::Magic;
+use bitcoin::{BlockHash, Network, Work};
+#[cfg(feature = "mmap")]
+use memmap2::{Mmap, MmapOptions};
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+#[cfg(feature = "metrics")]
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// bitcoin core's MAX_BLOCK_SERIALIZED_SIZE, a block can't be larger than its weight
+const MAX_FRAME_LEN: usize = 4_000_000;
+
+// What a block reader got through before it stopped
+#[derive(Debug, Default, PartialEq)]
+pub struct ReadCount {
+    pub blocks: usize,
+    // bytes of complete frames, a truncated final frame is not counted
+    pub consumed: usize,
+}
+
+pub struct Importer {
+    // bitcoin core blocks directory containing blk?????.dat files
+    dir_path: PathBuf,
+    // number of the next blk file to read
+    file_num: u32,
+    // network whose magic bytes prefix every block frame in the blk files,
+    // detected from the first frame if not set explicitly
+    network: Option<Network>,
+    // the cache threshold is how deep the staged tree has to be before its root block is imported
+    block_cache: BlockCache<PendingBlock>,
+    prev_block_hash: Option<BlockHash>,
+    prev_block_height: u64,
+    // blocks imported since the importer was created, not counting the ones before a checkpoint
+    imported_count: u64,
+    // summed header work of the imported blocks, from the first one imported, kept in the checkpoint
+    total_chainwork: Work,
+    // blk file the last imported block was read from, where a resumed import restarts
+    prev_block_file_num: u32,
+    // after loading a checkpoint, re-read blocks up to and including this one are skipped
+    resume_hash: Option<BlockHash>,
+    // checkpoint is saved here after every blk file
+    checkpoint_path: Option<PathBuf>,
+    // bitcoin core's block index in height order, if blocks are to be read in that order
+    block_index: Option<Vec<BlockIndexEntry>>,
+    blocks_read: u64,
+    progress: Option<Progress>,
+    // updated as blocks are read, imported and purged, while a thread may be rendering them
+    #[cfg(feature = "metrics")]
+    metrics: Option<Arc<Metrics>>,
+    // log and skip blocks that fail to decode instead of returning an error
+    skip_corrupt: bool,
+    corrupt_count: u64,
+    // blocks read again, e.g. leftover copies in another blk file after a reorg
+    duplicate_file_blocks: u64,
+    // check every block read and skip the invalid ones
+    validate: bool,
+    validate_pow: bool,
+    invalid_count: u64,
+    // imported blocks and purged losing blocks are passed here
+    sink: Box<dyn BlockSink>,
+    // if set, every imported block is applied to the UTXO set before it is passed to the sink
+    utxo_tracker: Option<UtxoTracker>,
+    // compute a BlockSummary for every imported block
+    summaries: bool,
+    // OP_RETURN outputs with a payload at least this long are passed to the sink
+    op_return_min_len: Option<usize>,
+    // a LinkageError stops the import, instead of being logged
+    strict_linkage: bool,
+    // blocks are kept raw in the cache and decoded when they migrate
+    lazy_decode: bool,
+    // only the block headers are decoded and cached, blocks are imported without transactions
+    headers_only: bool,
+    // imported blocks outside the range are not passed to the sink
+    min_height: Option<u64>,
+    max_height: Option<u64>,
+    // imported blocks whose header time is outside [after_time, before_time) are not passed to the sink
+    after_time: Option<u32>,
+    before_time: Option<u32>,
+    // read_blocks_streaming() reads every frame into this buffer
+    frame_buf: Vec<u8>,
+    // if set, every block read is recorded here, and open_block() looks blocks up here
+    index: Option<Index>,
+    // bytes of complete frames read from each blk file, where follow() resumes reading the last one
+    file_offsets: HashMap<u32, usize>,
+}
+
+// A block that migrated out of the cache to the main chain
+#[derive(Debug)]
+pub struct ImportedBlock {
+    pub block: Block,
+    // counted from the first imported block, whose height is its BIP34 height or the cache base height
+    pub height: u64,
+}
+
+// What an import left behind, see Importer::completion_report()
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompletionReport {
+    // blocks that migrated out of the cache, passed to the sink or not
+    pub imported: u64,
+    // blocks still in the cache, out_of_order included
+    pub pending: usize,
+    // pending blocks in detached trees, whose parent never arrived
+    pub out_of_order: usize,
+    pub duplicates: u64,
+    pub corrupt: u64,
+}
+
+pub struct ProgressEvent {
+    pub file_num: u32,
+    // position of the last read block's frame within the file
+    pub offset: usize,
+    pub blocks_read: u64,
+    // exponential moving average over the reporting intervals
+    pub blocks_per_sec: f64,
+}
+
+struct Progress {
+    // callback fires every interval blocks read
+    interval: u64,
+    callback: Box<dyn FnMut(ProgressEvent)>,
+    last_report: Instant,
+    blocks_per_sec: Option<f64>,
+}
+
+// Importer configuration with chained setters, Importer::builder().dir(path).validate_pow(true).build().
+// Unlike Importer::new, the network is mainnet unless set, and imported blocks are printed to stdout.
+pub struct ImporterBuilder {
+    dir_path: PathBuf,
+    network: Network,
+    start_file_num: u32,
+    reorg_depth: u32,
+    skip_corrupt: bool,
+    validate: bool,
+    validate_pow: bool,
+    lazy_decode: bool,
+    headers_only: bool,
+    strict_linkage: bool,
+    sink: Box<dyn BlockSink>,
+}
+
+impl Default for ImporterBuilder {
+    fn default() -> Self {
+        ImporterBuilder {
+            dir_path: PathBuf::new(),
+            network: Network::Bitcoin,
+            start_file_num: 0,
+            reorg_depth: DEFAULT_DEPTH_THRESHOLD,
+            skip_corrupt: false,
+            validate: false,
+            validate_pow: false,
+            lazy_decode: false,
+            headers_only: false,
+            strict_linkage: true,
+            sink: Box::new(StdoutSink),
+        }
+    }
+}
+
+impl ImporterBuilder {
+    pub fn dir(mut self, dir_path: impl AsRef<Path>) -> Self {
+        self.dir_path = dir_path.as_ref().to_path_buf();
+        self
+    }
+
+    pub fn network(mut self, network: Network) -> Self {
+        self.network = network;
+        self
+    }
+
+    pub fn start_file(mut self, start_file_num: u32) -> Self {
+        self.start_file_num = start_file_num;
+        self
+    }
+
+    pub fn reorg_depth(mut self, reorg_depth: u32) -> Self {
+        self.reorg_depth = reorg_depth;
+        self
+    }
+
+    pub fn skip_corrupt(mut self, skip_corrupt: bool) -> Self {
+        self.skip_corrupt = skip_corrupt;
+        self
+    }
+
+    pub fn validate(mut self, validate: bool) -> Self {
+        self.validate = validate;
+        self
+    }
+
+    pub fn validate_pow(mut self, validate_pow: bool) -> Self {
+        self.validate_pow = validate_pow;
+        self
+    }
+
+    pub fn lazy_decode(mut self, lazy_decode: bool) -> Self {
+        self.lazy_decode = lazy_decode;
+        self
+    }
+
+    pub fn headers_only(mut self, headers_only: bool) -> Self {
+        self.headers_only = headers_only;
+        self
+    }
+
+    pub fn strict_linkage(mut self, strict_linkage: bool) -> Self {
+        self.strict_linkage = strict_linkage;
+        self
+    }
+
+    pub fn sink(mut self, sink: impl BlockSink + 'static) -> Self {
+        self.sink = Box::new(sink);
+        self
+    }
+
+    pub fn build(self) -> Importer {
+        let mut importer = Importer::new(self.dir_path, self.start_file_num);
+        importer.set_network(self.network);
+        importer.set_reorg_depth(self.reorg_depth);
+        importer.set_skip_corrupt(self.skip_corrupt);
+        importer.set_validate(self.validate);
+        importer.set_validate_pow(self.validate_pow);
+        importer.set_lazy_decode(self.lazy_decode);
+        importer.set_headers_only(self.headers_only);
+        importer.set_strict_linkage(self.strict_linkage);
+        importer.sink = self.sink;
+        importer
+    }
+}
+
+impl Importer {
+    pub fn builder() -> ImporterBuilder {
+        ImporterBuilder::default()
+    }
+
+    pub fn new(dir_path: impl AsRef<Path>, start_file_num: u32) -> Self {
+        Importer {
+            dir_path: dir_path.as_ref().to_path_buf(),
+            file_num: start_file_num,
+            network: None,
+            block_cache: BlockCache::new_with_threshold(DEFAULT_DEPTH_THRESHOLD),
+            prev_block_hash: None,
+            prev_block_height: 0,
+            imported_count: 0,
+            total_chainwork: Work::from_be_bytes([0; 32]),
+            prev_block_file_num: start_file_num,
+            resume_hash: None,
+            checkpoint_path: None,
+            block_index: None,
+            blocks_read: 0,
+            progress: None,
+            skip_corrupt: false,
+            corrupt_count: 0,
+            duplicate_file_blocks: 0,
+            validate: false,
+            validate_pow: false,
+            invalid_count: 0,
+            sink: Box::new(LogSink),
+            utxo_tracker: None,
+            summaries: false,
+            op_return_min_len: None,
+            strict_linkage: true,
+            lazy_decode: false,
+            headers_only: false,
+            min_height: None,
+            max_height: None,
+            after_time: None,
+            before_time: None,
+            frame_buf: Vec::new(),
+            index: None,
+            file_offsets: HashMap::new(),
+            #[cfg(feature = "metrics")]
+            metrics: None,
+        }
+    }
+
+    // Imported blocks are sent to sink, so that a consumer thread can process them while the import continues
+    pub fn new_with_sink(
+        dir_path: impl AsRef<Path>,
+        start_file_num: u32,
+        sink: mpsc::Sender<ImportedBlock>,
+    ) -> Self {
+        let mut importer = Importer::new(dir_path, start_file_num);
+        importer.set_sink(ChannelSink::new(sink));
+        importer
+    }
+
+    pub fn set_utxo_tracker(&mut self, utxo_tracker: UtxoTracker) {
+        self.utxo_tracker = Some(utxo_tracker);
+    }
+
+    pub fn utxo_tracker(&self) -> Option<&UtxoTracker> {
+        self.utxo_tracker.as_ref()
+    }
+
+    // BlockMeta passed to the sink gets a BlockSummary, with the fee if the UTXO set is tracked
+    pub fn set_summaries(&mut self, summaries: bool) {
+        self.summaries = summaries;
+    }
+
+    // BlockMeta passed to the sink gets the OP_RETURN outputs whose payload is at least min_len bytes,
+    // 0 for all of them, e.g. 1 skips the bare OP_RETURN markers
+    pub fn set_op_return_min_len(&mut self, min_len: usize) {
+        self.op_return_min_len = Some(min_len);
+    }
+
+    // Strict by default: a block that doesn't extend the last imported block is not imported,
+    // and import_block_if_ready() and the read methods return a LinkageError.
+    // Otherwise the error is logged and the block is imported anyway.
+    pub fn set_strict_linkage(&mut self, strict_linkage: bool) {
+        self.strict_linkage = strict_linkage;
+    }
+
+    // replaces the default LogSink
+    pub fn set_sink(&mut self, sink: impl BlockSink + 'static) {
+        self.sink = Box::new(sink);
+    }
+
+    // Blocks are read in height order as listed in bitcoin core's blocks/index LevelDB, instead of
+    // file by file, so they arrive at the cache mostly in order.
+    // The LevelDB is read from a temp copy, see core_index::read_block_index()
+    #[cfg(feature = "leveldb")]
+    pub fn new_with_index(
+        dir_path: impl AsRef<Path>,
+        index_dir: impl AsRef<Path>,
+    ) -> std::io::Result<Self> {
+        let mut importer = Importer::new(dir_path, 0);
+        let block_index = core_index::read_block_index(index_dir)?;
+        info!("read block index: {} blocks", block_index.len());
+        importer.block_index = Some(block_index);
+        Ok(importer)
+    }
+
+    pub fn set_network(&mut self, network: Network) {
+        self.network = Some(network);
+    }
+
+    pub fn network(&self) -> Option<Network> {
+        self.network
+    }
+
+    pub fn set_reorg_depth(&mut self, reorg_depth: u32) {
+        self.block_cache.set_threshold(reorg_depth);
+    }
+
+    // Blocks below min_height are imported, so that the blocks in range link to them and the UTXO set is complete,
+    // but they are not passed to the sink
+    pub fn set_min_height(&mut self, min_height: u64) {
+        self.min_height = Some(min_height);
+    }
+
+    // Reading stops once the block at max_height is imported, blocks past it that migrate meanwhile
+    // are not passed to the sink
+    pub fn set_max_height(&mut self, max_height: u64) {
+        self.max_height = Some(max_height);
+    }
+
+    // Header times are not monotonic, a block may be up to 2 hours ahead of the next ones,
+    // so unlike max_height, before_time doesn't stop reading. Times are unix seconds
+    pub fn set_time_range(&mut self, after_time: Option<u32>, before_time: Option<u32>) {
+        self.after_time = after_time;
+        self.before_time = before_time;
+    }
+
+    // whether an imported block is passed to the sink
+    fn in_range(&self, block: &Block, height: u64) -> bool {
+        let time = block.header.time;
+        self.min_height
+            .is_none_or(|min_height| height >= min_height)
+            && self
+                .max_height
+                .is_none_or(|max_height| height <= max_height)
+            && self.after_time.is_none_or(|after_time| time >= after_time)
+            && self
+                .before_time
+                .is_none_or(|before_time| time < before_time)
+    }
+
+    fn reached_max_height(&self) -> bool {
+        self.prev_block_hash.is_some()
+            && self
+                .max_height
+                .is_some_and(|max_height| self.prev_block_height >= max_height)
+    }
+
+    // see BlockCache::set_removal_policy()
+    pub fn set_removal_policy(&mut self, removal_policy: RemovalPolicy) {
+        self.block_cache.set_removal_policy(removal_policy);
+    }
+
+    // see BlockCache::set_max_reorg_depth()
+    pub fn set_max_reorg_depth(&mut self, max_depth: u32) {
+        self.block_cache.set_max_reorg_depth(max_depth);
+    }
+
+    // While the cache holds max_pending_cnt blocks or more, every block read is followed by importing
+    // all the ready blocks instead of one, see BlockCache::set_max_pending()
+    pub fn set_max_pending(&mut self, max_pending_cnt: usize) {
+        self.block_cache.set_max_pending(Some(max_pending_cnt));
+    }
+
+    // see BlockCache::set_stale_margin()
+    pub fn set_stale_margin(&mut self, margin: u64) {
+        self.block_cache.set_stale_margin(margin);
+    }
+
+    pub fn set_start_file(&mut self, start_file_num: u32) {
+        self.file_num = start_file_num;
+        self.prev_block_file_num = start_file_num;
+    }
+
+    pub fn file_num(&self) -> u32 {
+        self.file_num
+    }
+
+    // hash and height of the last imported block
+    pub fn tip(&self) -> Option<(BlockHash, u64)> {
+        self.prev_block_hash
+            .map(|prev_block_hash| (prev_block_hash, self.prev_block_height))
+    }
+
+    // Cumulative work of the imported chain, compare it with bitcoin core's getblockchaininfo chainwork
+    // to confirm a complete import; it only matches if the import started from genesis
+    pub fn total_chainwork(&self) -> Work {
+        self.total_chainwork
+    }
+
+    // the counts for the end of an import, e.g. after drain_ready(0)
+    pub fn completion_report(&self) -> CompletionReport {
+        CompletionReport {
+            imported: self.imported_count,
+            pending: self.block_cache.pending_cnt(),
+            out_of_order: self.block_cache.out_of_order_cnt(),
+            duplicates: self.duplicate_file_blocks,
+            corrupt: self.corrupt_count,
+        }
+    }
+
+    pub fn block_cache(&self) -> &BlockCache<PendingBlock> {
+        &self.block_cache
+    }
+
+    // height of the first imported block, if it is not genesis and has no BIP34 height
+    pub fn set_base_height(&mut self, height: u64) {
+        self.block_cache.set_base_height(height);
+    }
+
+    // The import continues from the block base_hash at base_height, e.g. the last block a pruned node deleted:
+    // the first imported block is checked to be its child, at base_height + 1, and the chain from that child
+    // wins over blocks in the first files whose parent is unknown, see BlockCache::set_anchor().
+    // tip() is the base until the first block is imported
+    pub fn set_base(&mut self, base_hash: BlockHash, base_height: u64) {
+        self.block_cache.set_anchor(base_hash, base_height);
+        self.prev_block_hash = Some(base_hash);
+        self.prev_block_height = base_height;
+    }
+
+    // Opt-in progress reporting: the callback is called every interval blocks read
+    pub fn set_progress(&mut self, interval: u64, callback: impl FnMut(ProgressEvent) + 'static) {
+        self.progress = Some(Progress {
+            interval: interval.max(1),
+            callback: Box::new(callback),
+            last_report: Instant::now(),
+            blocks_per_sec: None,
+        });
+    }
+
+    // Opt-in Prometheus metrics, shared with whatever serves them, e.g. Metrics::serve()
+    #[cfg(feature = "metrics")]
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    pub fn set_skip_corrupt(&mut self, skip_corrupt: bool) {
+        self.skip_corrupt = skip_corrupt;
+    }
+
+    pub fn corrupt_count(&self) -> u64 {
+        self.corrupt_count
+    }
+
+    // Blocks read while a copy of them was still in the cache, or already in the index if one is built.
+    // A healthy data directory has none, copies are leftovers of a reorg or of a rescan
+    pub fn duplicate_file_blocks(&self) -> u64 {
+        self.duplicate_file_blocks
+    }
+
+    // Blocks whose merkle root doesn't match their transactions are logged, counted and not added to the cache.
+    // Costs a hash of every transaction.
+    pub fn set_validate(&mut self, validate: bool) {
+        self.validate = validate;
+    }
+
+    // Only the headers are decoded as the blocks are read, the cache keeps the blocks raw until they migrate,
+    // so losing blocks are never decoded. Ignored with set_validate(), the merkle root check needs the transactions.
+    pub fn set_lazy_decode(&mut self, lazy_decode: bool) {
+        self.lazy_decode = lazy_decode;
+    }
+
+    // Blocks whose header hash doesn't meet the target claimed in their own bits are skipped like invalid blocks.
+    // Whether bits follows the difficulty adjustment rules is not checked, only that the claimed target is met,
+    // so low difficulty regtest blocks pass.
+    // The sink gets blocks without transactions, so their BIP34 heights are unknown and the heights are counted
+    // from the cache base height. The UTXO set isn't tracked, there are no summaries and only the header is validated
+    pub fn set_headers_only(&mut self, headers_only: bool) {
+        self.headers_only = headers_only;
+    }
+
+    pub fn set_validate_pow(&mut self, validate_pow: bool) {
+        self.validate_pow = validate_pow;
+    }
+
+    // blocks skipped by set_validate() or set_validate_pow() checks
+    pub fn invalid_count(&self) -> u64 {
+        self.invalid_count
+    }
+
+    // Records where every block read from now on came from, in an Index that can be saved with index().save()
+    pub fn set_build_index(&mut self, build_index: bool) {
+        self.index = build_index.then(Index::new);
+    }
+
+    // e.g. loaded with Index::load(), for open_block(); blocks read from now on are added to it
+    pub fn set_index(&mut self, index: Index) {
+        self.index = Some(index);
+    }
+
+    pub fn index(&self) -> Option<&Index> {
+        self.index.as_ref()
+    }
+
+    // Reads just the block from the blk file the index says it is in.
+    // None if there is no index or the block isn't in it
+    pub fn open_block(&self, hash: &BlockHash) -> Result<Option<Block>> {
+        let Some(entry) = self.index.as_ref().and_then(|index| index.get(hash)) else {
+            return Ok(None);
+        };
+        let contents = open_block_file(&self.dir_path, entry.file_num).unwrap_or_else(|| {
+            Err(Error::File {
+                path: self.dir_path.join(format!("blk{:05}.dat", entry.file_num)),
+                source: std::io::ErrorKind::NotFound.into(),
+            })
+        })?;
+        // an offset past the end of the file, or of the address space, is a stale index
+        let offset = usize::try_from(entry.offset).unwrap_or(usize::MAX);
+        let bytes = offset
+            .checked_add(8)
+            .and_then(|start| contents.get(start..start.checked_add(entry.len as usize)?))
+            .ok_or(Error::ShortFrame { offset })?;
+        Ok(Some(Block::consensus_decode(&mut &bytes[..])?))
+    }
+
+    // Reads the blk files from file_num like read_files(), but only decodes the block headers, to index the blocks.
+    // Nothing is added to the cache or imported
+    pub fn scan_index(&mut self) -> Result<Index> {
+        let mut index = Index::new();
+        for (file_num, contents) in prefetch_files(self.dir_path.clone(), self.file_num) {
+            self.file_num = file_num;
+            let contents = contents?;
+            let mut i = 0;
+            while let Some(len) = self.next_frame(&contents, &mut i)? {
+                let bytes = &contents[i + 8..i + 8 + len];
+                if let Some(header) = self.decode_frame::<Header>(bytes, i)? {
+                    index.insert(header.block_hash(), self.index_entry(i, len));
+                }
+                i += 8 + len;
+            }
+            self.file_num += 1;
+        }
+        Ok(index)
+    }
+
+    // next_frame() caps len at MAX_FRAME_LEN, so it fits in a u32
+    fn index_entry(&self, offset: usize, len: usize) -> IndexEntry {
+        IndexEntry {
+            file_num: self.file_num,
+            offset: offset as u64,
+            len: len as u32,
+        }
+    }
+
+    pub fn set_checkpoint_path(&mut self, path: impl AsRef<Path>) {
+        self.checkpoint_path = Some(path.as_ref().to_path_buf());
+    }
+
+    // The checkpoint holds the last imported block hash and height, the blk file that block was read from,
+    // and the total chainwork in hex.
+    // The file is written to a temp file first and then renamed, so a crash never leaves a partial checkpoint.
+    pub fn save_checkpoint(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let path = path.as_ref();
+        let prev_block_hash = self
+            .prev_block_hash
+            .ok_or_else(|| std::io::Error::other("no block imported yet"))?;
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        writeln!(file, "{}", prev_block_hash)?;
+        writeln!(file, "{}", self.prev_block_height)?;
+        writeln!(file, "{}", self.prev_block_file_num)?;
+        writeln!(file, "{:x}", self.total_chainwork)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, path)
+    }
+
+    // Resumes the import by re-reading the blk file the checkpointed block was read from.
+    // Blocks re-read from that file which were already imported before the checkpoint are skipped.
+    // A checkpoint saved without the chainwork line resumes counting the chainwork from zero.
+    pub fn load_checkpoint(&mut self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let invalid =
+            |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string());
+        let contents = std::fs::read_to_string(path)?;
+        let mut lines = contents.lines();
+        let prev_block_hash: BlockHash = lines
+            .next()
+            .and_then(|l| l.parse().ok())
+            .ok_or_else(|| invalid("block hash expected"))?;
+        let prev_block_height = lines
+            .next()
+            .and_then(|l| l.parse().ok())
+            .ok_or_else(|| invalid("block height expected"))?;
+        let file_num = lines
+            .next()
+            .and_then(|l| l.parse().ok())
+            .ok_or_else(|| invalid("file num expected"))?;
+        let total_chainwork = match lines.next() {
+            Some(line) => {
+                Work::from_unprefixed_hex(line).map_err(|_| invalid("chainwork expected"))?
+            }
+            None => Work::from_be_bytes([0; 32]),
+        };
+        self.prev_block_hash = Some(prev_block_hash);
+        self.prev_block_height = prev_block_height;
+        self.prev_block_file_num = file_num;
+        self.total_chainwork = total_chainwork;
+        self.file_num = file_num;
+        self.resume_hash = Some(prev_block_hash);
+        Ok(())
+    }
+
+    // The first frame's magic determines the network, unless it was set explicitly.
+    // All subsequent frames must carry the same magic.
+    fn check_magic(&mut self, magic: [u8; 4], offset: usize) -> Result<()> {
+        let magic = Magic::from_bytes(magic);
+        match self.network {
+            Some(network) if network.magic() == magic => Ok(()),
+            Some(_) => Err(Error::BadMagic { offset, magic }),
+            None => {
+                let network =
+                    Network::from_magic(magic).ok_or(Error::BadMagic { offset, magic })?;
+                info!("detected network {} from magic {}", network, magic);
+                self.network = Some(network);
+                Ok(())
+            }
+        }
+    }
+
+    // Reads blk files one by one starting from file_num, until the next file can't be opened.
+    // A worker thread maps and pages in the next files while the blocks of the current one are decoded.
+    // Returns the number of blocks read.
+    pub fn read_files(&mut self) -> Result<usize> {
+        if self.block_index.is_some() {
+            return self.read_indexed_files();
+        }
+        let prefetched_files = prefetch_files(self.dir_path.clone(), self.file_num);
+        let mut block_cnt = 0;
+        for (file_num, contents) in prefetched_files {
+            self.file_num = file_num;
+            let contents = contents?;
+            info!("File blk{:05}.dat: {} bytes", file_num, contents.len());
+            #[cfg(not(feature = "rayon"))]
+            let read_count = self.read_blocks_slice(&contents)?;
+            #[cfg(feature = "rayon")]
+            let read_count = self.read_blocks_parallel(&contents)?;
+            block_cnt += read_count.blocks;
+            self.file_offsets.insert(file_num, read_count.consumed);
+            if let Some(checkpoint_path) = &self.checkpoint_path {
+                if self.prev_block_hash.is_some() {
+                    self.save_checkpoint(checkpoint_path)?;
+                }
+            }
+            if self.reached_max_height() {
+                info!(
+                    "reached max height {}, stopped reading",
+                    self.prev_block_height
+                );
+                break;
+            }
+            self.file_num += 1;
+        }
+        Ok(block_cnt)
+    }
+
+    // Reads the blk files like read_files(), then keeps reading the blocks a running node appends,
+    // polling every poll_interval for as long as keep_following() returns true, so possibly forever.
+    // The last file is re-read from where the last complete frame ended, so a block that was still being written
+    // when the file was read last time is read once it is complete. Once the next file appears, the node writes
+    // no more to the current one, which is read to its end first.
+    // Returns the number of blocks read.
+    pub fn follow(
+        &mut self,
+        poll_interval: Duration,
+        mut keep_following: impl FnMut() -> bool,
+    ) -> Result<usize> {
+        self.follow_until(|| {
+            thread::sleep(poll_interval);
+            keep_following()
+        })
+    }
+
+    // Like follow(), but the files are re-read on every wakeup from events, e.g. from watch::dir_events(),
+    // until its sender is gone. The wakeups that queued up while the files were read are handled by a single read
+    pub fn follow_events(&mut self, events: &mpsc::Receiver<()>) -> Result<usize> {
+        self.follow_until(|| {
+            let woken = events.recv().is_ok();
+            events.try_iter().count();
+            woken
+        })
+    }
+
+    // next_poll() returns when the files are to be read again, false to stop following
+    fn follow_until(&mut self, mut next_poll: impl FnMut() -> bool) -> Result<usize> {
+        let start_file_num = self.file_num;
+        let mut block_cnt = self.read_files()?;
+        // the last file read, or the first one if it doesn't exist yet
+        let mut file_num = self.file_num.saturating_sub(1).max(start_file_num);
+        while !self.reached_max_height() && next_poll() {
+            let poll_block_cnt = self.read_appended(&mut file_num)?;
+            if poll_block_cnt > 0 {
+                if let Some(checkpoint_path) = &self.checkpoint_path {
+                    if self.prev_block_hash.is_some() {
+                        self.save_checkpoint(checkpoint_path)?;
+                    }
+                }
+            }
+            block_cnt += poll_block_cnt;
+        }
+        Ok(block_cnt)
+    }
+
+    // reads the blocks appended to the file since it was read last, moving on to the next files if they exist
+    fn read_appended(&mut self, file_num: &mut u32) -> Result<usize> {
+        let mut block_cnt = 0;
+        loop {
+            // checked first, so that the blocks written to the current file before the next one was created are read
+            let next_file_exists = block_file_exists(&self.dir_path, *file_num + 1);
+            let Some(contents) = open_block_file(&self.dir_path, *file_num) else {
+                break;
+            };
+            let contents = contents?;
+            self.file_num = *file_num;
+            let offset = self.file_offsets.get(file_num).copied().unwrap_or(0);
+            let read_count = self.read_blocks_from(&contents, offset)?;
+            if read_count.blocks > 0 {
+                debug!(
+                    "blk{:05}.dat: {} new blocks at offset {}",
+                    file_num, read_count.blocks, offset
+                );
+            }
+            block_cnt += read_count.blocks;
+            self.file_offsets.insert(*file_num, read_count.consumed);
+            if !next_file_exists {
+                break;
+            }
+            *file_num += 1;
+            info!("File blk{:05}.dat: following", file_num);
+        }
+        // the next file to read, as after read_files()
+        self.file_num = *file_num + 1;
+        Ok(block_cnt)
+    }
+
+    // The file may be zstd compressed, if its name ends with .zst
+    pub fn read_file(&mut self, path: impl AsRef<Path>) -> Result<ReadCount> {
+        let contents = read_block_file(path.as_ref())?;
+        self.read_blocks_slice(&contents)
+    }
+
+    // Reads blk files served over HTTP from base_url, e.g. https://host/blocks/, like read_files() reads a directory:
+    // from the start file on until the next file isn't found. Every file is streamed with ranged GETs,
+    // see HttpRangeReader. Returns the number of blocks read.
+    #[cfg(feature = "reqwest")]
+    pub fn read_url_dir(&mut self, base_url: &str) -> Result<usize> {
+        self.read_url_dir_with_client(base_url, crate::http::default_client()?)
+    }
+
+    // Like read_url_dir(), the requests sent with client, shared by all files
+    #[cfg(feature = "reqwest")]
+    pub fn read_url_dir_with_client(
+        &mut self,
+        base_url: &str,
+        client: reqwest::blocking::Client,
+    ) -> Result<usize> {
+        let base_url = base_url.trim_end_matches('/');
+        let mut block_cnt = 0;
+        loop {
+            let url = format!("{}/blk{:05}.dat", base_url, self.file_num);
+            let file_error = |source| Error::File {
+                path: PathBuf::from(&url),
+                source,
+            };
+            let reader = match HttpRangeReader::open_with_client(client.clone(), &url) {
+                Ok(reader) => reader,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => break,
+                Err(err) => return Err(file_error(err)),
+            };
+            info!("File {}: {} bytes", url, reader.len());
+            let read_count = match self.read_blocks_streaming(reader) {
+                Err(Error::Io(err)) => return Err(file_error(err)),
+                read_count => read_count?,
+            };
+            block_cnt += read_count.blocks;
+            self.file_offsets.insert(self.file_num, read_count.consumed);
+            if let Some(checkpoint_path) = &self.checkpoint_path {
+                if self.prev_block_hash.is_some() {
+                    self.save_checkpoint(checkpoint_path)?;
+                }
+            }
+            if self.reached_max_height() {
+                info!(
+                    "reached max height {}, stopped reading",
+                    self.prev_block_height
+                );
+                break;
+            }
+            self.file_num += 1;
+        }
+        Ok(block_cnt)
+    }
+
+    // Reads the blk?????.dat entries of a tar archive, e.g. a backup of a blocks directory, straight from reader,
+    // one entry at a time into memory; the other entries are skipped. The archive is read in its order, which is
+    // the name order of an archive made with tar --sort=name, files before the start file are skipped.
+    // Returns the number of blocks read.
+    #[cfg(feature = "tar")]
+    pub fn read_tar<R: Read>(&mut self, reader: R) -> Result<usize> {
+        let start_file_num = self.file_num;
+        let mut archive = tar::Archive::new(reader);
+        let mut block_cnt = 0;
+        let mut last_file_num = None;
+        // entries() reads the archive forwards, the data of an entry not read is skipped by the next one
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_string_lossy().into_owned();
+            let is_file = entry.header().entry_type().is_file();
+            let Some(file_num) = path
+                .rsplit('/')
+                .next()
+                .and_then(|name| name.strip_prefix("blk")?.strip_suffix(".dat"))
+                .filter(|num| num.len() == 5)
+                .and_then(|num| num.parse::<u32>().ok())
+                .filter(|file_num| is_file && *file_num >= start_file_num)
+            else {
+                continue;
+            };
+            if last_file_num.is_some_and(|last_file_num| file_num < last_file_num) {
+                warn!(
+                    "{} after blk{:05}.dat in the archive, its blocks may wait long in the cache",
+                    path,
+                    last_file_num.unwrap()
+                );
+            }
+            last_file_num = Some(file_num);
+            self.file_num = file_num;
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            info!("File {}: {} bytes", path, contents.len());
+            let read_count = self.read_blocks_slice(&contents)?;
+            block_cnt += read_count.blocks;
+            self.file_offsets.insert(file_num, read_count.consumed);
+            if self.reached_max_height() {
+                info!(
+                    "reached max height {}, stopped reading",
+                    self.prev_block_height
+                );
+                break;
+            }
+        }
+        Ok(block_cnt)
+    }
+
+    // Reads the blocks from the block index entries in height order, skipping the ones in files before file_num
+    fn read_indexed_files(&mut self) -> Result<usize> {
+        let block_index = self.block_index.take().expect("block index expected");
+        let result = self.read_index_entries(&block_index);
+        self.block_index = Some(block_index);
+        result
+    }
+
+    fn read_index_entries(&mut self, block_index: &[BlockIndexEntry]) -> Result<usize> {
+        let start_file_num = self.file_num;
+        let mut block_cnt = 0;
+        let mut open_file: Option<(u32, BlockFile)> = None;
+        for entry in block_index {
+            if entry.file_num < start_file_num {
+                continue;
+            }
+            if open_file.as_ref().map(|(file_num, _)| *file_num) != Some(entry.file_num) {
+                let contents =
+                    open_block_file(&self.dir_path, entry.file_num).unwrap_or_else(|| {
+                        Err(Error::File {
+                            path: self.dir_path.join(format!("blk{:05}.dat", entry.file_num)),
+                            source: std::io::ErrorKind::NotFound.into(),
+                        })
+                    })?;
+                open_file = Some((entry.file_num, contents));
+            }
+            let file_bytes = &open_file.as_ref().expect("open file expected").1;
+            self.file_num = entry.file_num;
+
+            let offset = entry.data_pos as usize;
+            let frame_header = offset
+                .checked_sub(8)
+                .and_then(|i| file_bytes.get(i..offset))
+                .ok_or(Error::ShortFrame { offset })?;
+            self.check_magic(frame_header[0..4].try_into().unwrap(), offset - 8)?;
+            let len = u32::from_le_bytes(frame_header[4..8].try_into().unwrap()) as usize;
+            let bytes = file_bytes
+                .get(offset..offset + len)
+                .ok_or(Error::ShortFrame { offset })?;
+            if self.read_block(bytes, offset - 8)? {
+                block_cnt += 1;
+            }
+
+            self.import_block_if_ready_default()?;
+            if self.reached_max_height() {
+                break;
+            }
+        }
+        Ok(block_cnt)
+    }
+
+    // Reading stops at the zero padding or at a truncated final frame, e.g. one that is still being written by a node.
+    // An empty or all zero buffer, e.g. a blk file bitcoin core just created, reads no blocks.
+    pub fn read_blocks(&mut self, file_bytes: Vec<u8>) -> Result<ReadCount> {
+        self.read_blocks_slice(&file_bytes)
+    }
+
+    pub fn read_blocks_slice(&mut self, file_bytes: &[u8]) -> Result<ReadCount> {
+        self.read_blocks_from(file_bytes, 0)
+    }
+
+    // starts reading at the frame at offset start, the consumed count includes the bytes before it
+    fn read_blocks_from(&mut self, file_bytes: &[u8], start: usize) -> Result<ReadCount> {
+        let mut block_cnt = 0;
+        let mut i = start;
+        while let Some(len) = self.next_frame(file_bytes, &mut i)? {
+            let bytes = &file_bytes[i + 8..i + 8 + len];
+            if self.read_block(bytes, i)? {
+                block_cnt += 1;
+            }
+
+            i += 8 + len;
+
+            self.import_block_if_ready_default()?;
+            if self.reached_max_height() {
+                break;
+            }
+        }
+        Ok(ReadCount {
+            blocks: block_cnt,
+            consumed: i,
+        })
+    }
+
+    // Same as read_blocks_slice, but all the frames of the file are sliced out first and decoded on rayon's thread pool,
+    // a batch of DECODED_PER_THREAD frames per thread at a time. The decoded blocks of a batch are collected in file
+    // order and added to the cache in it, as the out-of-order handling depends on it, before the next batch is decoded.
+    // Raw blocks are cheap to add, so in lazy_decode mode this is the same as read_blocks_slice.
+    #[cfg(feature = "rayon")]
+    pub fn read_blocks_parallel(&mut self, file_bytes: &[u8]) -> Result<ReadCount> {
+        if self.headers_only || (self.lazy_decode && !self.validate) {
+            return self.read_blocks_slice(file_bytes);
+        }
+        let mut frames = Vec::new();
+        let mut i = 0;
+        while let Some(len) = self.next_frame(file_bytes, &mut i)? {
+            frames.push((i, &file_bytes[i + 8..i + 8 + len]));
+            i += 8 + len;
+        }
+
+        let mut read_count = ReadCount {
+            blocks: 0,
+            consumed: i,
+        };
+        let batch_len = rayon::current_num_threads() * DECODED_PER_THREAD;
+        for batch in frames.chunks(batch_len) {
+            let decoded: Vec<_> = batch
+                .par_iter()
+                .map(|(_, bytes)| Block::consensus_decode(&mut &bytes[..]))
+                .collect();
+            for (&(offset, bytes), decoded) in batch.iter().zip(decoded) {
+                if self.add_decoded_block(decoded, bytes, offset)? {
+                    read_count.blocks += 1;
+                }
+
+                self.import_block_if_ready_default()?;
+                if self.reached_max_height() {
+                    // the frames after this one were never added
+                    read_count.consumed = offset + 8 + bytes.len();
+                    return Ok(read_count);
+                }
+            }
+        }
+        Ok(read_count)
+    }
+
+    // Returns the length of the block in the frame at *i, or None at the end of data, where *i is left:
+    // at the end of the buffer, at zero padding that runs to the end, or at a frame or frame header cut short.
+    // Garbage or zeros before the next frame are skipped, moving *i to it.
+    fn next_frame(&mut self, file_bytes: &[u8], i: &mut usize) -> Result<Option<usize>> {
+        loop {
+            if *i >= file_bytes.len() {
+                return Ok(None);
+            }
+            if file_bytes[*i..].iter().all(|byte| *byte == 0) {
+                // bitcoin core preallocates blk files, the zero padding after the last block is the end of data,
+                // however short
+                return Ok(None);
+            }
+
+            if file_bytes[*i] == 0 {
+                // no frame magic starts with a zero byte, so these zeros are followed by more frames,
+                // e.g. a region a crash left unwritten before the blocks written after it
+                let zero_len = file_bytes[*i..]
+                    .iter()
+                    .position(|byte| *byte != 0)
+                    .expect("non-zero byte expected");
+                warn!("skipping {} zero bytes at offset {}", zero_len, *i);
+                *i += zero_len;
+                continue;
+            }
+
+            let Some(frame_header) = file_bytes.get(*i..*i + 8) else {
+                // like a truncated frame, the rest of the header may still be being written
+                warn!(
+                    "truncated frame header at offset {}: {} bytes available",
+                    *i,
+                    file_bytes.len() - *i
+                );
+                return Ok(None);
+            };
+            let magic: [u8; 4] = frame_header[0..4].try_into().unwrap();
+            let len = u32::from_le_bytes(frame_header[4..8].try_into().unwrap()) as usize;
+            trace!("read frame at offset {}: {} bytes", *i, len);
+            if let Err(err) = self.check_magic(magic, *i) {
+                // like bitcoind, skip ahead to the next frame magic, once the network is known
+                let Some(network) = self.network else {
+                    return Err(err);
+                };
+                *i += skip_to_magic(file_bytes, *i, network, &err);
+                continue;
+            }
+            if len == 0 || len > MAX_FRAME_LEN {
+                // a corrupt length, the frame's block can't be found but the next frame can
+                let err = match len {
+                    0 => Error::EmptyFrame { offset: *i },
+                    _ => Error::OversizedFrame { offset: *i, len },
+                };
+                *i += skip_to_magic(
+                    file_bytes,
+                    *i,
+                    self.network.expect("network expected"),
+                    &err,
+                );
+                continue;
+            }
+            if *i + 8 + len > file_bytes.len() {
+                warn!(
+                    "truncated frame at offset {}: {} bytes expected, {} available",
+                    *i,
+                    len,
+                    file_bytes.len() - *i - 8
+                );
+                return Ok(None);
+            }
+            return Ok(Some(len));
+        }
+    }
+
+    // Same framing as read_blocks, but the frames are read from the reader one at a time,
+    // so the whole blk file never has to be held in memory. The reader can't be searched for the next frame,
+    // so a frame with a bad magic or a corrupt length stops the read with an error. Zeros between frames are
+    // skipped like read_blocks does, the zero padding at the end is read through to the end of the reader.
+    // Every frame is read into the importer's frame buffer, which grows to the largest block read (~4 MB)
+    // and is reused by the next calls.
+    pub fn read_blocks_streaming<R: Read>(&mut self, reader: R) -> Result<ReadCount> {
+        let mut bytes = std::mem::take(&mut self.frame_buf);
+        let result = self.read_frames(reader, &mut bytes);
+        self.frame_buf = bytes;
+        result
+    }
+
+    fn read_frames<R: Read>(&mut self, mut reader: R, bytes: &mut Vec<u8>) -> Result<ReadCount> {
+        let mut block_cnt = 0;
+        // position in the reader, and the end of the last complete frame
+        let mut offset = 0;
+        let mut consumed = 0;
+        let mut frame_header = [0u8; 8];
+        loop {
+            match read_frame_header(&mut reader, &mut frame_header, &mut offset) {
+                Ok(()) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err.into()),
+            }
+
+            let magic: [u8; 4] = frame_header[0..4].try_into().unwrap();
+            let len = u32::from_le_bytes(frame_header[4..8].try_into().unwrap()) as usize;
+            self.check_magic(magic, offset)?;
+            if len == 0 {
+                return Err(Error::EmptyFrame { offset });
+            }
+            if len > MAX_FRAME_LEN {
+                return Err(Error::OversizedFrame { offset, len });
+            }
+            bytes.resize(len, 0);
+            match reader.read_exact(bytes) {
+                Ok(()) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    warn!(
+                        "truncated frame at offset {}: {} bytes expected",
+                        offset, len
+                    );
+                    break;
+                }
+                Err(err) => return Err(err.into()),
+            }
+            if self.read_block(bytes, offset)? {
+                block_cnt += 1;
+            }
+
+            offset += 8 + len;
+            consumed = offset;
+
+            self.import_block_if_ready_default()?;
+            if self.reached_max_height() {
+                break;
+            }
+        }
+        Ok(ReadCount {
+            blocks: block_cnt,
+            consumed,
+        })
+    }
+
+    // Adds the frame's block to the cache, false if it was skipped as corrupt.
+    // In lazy_decode mode only the header is decoded, unless the block is to be validated.
+    fn read_block(&mut self, bytes: &[u8], offset: usize) -> Result<bool> {
+        if self.headers_only {
+            let Some(header) = self.decode_frame::<Header>(bytes, offset)? else {
+                return Ok(false);
+            };
+            let added = match self.check_header(&header) {
+                Err(err) => Err(err),
+                Ok(()) => Ok(self
+                    .block_cache
+                    .add_header_from(header, self.source_loc(offset))),
+            };
+            self.count_read_block(&header, added, offset, bytes.len());
+            Ok(true)
+        } else if self.lazy_decode && !self.validate {
+            let Some(header) = self.decode_frame::<Header>(bytes, offset)? else {
+                return Ok(false);
+            };
+            let added = match self.check_header(&header) {
+                Err(err) => Err(err),
+                Ok(()) => Ok(self
+                    .block_cache
+                    .add_raw_block_from(bytes.to_vec(), self.source_loc(offset))?),
+            };
+            self.count_read_block(&header, added, offset, bytes.len());
+            Ok(true)
+        } else {
+            self.add_decoded_block(Block::consensus_decode(&mut &bytes[..]), bytes, offset)
+        }
+    }
+
+    // decoded is the result of decoding the frame bytes
+    fn add_decoded_block(
+        &mut self,
+        decoded: std::result::Result<Block, encode::Error>,
+        bytes: &[u8],
+        offset: usize,
+    ) -> Result<bool> {
+        let Some(block) = self.check_decoded(decoded, bytes, offset)? else {
+            return Ok(false);
+        };
+        let header = block.header;
+        let added = match self.check_block(&block) {
+            Err(err) => Err(err),
+            Ok(()) => Ok(self
+                .block_cache
+                .add_block_from(block.into(), self.source_loc(offset))),
+        };
+        self.count_read_block(&header, added, offset, bytes.len());
+        Ok(true)
+    }
+
+    fn source_loc(&self, offset: usize) -> SourceLoc {
+        SourceLoc {
+            file_num: self.file_num,
+            offset,
+        }
+    }
+
+    // In skip_corrupt mode, a block that fails to decode is logged and counted, and None is returned
+    // so the reader continues with the next frame.
+    fn decode_frame<T: Decodable>(&mut self, bytes: &[u8], offset: usize) -> Result<Option<T>> {
+        self.check_decoded(T::consensus_decode(&mut &bytes[..]), bytes, offset)
+    }
+
+    fn check_decoded<T>(
+        &mut self,
+        decoded: std::result::Result<T, encode::Error>,
+        bytes: &[u8],
+        offset: usize,
+    ) -> Result<Option<T>> {
+        match decoded {
+            Ok(block) => Ok(Some(block)),
+            Err(err) if self.skip_corrupt => {
+                self.corrupt_count += 1;
+                warn!(
+                    "skipping corrupt block in blk{:05}.dat at offset {}: {}, frame {}",
+                    self.file_num,
+                    offset,
+                    err,
+                    hex::encode(bytes)
+                );
+                Ok(None)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // added is what the cache did with the block, or an Error::Invalid.
+    // offset is the position of the block's frame within the current file, len the length of the block
+    fn count_read_block(
+        &mut self,
+        header: &Header,
+        added: Result<AddResult>,
+        offset: usize,
+        len: usize,
+    ) {
+        let block_hash = header.block_hash();
+        trace!(
+            "...read block {:?} header: work {} prev_hash {:?}",
+            block_hash,
+            header.work(),
+            header.prev_blockhash
+        );
+        // a block already in the cache is all the cache turns down besides stale blocks,
+        // and the index keeps the first copy of every block read
+        let duplicate = matches!(added, Ok(AddResult::Skipped))
+            && (self.block_cache.contains_block(&block_hash)
+                || self
+                    .index
+                    .as_ref()
+                    .is_some_and(|index| index.get(&block_hash).is_some()));
+        if added.is_ok() {
+            let entry = self.index_entry(offset, len);
+            if let Some(index) = &mut self.index {
+                index.insert(block_hash, entry);
+            }
+        }
+        match added {
+            Err(err) => {
+                self.invalid_count += 1;
+                warn!("--- skipping {}", err);
+            }
+            Ok(AddResult::Accepted | AddResult::Full) => {}
+            Ok(AddResult::Skipped) if duplicate => {
+                self.duplicate_file_blocks += 1;
+                warn!(
+                    "--- skipping duplicate block {:?} in blk{:05}.dat at offset {}",
+                    block_hash, self.file_num, offset
+                );
+            }
+            Ok(AddResult::Skipped) => warn!("--- skipping block {:?}", block_hash),
+        }
+        self.blocks_read += 1;
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            // the frame is the magic, the length and the block
+            metrics.on_block_read(8 + len);
+        }
+        self.update_pending_metrics();
+        if let Some(progress) = &mut self.progress {
+            if self.blocks_read.is_multiple_of(progress.interval) {
+                progress.report(self.file_num, offset, self.blocks_read);
+            }
+        }
+    }
+
+    fn check_block(&self, block: &Block) -> Result<()> {
+        if self.validate && !block.check_merkle_root() {
+            return Err(Error::Invalid {
+                block_hash: block.block_hash(),
+                reason: "merkle root mismatch",
+            });
+        }
+        self.check_header(&block.header)
+    }
+
+    fn check_header(&self, header: &Header) -> Result<()> {
+        if self.validate_pow && header.validate_pow(header.target()).is_err() {
+            return Err(Error::Invalid {
+                block_hash: header.block_hash(),
+                reason: "proof of work doesn't meet the target",
+            });
+        }
+        Ok(())
+    }
+
+    // explicit cache_threshold, e.g. 0 to drain the cache after the last file
+    pub fn import_block_if_ready(&mut self, cache_threshold: u32) -> Result<()> {
+        // check if the top (FIFO) block in the cache is ready for import
+        let Some(block_hash) = self
+            .block_cache
+            .peek_ready(cache_threshold)
+            .map(|block_info| block_info.hash)
+        else {
+            return Ok(());
+        };
+        let source = self.block_cache.source_of(&block_hash);
+        let removed = self
+            .block_cache
+            .remove_block_with_height_if_ready(cache_threshold)
+            .inspect_err(|_| {
+                // the root is gone with its block, but the losing blocks it purged are still reported
+                self.report_purged();
+                self.update_pending_metrics();
+            })?;
+        if let Some((pending_block, cache_height)) = removed {
+            // a raw block is decoded only now
+            let header = *pending_block.header();
+            let imported = match pending_block.into_block() {
+                Ok(block) => self.import_block(block, cache_height, source),
+                Err(err) => self.skip_undecodable(header, cache_height, source, err),
+            };
+            self.update_pending_metrics();
+            imported?;
+        }
+        Ok(())
+    }
+
+    // A migrated block that can't be decoded stops the import, unless in skip_corrupt mode, where it is counted
+    // as corrupt and the import continues from it without passing it to the sink
+    fn skip_undecodable(
+        &mut self,
+        header: Header,
+        cache_height: u64,
+        source_loc: Option<SourceLoc>,
+        err: encode::Error,
+    ) -> Result<()> {
+        self.report_purged();
+        let err = Error::PendingDecode {
+            block_hash: header.block_hash(),
+            source_loc,
+            source: err,
+        };
+        if !self.skip_corrupt {
+            return Err(err);
+        }
+        self.corrupt_count += 1;
+        warn!("skipping {}", err);
+        self.prev_block_height = match self.prev_block_hash {
+            Some(_) => self.prev_block_height + 1,
+            None => cache_height,
+        };
+        self.prev_block_hash = Some(header.block_hash());
+        self.total_chainwork = add_work(self.total_chainwork, header.work());
+        Ok(())
+    }
+
+    fn update_pending_metrics(&self) {
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.set_pending(
+                self.block_cache.pending_cnt(),
+                self.block_cache.out_of_order_cnt(),
+            );
+        }
+    }
+
+    // Imports blocks until none is ready at cache_threshold, 0 imports all the staged blocks,
+    // e.g. after the last file
+    pub fn drain_ready(&mut self, cache_threshold: u32) -> Result<()> {
+        while self.block_cache.peek_ready(cache_threshold).is_some() && !self.reached_max_height() {
+            self.import_block_if_ready(cache_threshold)?;
+        }
+        Ok(())
+    }
+
+    // a full cache imports the blocks ready at the threshold until it has room again.
+    // It stays full while none is, as importing at a lower threshold would give up on the reorgs it waits for
+    fn import_block_if_ready_default(&mut self) -> Result<()> {
+        let threshold = self.block_cache.threshold();
+        self.import_block_if_ready(threshold)?;
+        while self.block_cache.is_full()
+            && self.block_cache.peek_ready(threshold).is_some()
+            && !self.reached_max_height()
+        {
+            self.import_block_if_ready(threshold)?;
+        }
+        Ok(())
+    }
+
+    // called right after a root removal
+    fn report_purged(&mut self) {
+        if let Some(reorg) = self.block_cache.last_reorg() {
+            #[cfg(feature = "metrics")]
+            if let Some(metrics) = &self.metrics {
+                metrics.on_reorg(reorg.abandoned_blocks.len(), reorg.depth);
+            }
+            if let Some(utxo_tracker) = &mut self.utxo_tracker {
+                utxo_tracker.on_reorg(&reorg.abandoned_blocks);
+            }
+            self.sink.on_reorg(reorg);
+        }
+    }
+
+    // cache_height is the height the cache counted from its base height
+    fn import_block(
+        &mut self,
+        block: Block,
+        cache_height: u64,
+        source: Option<SourceLoc>,
+    ) -> Result<()> {
+        self.report_purged();
+        let block_hash = block.block_hash();
+        if let Some(resume_hash) = self.resume_hash {
+            // a block re-read after resuming from a checkpoint is skipped, unless it follows the checkpointed block
+            if block.header.prev_blockhash != resume_hash {
+                if block_hash == resume_hash {
+                    self.resume_hash = None;
+                }
+                debug!("--- skipping already imported block {:?}", block_hash);
+                return Ok(());
+            }
+            self.resume_hash = None;
+        }
+        // counted from the last imported block, which survives a resume unlike the cache's count.
+        // An import that doesn't start from genesis starts from the BIP34 height if there is one
+        let block_height = match self.prev_block_hash {
+            Some(_) => self.prev_block_height + 1,
+            None => block.bip34_block_height().unwrap_or(cache_height),
+        };
+        if let Some(prev_block_hash) = self.prev_block_hash {
+            match block.bip34_block_height() {
+                Ok(bip34_height) if bip34_height != block_height => warn!(
+                    "prev imported block {:?} {}, current block {:?} BIP34 height {} prev_hash {:?}",
+                    prev_block_hash,
+                    self.prev_block_height,
+                    block_hash,
+                    bip34_height,
+                    block.header.prev_blockhash
+                ),
+                _ => {}
+            }
+            if prev_block_hash != block.header.prev_blockhash {
+                let err = LinkageError {
+                    block_hash,
+                    prev_hash: block.header.prev_blockhash,
+                    expected_prev_hash: prev_block_hash,
+                };
+                if self.strict_linkage {
+                    return Err(err.into());
+                }
+                error!("{}, imported anyway", err);
+            }
+        }
+        self.prev_block_hash = Some(block_hash);
+        self.prev_block_height = block_height;
+        self.total_chainwork = add_work(self.total_chainwork, block.header.work());
+        self.imported_count += 1;
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.on_block_imported(block_height);
+        }
+        self.prev_block_file_num = source.map_or(self.file_num, |source| source.file_num);
+        let fee = self
+            .utxo_tracker
+            .as_mut()
+            .filter(|_| !self.headers_only)
+            .and_then(|utxo_tracker| utxo_tracker.apply_block(&block, block_height));
+        if !self.in_range(&block, block_height) {
+            return Ok(());
+        }
+        let summary = (self.summaries && !self.headers_only).then(|| {
+            let mut summary = block_summary(&block);
+            summary.fee = fee;
+            summary
+        });
+        let meta = BlockMeta {
+            height: block_height,
+            file_num: self.prev_block_file_num,
+            summary,
+            op_returns: self
+                .op_return_min_len
+                .filter(|_| !self.headers_only)
+                .map_or_else(Vec::new, |min_len| op_returns(&block, min_len)),
+        };
+        self.sink.on_block(&block, &meta);
+        Ok(())
+    }
+}
+
+impl CompletionReport {
+    // nothing left in the cache, every block read was imported or purged as losing
+    pub fn is_complete(&self) -> bool {
+        self.pending == 0
+    }
+}
+
+impl fmt::Display for CompletionReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "imported {} pending {} out of order {} duplicates {} corrupt {}",
+            self.imported, self.pending, self.out_of_order, self.duplicates, self.corrupt
+        )
+    }
+}
+
+impl Progress {
+    fn report(&mut self, file_num: u32, offset: usize, blocks_read: u64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_report).as_secs_f64();
+        self.last_report = now;
+        let rate = self.interval as f64 / elapsed.max(f64::EPSILON);
+        let blocks_per_sec = match self.blocks_per_sec {
+            Some(avg) => 0.8 * avg + 0.2 * rate,
+            None => rate,
+        };
+        self.blocks_per_sec = Some(blocks_per_sec);
+        (self.callback)(ProgressEvent {
+            file_num,
+            offset,
+            blocks_read,
+            blocks_per_sec,
+        });
+    }
+}
+
+// Blocks each thread of read_blocks_parallel() decodes in a batch
+#[cfg(feature = "rayon")]
+const DECODED_PER_THREAD: usize = 16;
+
+// Number of opened blk files the prefetch worker keeps ready ahead of the reader
+const PREFETCH_DEPTH: usize = 2;
+
+// Contents of a blk file, either mapped or read into memory, decompressed if it is blk?????.dat.zst
+enum BlockFile {
+    #[cfg(feature = "mmap")]
+    Mapped(Mmap),
+    #[cfg(any(feature = "zstd", not(feature = "mmap")))]
+    Read(Vec<u8>),
+}
+
+impl Deref for BlockFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            #[cfg(feature = "mmap")]
+            BlockFile::Mapped(mmap) => mmap,
+            #[cfg(any(feature = "zstd", not(feature = "mmap")))]
+            BlockFile::Read(bytes) => bytes,
+        }
+    }
+}
+
+// Reads the next frame header, skipping the zeros before it, and moves *offset to it.
+// No frame magic starts with a zero byte, so the header starts at the first non-zero byte
+fn read_frame_header<R: Read>(
+    reader: &mut R,
+    frame_header: &mut [u8; 8],
+    offset: &mut usize,
+) -> std::io::Result<()> {
+    reader.read_exact(frame_header)?;
+    loop {
+        match frame_header.iter().position(|byte| *byte != 0) {
+            Some(0) => return Ok(()),
+            Some(zero_len) => {
+                frame_header.copy_within(zero_len.., 0);
+                reader.read_exact(&mut frame_header[8 - zero_len..])?;
+                *offset += zero_len;
+                return Ok(());
+            }
+            None => {
+                reader.read_exact(frame_header)?;
+                *offset += 8;
+            }
+        }
+    }
+}
+
+// blk?????.dat or blk?????.dat.zst exists for file_num
+fn block_file_exists(dir_path: &Path, file_num: u32) -> bool {
+    let file_path = dir_path.join(format!("blk{:05}.dat", file_num));
+    file_path.exists() || file_path.with_extension("dat.zst").exists()
+}
+
+// Number of bytes from the frame at offset to the next frame magic of the network, or to the end of the file
+fn skip_to_magic(file_bytes: &[u8], offset: usize, network: Network, err: &Error) -> usize {
+    let magic = network.magic().to_bytes();
+    let skipped = file_bytes[offset + 1..]
+        .windows(4)
+        .position(|window| window == magic)
+        .map_or(file_bytes.len() - offset, |pos| pos + 1);
+    warn!(
+        "{} at offset {}, skipped {} bytes to the next frame",
+        err, offset, skipped
+    );
+    skipped
+}
+
+// Opens blk?????.dat for file_num, or blk?????.dat.zst if only the compressed file exists.
+// Returns None if there is neither.
+fn open_block_file(dir_path: &Path, file_num: u32) -> Option<Result<BlockFile>> {
+    let file_path = dir_path.join(format!("blk{:05}.dat", file_num));
+    if file_path.exists() {
+        return Some(read_block_file(&file_path));
+    }
+    let zst_path = file_path.with_extension("dat.zst");
+    if zst_path.exists() {
+        return Some(read_block_file(&zst_path));
+    }
+    None
+}
+
+// A .zst file is decompressed into memory, any other file is mapped with the mmap feature, read into memory
+// without it. Without the zstd feature a .zst file is an Unsupported error. The mapping is populated up front
+// so that the disk reads happen here rather than while decoding.
+fn read_block_file(path: &Path) -> Result<BlockFile> {
+    let file_error = |source| Error::File {
+        path: path.to_path_buf(),
+        source,
+    };
+    let file = File::open(path).map_err(file_error)?;
+    if path.extension().is_some_and(|ext| ext == "zst") {
+        #[cfg(not(feature = "zstd"))]
+        return Err(file_error(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "built without the zstd feature",
+        )));
+        #[cfg(feature = "zstd")]
+        {
+            let mut contents = Vec::new();
+            zstd::Decoder::new(file)
+                .and_then(|mut decoder| decoder.read_to_end(&mut contents))
+                .map_err(file_error)?;
+            Ok(BlockFile::Read(contents))
+        }
+    } else {
+        // the node may append to the file while it is mapped or read, but only past the
+        // frames already written, which read_blocks_slice treats as a truncated tail
+        #[cfg(feature = "mmap")]
+        {
+            let contents =
+                unsafe { MmapOptions::new().populate().map(&file) }.map_err(file_error)?;
+            Ok(BlockFile::Mapped(contents))
+        }
+        #[cfg(not(feature = "mmap"))]
+        {
+            let mut contents = Vec::new();
+            (&file).read_to_end(&mut contents).map_err(file_error)?;
+            Ok(BlockFile::Read(contents))
+        }
+    }
+}
+
+// Spawns a worker that opens blk files in order starting from start_file_num, until the next file doesn't exist,
+// so that the disk reads overlap with the decoding of the previous file.
+fn prefetch_files(
+    dir_path: PathBuf,
+    start_file_num: u32,
+) -> mpsc::IntoIter<(u32, Result<BlockFile>)> {
+    let (sender, receiver) = mpsc::sync_channel(PREFETCH_DEPTH);
+    thread::spawn(move || {
+        for file_num in start_file_num.. {
+            let Some(contents) = open_block_file(&dir_path, file_num) else {
+                // file not found, assume it's the last file
+                info!("blk{:05}.dat not found, assuming the last file", file_num);
+                break;
+            };
+            if sender.send((file_num, contents)).is_err() {
+                // the reader stopped
+                break;
+            }
+        }
+    });
+    receiver.into_iter()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_cache::ReorgEvent;
+    use crate::summary::BlockSummary;
+    use bitcoin::consensus::serialize;
+    use bitcoin::constants::genesis_block;
+    use bitcoin::hashes::Hash;
+    use bitcoin::Amount;
+    use bitcoin::CompactTarget;
+    use bitcoin::TxMerkleNode;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn block_frame(network: Network, block: &Block) -> Vec<u8> {
+        let block_bytes = serialize(block);
+        let mut frame = network.magic().to_bytes().to_vec();
+        frame.extend_from_slice(&(block_bytes.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&block_bytes);
+        frame
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("read-blk-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_read_files() {
+        let dir = temp_dir("read-files");
+        std::fs::write(
+            dir.join("blk00000.dat"),
+            block_frame(Network::Bitcoin, &genesis_block(Network::Bitcoin)),
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("blk00001.dat"),
+            block_frame(Network::Bitcoin, &genesis_block(Network::Testnet)),
+        )
+        .unwrap();
+
+        let mut importer = Importer::new(&dir, 0);
+        assert_eq!(importer.read_files().unwrap(), 2);
+        assert_eq!(importer.file_num(), 2);
+        assert_eq!(importer.block_cache.pending_cnt(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_empty_file() {
+        let mut importer = Importer::new("", 0);
+        for file_bytes in [Vec::new(), vec![0; 5], vec![0; 4096]] {
+            let read_count = importer.read_blocks(file_bytes.clone()).unwrap();
+            assert_eq!(read_count.blocks, 0);
+            assert_eq!(read_count.consumed, 0);
+            let read_count = importer
+                .read_blocks_streaming(file_bytes.as_slice())
+                .unwrap();
+            assert_eq!(read_count.blocks, 0);
+        }
+        assert_eq!(importer.network(), None);
+
+        // the scan goes on past a freshly created and a preallocated file
+        let dir = temp_dir("read-empty-files");
+        std::fs::write(dir.join("blk00000.dat"), []).unwrap();
+        std::fs::write(dir.join("blk00001.dat"), [0; 4096]).unwrap();
+        std::fs::write(
+            dir.join("blk00002.dat"),
+            block_frame(Network::Bitcoin, &genesis_block(Network::Bitcoin)),
+        )
+        .unwrap();
+        let mut importer = Importer::new(&dir, 0);
+        assert_eq!(importer.read_files().unwrap(), 1);
+        assert_eq!(importer.file_num(), 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_read_compressed_files() {
+        let dir = temp_dir("read-compressed-files");
+        std::fs::write(
+            dir.join("blk00000.dat.zst"),
+            zstd::encode_all(
+                block_frame(Network::Bitcoin, &genesis_block(Network::Bitcoin)).as_slice(),
+                0,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("blk00001.dat"),
+            block_frame(Network::Bitcoin, &genesis_block(Network::Testnet)),
+        )
+        .unwrap();
+
+        let mut importer = Importer::new(&dir, 0);
+        assert_eq!(importer.read_files().unwrap(), 2);
+        assert_eq!(importer.block_cache.pending_cnt(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_trailing_zero_padding() {
+        let mut file_bytes = block_frame(Network::Bitcoin, &genesis_block(Network::Bitcoin));
+        file_bytes.extend_from_slice(&[0; 4096]);
+
+        let mut importer = Importer::new("", 0);
+        assert_eq!(importer.read_blocks(file_bytes).unwrap().blocks, 1);
+        assert_eq!(importer.network(), Some(Network::Bitcoin));
+        assert_eq!(importer.block_cache.pending_cnt(), 1);
+        assert_eq!(importer.block_cache.staged_cnt(), 1);
+    }
+
+    #[test]
+    fn test_zeros_between_frames() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut next_block = genesis.clone();
+        next_block.header.prev_blockhash = genesis.block_hash();
+        let mut file_bytes = block_frame(Network::Bitcoin, &genesis);
+        file_bytes.extend_from_slice(&[0; 4099]);
+        file_bytes.extend(block_frame(Network::Bitcoin, &next_block));
+        file_bytes.extend_from_slice(&[0; 4096]);
+        let consumed = file_bytes.len() - 4096;
+
+        let mut importer = Importer::new("", 0);
+        let read_count = importer.read_blocks_slice(&file_bytes).unwrap();
+        assert_eq!(
+            read_count,
+            ReadCount {
+                blocks: 2,
+                consumed
+            }
+        );
+        let mut importer = Importer::new("", 0);
+        let read_count = importer
+            .read_blocks_streaming(file_bytes.as_slice())
+            .unwrap();
+        assert_eq!(
+            read_count,
+            ReadCount {
+                blocks: 2,
+                consumed
+            }
+        );
+        assert_eq!(importer.block_cache.pending_cnt(), 2);
+    }
+
+    #[test]
+    fn test_read_blocks_streaming() {
+        let mut file_bytes = block_frame(Network::Bitcoin, &genesis_block(Network::Bitcoin));
+        file_bytes.extend_from_slice(&[0; 4096]);
+
+        let mut importer = Importer::new("", 0);
+        assert_eq!(
+            importer
+                .read_blocks_streaming(file_bytes.as_slice())
+                .unwrap()
+                .blocks,
+            1
+        );
+        assert_eq!(importer.network(), Some(Network::Bitcoin));
+        assert_eq!(importer.block_cache.pending_cnt(), 1);
+        // the frame buffer is kept for the next file
+        assert!(importer.frame_buf.capacity() >= 285);
+    }
+
+    #[test]
+    fn test_tip() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut next_block = genesis.clone();
+        next_block.header.prev_blockhash = genesis.block_hash();
+        let mut file_bytes = block_frame(Network::Bitcoin, &genesis);
+        file_bytes.extend(block_frame(Network::Bitcoin, &next_block));
+
+        let mut importer = Importer::new("", 0);
+        importer.read_blocks(file_bytes).unwrap();
+        assert_eq!(importer.tip(), None);
+        importer.import_block_if_ready(0).unwrap();
+        assert_eq!(importer.tip(), Some((genesis.block_hash(), 0)));
+        assert_eq!(importer.total_chainwork(), genesis.header.work());
+        let report = importer.completion_report();
+        assert_eq!(
+            report,
+            CompletionReport {
+                imported: 1,
+                pending: 1,
+                out_of_order: 0,
+                duplicates: 0,
+                corrupt: 0,
+            }
+        );
+        assert!(!report.is_complete());
+        importer.drain_ready(0).unwrap();
+        assert!(importer.completion_report().is_complete());
+        assert_eq!(
+            importer.completion_report().to_string(),
+            "imported 2 pending 0 out of order 0 duplicates 0 corrupt 0"
+        );
+    }
+
+    #[test]
+    fn test_total_chainwork() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let child_block = |parent: &Block, bits: u32| {
+            let mut block = genesis.clone();
+            block.header.prev_blockhash = parent.block_hash();
+            block.header.bits = CompactTarget::from_consensus(bits);
+            block
+        };
+        // two blocks at the genesis difficulty lose to one at 256 times the work
+        let losing_block1 = child_block(&genesis, 0x1d00ffff);
+        let losing_block2 = child_block(&losing_block1, 0x1d00ffff);
+        let winning_block = child_block(&genesis, 0x1c00ffff);
+        let mut file_bytes = Vec::new();
+        for block in [&genesis, &losing_block1, &losing_block2, &winning_block] {
+            file_bytes.extend(block_frame(Network::Bitcoin, block));
+        }
+
+        let mut importer = Importer::new("", 0);
+        importer.read_blocks(file_bytes).unwrap();
+        importer.drain_ready(0).unwrap();
+        assert_eq!(importer.tip(), Some((winning_block.block_hash(), 1)));
+        assert_eq!(
+            importer.total_chainwork(),
+            genesis.header.work() + winning_block.header.work()
+        );
+
+        // the chainwork survives a checkpoint
+        let path = std::env::temp_dir().join(format!("read-blk-chainwork-{}", std::process::id()));
+        importer.save_checkpoint(&path).unwrap();
+        let mut resumed = Importer::new("", 0);
+        resumed.load_checkpoint(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(resumed.total_chainwork(), importer.total_chainwork());
+    }
+
+    #[test]
+    fn test_sink() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut next_block = genesis.clone();
+        next_block.header.prev_blockhash = genesis.block_hash();
+        let mut file_bytes = block_frame(Network::Bitcoin, &genesis);
+        file_bytes.extend(block_frame(Network::Bitcoin, &next_block));
+
+        let (sender, receiver) = mpsc::channel();
+        let mut importer = Importer::new_with_sink("", 0, sender);
+        importer.read_blocks(file_bytes).unwrap();
+        while importer.block_cache().staged_cnt() > 0 {
+            importer.import_block_if_ready(0).unwrap();
+        }
+        drop(importer);
+        let imported: Vec<_> = receiver
+            .iter()
+            .map(|imported_block| (imported_block.block.block_hash(), imported_block.height))
+            .collect();
+        assert_eq!(
+            imported,
+            vec![(genesis.block_hash(), 0), (next_block.block_hash(), 1)]
+        );
+    }
+
+    #[test]
+    fn test_block_sink() {
+        // records the hashes of the imported and purged blocks
+        struct RecordingSink(Rc<RefCell<(Vec<BlockHash>, Vec<BlockHash>)>>);
+        impl BlockSink for RecordingSink {
+            fn on_block(&mut self, block: &Block, _meta: &BlockMeta) {
+                self.0.borrow_mut().0.push(block.block_hash());
+            }
+            fn on_reorg(&mut self, reorg: &ReorgEvent) {
+                self.0
+                    .borrow_mut()
+                    .1
+                    .extend_from_slice(&reorg.abandoned_blocks);
+            }
+        }
+
+        let genesis = genesis_block(Network::Bitcoin);
+        let child_block = |parent: &Block, nonce: u32| {
+            let mut block = genesis.clone();
+            block.header.prev_blockhash = parent.block_hash();
+            block.header.nonce = nonce;
+            block
+        };
+        // losing_block is a one block fork off genesis
+        let losing_block = child_block(&genesis, 1);
+        let winning_block = child_block(&genesis, 2);
+        let next_block = child_block(&winning_block, 3);
+        let mut file_bytes = Vec::new();
+        for block in [&genesis, &losing_block, &winning_block, &next_block] {
+            file_bytes.extend(block_frame(Network::Bitcoin, block));
+        }
+
+        let recorded = Rc::new(RefCell::new((Vec::new(), Vec::new())));
+        let mut importer = Importer::new("", 0);
+        importer.set_sink(RecordingSink(recorded.clone()));
+        importer.read_blocks(file_bytes).unwrap();
+        while importer.block_cache().staged_cnt() > 0 {
+            importer.import_block_if_ready(0).unwrap();
+        }
+        let (imported, purged) = &*recorded.borrow();
+        assert_eq!(
+            imported,
+            &vec![
+                genesis.block_hash(),
+                winning_block.block_hash(),
+                next_block.block_hash()
+            ]
+        );
+        assert_eq!(purged, &vec![losing_block.block_hash()]);
+    }
+
+    #[test]
+    fn test_summaries() {
+        struct SummarySink(Rc<RefCell<Vec<Option<BlockSummary>>>>);
+        impl BlockSink for SummarySink {
+            fn on_block(&mut self, _block: &Block, meta: &BlockMeta) {
+                self.0.borrow_mut().push(meta.summary.clone());
+            }
+        }
+
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut next_block = genesis.clone();
+        next_block.header.prev_blockhash = genesis.block_hash();
+        let mut file_bytes = block_frame(Network::Bitcoin, &genesis);
+        file_bytes.extend(block_frame(Network::Bitcoin, &next_block));
+
+        let import_summaries = |utxo_tracker: Option<UtxoTracker>| {
+            let recorded = Rc::new(RefCell::new(Vec::new()));
+            let mut importer = Importer::new("", 0);
+            importer.set_sink(SummarySink(recorded.clone()));
+            importer.set_summaries(true);
+            if let Some(utxo_tracker) = utxo_tracker {
+                importer.set_utxo_tracker(utxo_tracker);
+            }
+            importer.read_blocks(file_bytes.clone()).unwrap();
+            while importer.block_cache().staged_cnt() > 0 {
+                importer.import_block_if_ready(0).unwrap();
+            }
+            recorded.take()
+        };
+        let summaries = import_summaries(None);
+        assert_eq!(summaries.len(), 2);
+        let summary = summaries[1].as_ref().unwrap();
+        assert_eq!(summary.tx_count, 1);
+        assert_eq!(summary.fee, None);
+        // a coinbase only block pays no fees
+        let summaries = import_summaries(Some(UtxoTracker::new()));
+        assert_eq!(summaries[1].as_ref().unwrap().fee, Some(Amount::ZERO));
+    }
+
+    #[test]
+    fn test_read_errors() {
+        let frame = block_frame(Network::Bitcoin, &genesis_block(Network::Bitcoin));
+
+        // the network can't be detected from an unknown magic
+        let mut unknown_magic = frame.clone();
+        unknown_magic[0..4].copy_from_slice(&[1, 2, 3, 4]);
+        let mut importer = Importer::new("", 0);
+        let err = importer.read_blocks(unknown_magic).unwrap_err();
+        assert!(matches!(err, Error::BadMagic { offset: 0, .. }), "{}", err);
+
+        // a truncated frame header is the end of data, like a truncated frame
+        let mut importer = Importer::new("", 0);
+        let read_count = importer.read_blocks(frame[..4].to_vec()).unwrap();
+        assert_eq!(read_count, ReadCount::default());
+        let mut file_bytes = frame.clone();
+        file_bytes.extend_from_slice(&frame[..7]);
+        let read_count = importer.read_blocks(file_bytes).unwrap();
+        assert_eq!(read_count.blocks, 1);
+        assert_eq!(read_count.consumed, frame.len());
+
+        let mut corrupt = frame.clone();
+        corrupt[8 + 80] = 0xff; // tx count varint
+        let mut importer = Importer::new("", 0);
+        let err = importer.read_blocks(corrupt).unwrap_err();
+        assert!(matches!(err, Error::Decode(_)), "{}", err);
+    }
+
+    #[test]
+    fn test_skip_corrupt() {
+        let mut corrupt = block_frame(Network::Bitcoin, &genesis_block(Network::Bitcoin));
+        corrupt[8 + 80] = 0xff; // tx count varint
+        let mut file_bytes = corrupt.clone();
+        file_bytes.extend(block_frame(
+            Network::Bitcoin,
+            &genesis_block(Network::Testnet),
+        ));
+
+        let mut importer = Importer::new("", 0);
+        importer.set_skip_corrupt(true);
+        let read_count = importer.read_blocks(file_bytes.clone()).unwrap();
+        assert_eq!(read_count.blocks, 1);
+        assert_eq!(read_count.consumed, file_bytes.len());
+        assert_eq!(importer.corrupt_count(), 1);
+        assert_eq!(importer.block_cache.pending_cnt(), 1);
+    }
+
+    #[test]
+    fn test_lazy_decode() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut next_block = genesis.clone();
+        next_block.header.prev_blockhash = genesis.block_hash();
+        let mut file_bytes = block_frame(Network::Bitcoin, &genesis);
+        file_bytes.extend(block_frame(Network::Bitcoin, &next_block));
+
+        let (sender, receiver) = mpsc::channel();
+        let mut importer = Importer::new_with_sink("", 0, sender);
+        importer.set_lazy_decode(true);
+        assert_eq!(importer.read_blocks(file_bytes).unwrap().blocks, 2);
+        assert!(matches!(
+            importer.block_cache.get_block(&genesis.block_hash()),
+            Some(PendingBlock::Raw { .. })
+        ));
+        importer.import_block_if_ready(0).unwrap();
+        importer.import_block_if_ready(0).unwrap();
+        drop(importer);
+        let imported: Vec<_> = receiver.iter().map(|imported| imported.block).collect();
+        assert_eq!(imported, vec![genesis, next_block]);
+    }
+
+    #[test]
+    fn test_lazy_decode_error() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut broken_block = genesis.clone();
+        broken_block.header.prev_blockhash = genesis.block_hash();
+        let mut last_block = genesis.clone();
+        last_block.header.prev_blockhash = broken_block.block_hash();
+        let mut file_bytes = block_frame(Network::Bitcoin, &genesis);
+        let broken_offset = file_bytes.len();
+        // the header decodes, the transactions are cut short
+        let mut broken_bytes = serialize(&broken_block);
+        broken_bytes.truncate(broken_bytes.len() - 10);
+        file_bytes.extend(Network::Bitcoin.magic().to_bytes());
+        file_bytes.extend((broken_bytes.len() as u32).to_le_bytes());
+        file_bytes.extend(broken_bytes);
+        file_bytes.extend(block_frame(Network::Bitcoin, &last_block));
+
+        let mut importer = Importer::new("", 0);
+        importer.set_lazy_decode(true);
+        importer.read_blocks_slice(&file_bytes).unwrap();
+        importer.import_block_if_ready(0).unwrap();
+        match importer.import_block_if_ready(0) {
+            Err(Error::PendingDecode {
+                block_hash,
+                source_loc: Some(source_loc),
+                ..
+            }) => {
+                assert_eq!(block_hash, broken_block.block_hash());
+                assert_eq!(source_loc.offset, broken_offset);
+            }
+            result => panic!("decode error expected, got {:?}", result),
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        let mut importer = Importer::new_with_sink("", 0, sender);
+        importer.set_lazy_decode(true);
+        importer.set_skip_corrupt(true);
+        importer.read_blocks_slice(&file_bytes).unwrap();
+        importer.drain_ready(0).unwrap();
+        assert_eq!(importer.corrupt_count(), 1);
+        assert_eq!(importer.tip(), Some((last_block.block_hash(), 2)));
+        drop(importer);
+        let imported: Vec<_> = receiver.iter().map(|imported| imported.block).collect();
+        assert_eq!(imported, vec![genesis, last_block]);
+    }
+
+    #[test]
+    fn test_validate() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut bad_merkle_root = genesis.clone();
+        bad_merkle_root.header.merkle_root = TxMerkleNode::all_zeros();
+        let mut next_block = genesis.clone();
+        next_block.header.prev_blockhash = genesis.block_hash();
+        let mut file_bytes = block_frame(Network::Bitcoin, &bad_merkle_root);
+        file_bytes.extend(block_frame(Network::Bitcoin, &next_block));
+
+        let mut importer = Importer::new("", 0);
+        importer.read_blocks(file_bytes.clone()).unwrap();
+        assert_eq!(importer.block_cache.pending_cnt(), 2);
+
+        let mut importer = Importer::new("", 0);
+        importer.set_validate(true);
+        let read_count = importer.read_blocks(file_bytes).unwrap();
+        assert_eq!(read_count.blocks, 2);
+        assert_eq!(importer.invalid_count(), 1);
+        assert!(!importer
+            .block_cache
+            .contains_block(&bad_merkle_root.block_hash()));
+        assert!(importer
+            .block_cache
+            .contains_block(&next_block.block_hash()));
+    }
+
+    #[test]
+    fn test_validate_pow() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut bad_nonce = genesis.clone();
+        bad_nonce.header.nonce += 1;
+        let mut file_bytes = block_frame(Network::Bitcoin, &genesis);
+        file_bytes.extend(block_frame(Network::Bitcoin, &bad_nonce));
+
+        let mut importer = Importer::new("", 0);
+        importer.set_validate_pow(true);
+        importer.read_blocks(file_bytes).unwrap();
+        assert_eq!(importer.invalid_count(), 1);
+        assert!(importer.block_cache.contains_block(&genesis.block_hash()));
+        assert!(!importer.block_cache.contains_block(&bad_nonce.block_hash()));
+    }
+
+    #[test]
+    fn test_linkage() {
+        let genesis = genesis_block(Network::Bitcoin);
+        // the parent of unlinked is never read
+        let mut unlinked = genesis.clone();
+        unlinked.header.prev_blockhash = BlockHash::from_byte_array([1; 32]);
+        let import_unlinked = |importer: &mut Importer| {
+            importer
+                .read_blocks(block_frame(Network::Bitcoin, &genesis))
+                .unwrap();
+            importer.import_block_if_ready(0).unwrap();
+            importer
+                .read_blocks(block_frame(Network::Bitcoin, &unlinked))
+                .unwrap();
+            importer.import_block_if_ready(0)
+        };
+
+        let mut importer = Importer::new("", 0);
+        match import_unlinked(&mut importer) {
+            Err(Error::Linkage(err)) => assert_eq!(
+                err,
+                LinkageError {
+                    block_hash: unlinked.block_hash(),
+                    prev_hash: unlinked.header.prev_blockhash,
+                    expected_prev_hash: genesis.block_hash(),
+                }
+            ),
+            result => panic!("linkage error expected, got {:?}", result),
+        }
+        assert_eq!(importer.tip(), Some((genesis.block_hash(), 0)));
+
+        let mut importer = Importer::new("", 0);
+        importer.set_strict_linkage(false);
+        import_unlinked(&mut importer).unwrap();
+        // the height is counted from the previous imported block
+        assert_eq!(importer.tip(), Some((unlinked.block_hash(), 1)));
+    }
+
+    #[test]
+    fn test_resync_after_garbage() {
+        let mut file_bytes = block_frame(Network::Bitcoin, &genesis_block(Network::Bitcoin));
+        file_bytes.extend_from_slice(b"garbage between frames");
+        file_bytes.extend(block_frame(
+            Network::Bitcoin,
+            &genesis_block(Network::Testnet),
+        ));
+
+        let mut importer = Importer::new("", 0);
+        let read_count = importer.read_blocks(file_bytes.clone()).unwrap();
+        assert_eq!(read_count.blocks, 2);
+        assert_eq!(read_count.consumed, file_bytes.len());
+        assert_eq!(importer.block_cache.pending_cnt(), 2);
+    }
+
+    #[test]
+    fn test_corrupt_frame_len() {
+        let frame = block_frame(Network::Bitcoin, &genesis_block(Network::Bitcoin));
+        let mut file_bytes = frame.clone();
+        let mut corrupt_frame = block_frame(Network::Bitcoin, &genesis_block(Network::Testnet));
+        corrupt_frame[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        file_bytes.extend(corrupt_frame);
+        let mut block = genesis_block(Network::Bitcoin);
+        block.header.prev_blockhash = block.block_hash();
+        file_bytes.extend(block_frame(Network::Bitcoin, &block));
+
+        let mut importer = Importer::new("", 0);
+        let read_count = importer.read_blocks(file_bytes.clone()).unwrap();
+        assert_eq!(read_count.blocks, 2);
+        assert_eq!(read_count.consumed, file_bytes.len());
+        assert!(importer.block_cache.contains_block(&block.block_hash()));
+
+        // the streaming reader stops at the corrupt frame
+        let mut importer = Importer::new("", 0);
+        assert!(matches!(
+            importer.read_blocks_streaming(file_bytes.as_slice()),
+            Err(Error::OversizedFrame { offset, len: 0xffffffff }) if offset == frame.len()
+        ));
+    }
+
+    #[test]
+    fn test_truncated_final_block() {
+        let frame = block_frame(Network::Bitcoin, &genesis_block(Network::Bitcoin));
+        let mut file_bytes = frame.clone();
+        file_bytes.extend_from_slice(&frame[..100]);
+
+        let expected = ReadCount {
+            blocks: 1,
+            consumed: frame.len(),
+        };
+        let mut importer = Importer::new("", 0);
+        assert_eq!(importer.read_blocks(file_bytes.clone()).unwrap(), expected);
+        let mut importer = Importer::new("", 0);
+        assert_eq!(
+            importer
+                .read_blocks_streaming(file_bytes.as_slice())
+                .unwrap(),
+            expected
+        );
+    }
+
+    // frames of a chain of block_cnt blocks after genesis, with tx_cnt copies of the genesis coinbase each
+    #[cfg(feature = "rayon")]
+    fn chain_file(block_cnt: usize, tx_cnt: usize) -> Vec<u8> {
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut file_bytes = block_frame(Network::Bitcoin, &genesis);
+        let mut prev_hash = genesis.block_hash();
+        for _ in 0..block_cnt {
+            let mut block = genesis.clone();
+            block.header.prev_blockhash = prev_hash;
+            block.txdata = vec![genesis.txdata[0].clone(); tx_cnt];
+            prev_hash = block.block_hash();
+            file_bytes.extend(block_frame(Network::Bitcoin, &block));
+        }
+        file_bytes.extend_from_slice(&[0; 4096]);
+        file_bytes
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_read_blocks_parallel() {
+        let file_bytes = chain_file(20, 3);
+        let (serial_sender, serial_receiver) = mpsc::channel();
+        let mut serial_importer = Importer::new_with_sink("", 0, serial_sender);
+        serial_importer.set_reorg_depth(5);
+        let serial_count = serial_importer.read_blocks_slice(&file_bytes).unwrap();
+
+        let (parallel_sender, parallel_receiver) = mpsc::channel();
+        let mut parallel_importer = Importer::new_with_sink("", 0, parallel_sender);
+        parallel_importer.set_reorg_depth(5);
+        let parallel_count = parallel_importer.read_blocks_parallel(&file_bytes).unwrap();
+
+        assert_eq!(parallel_count, serial_count);
+        assert_eq!(parallel_count.blocks, 21);
+        let serial_hashes: Vec<_> = serial_receiver
+            .try_iter()
+            .map(|imported| imported.block.block_hash())
+            .collect();
+        let parallel_hashes: Vec<_> = parallel_receiver
+            .try_iter()
+            .map(|imported| imported.block.block_hash())
+            .collect();
+        assert_eq!(parallel_hashes.len(), 17);
+        assert_eq!(parallel_hashes, serial_hashes);
+        assert_eq!(parallel_importer.tip(), serial_importer.tip());
+
+        // stopping at max height, only the frames added to the cache are consumed
+        let mut serial_importer = Importer::new("", 0);
+        serial_importer.set_reorg_depth(5);
+        serial_importer.set_max_height(10);
+        let serial_count = serial_importer.read_blocks_slice(&file_bytes).unwrap();
+        let mut parallel_importer = Importer::new("", 0);
+        parallel_importer.set_reorg_depth(5);
+        parallel_importer.set_max_height(10);
+        let parallel_count = parallel_importer.read_blocks_parallel(&file_bytes).unwrap();
+        assert_eq!(parallel_count, serial_count);
+        assert!(parallel_count.consumed < file_bytes.len());
+        assert_eq!(parallel_importer.tip(), serial_importer.tip());
+    }
+
+    // cargo test --release --features rayon bench_read_blocks -- --ignored --nocapture
+    #[cfg(feature = "rayon")]
+    #[test]
+    #[ignore]
+    fn bench_read_blocks() {
+        let file_bytes = chain_file(500, 2000);
+        let start = Instant::now();
+        Importer::new("", 0).read_blocks_slice(&file_bytes).unwrap();
+        let serial_time = start.elapsed();
+        let start = Instant::now();
+        Importer::new("", 0)
+            .read_blocks_parallel(&file_bytes)
+            .unwrap();
+        let parallel_time = start.elapsed();
+        println!(
+            "{} bytes: serial {:?}, parallel {:?}",
+            file_bytes.len(),
+            serial_time,
+            parallel_time
+        );
+    }
+
+    #[test]
+    fn test_builder() {
+        let importer = Importer::builder().build();
+        assert_eq!(importer.network(), Some(Network::Bitcoin));
+        assert_eq!(importer.file_num(), 0);
+        assert_eq!(importer.block_cache().threshold(), DEFAULT_DEPTH_THRESHOLD);
+        assert!(!importer.validate && !importer.validate_pow);
+
+        let (sender, receiver) = mpsc::channel();
+        let mut importer = Importer::builder()
+            .dir("/tmp/blocks")
+            .network(Network::Regtest)
+            .start_file(3)
+            .reorg_depth(0)
+            .validate_pow(true)
+            .sink(ChannelSink::new(sender))
+            .build();
+        assert_eq!(importer.dir_path, PathBuf::from("/tmp/blocks"));
+        assert_eq!(importer.network(), Some(Network::Regtest));
+        assert_eq!(importer.file_num(), 3);
+        assert!(importer.validate_pow);
+
+        let genesis = genesis_block(Network::Regtest);
+        importer
+            .read_blocks(block_frame(Network::Regtest, &genesis))
+            .unwrap();
+        importer.import_block_if_ready(0).unwrap();
+        assert_eq!(receiver.try_recv().unwrap().block, genesis);
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let path = temp_dir("missing-file").join("blk00000.dat");
+        let err = Importer::new("", 0).read_file(&path).unwrap_err();
+        assert!(
+            matches!(&err, Error::File { path: err_path, .. } if *err_path == path),
+            "{}",
+            err
+        );
+        assert!(err.to_string().starts_with(&path.display().to_string()));
+    }
+
+    #[test]
+    fn test_index() {
+        let dir = temp_dir("index");
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut next_block = genesis.clone();
+        next_block.header.prev_blockhash = genesis.block_hash();
+        std::fs::write(
+            dir.join("blk00000.dat"),
+            block_frame(Network::Bitcoin, &genesis),
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("blk00001.dat"),
+            [vec![0xff; 3], block_frame(Network::Bitcoin, &next_block)].concat(),
+        )
+        .unwrap();
+
+        let index = crate::index::build_index(&dir).unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(
+            index.get(&next_block.block_hash()),
+            Some(IndexEntry {
+                file_num: 1,
+                offset: 3,
+                len: 285
+            })
+        );
+
+        let mut importer = Importer::new(&dir, 0);
+        importer.set_build_index(true);
+        importer.read_files().unwrap();
+        assert_eq!(importer.index(), Some(&index));
+        assert_eq!(
+            importer.open_block(&next_block.block_hash()).unwrap(),
+            Some(next_block)
+        );
+        assert_eq!(
+            importer
+                .open_block(&BlockHash::from_byte_array([1; 32]))
+                .unwrap(),
+            None
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // a frame past 4 GiB, in a sparse file, e.g. one that a BlkFileSink without a max file size wrote
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_open_block_past_4gib() {
+        use std::io::{Seek, SeekFrom};
+
+        let dir = temp_dir("open-block-4gib");
+        let genesis = genesis_block(Network::Bitcoin);
+        let offset = 5_000_000_000;
+        let mut file = File::create(dir.join("blk00000.dat")).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&block_frame(Network::Bitcoin, &genesis))
+            .unwrap();
+        drop(file);
+
+        let mut index = Index::new();
+        index.insert(
+            genesis.block_hash(),
+            IndexEntry {
+                file_num: 0,
+                offset,
+                len: 285,
+            },
+        );
+        let mut importer = Importer::new(&dir, 0);
+        importer.set_index(index);
+        assert_eq!(
+            importer.open_block(&genesis.block_hash()).unwrap(),
+            Some(genesis)
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_follow() {
+        let dir = temp_dir("follow");
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut blocks = vec![genesis.clone()];
+        for _ in 0..3 {
+            let mut block = genesis.clone();
+            block.header.prev_blockhash = blocks.last().unwrap().block_hash();
+            blocks.push(block);
+        }
+        let frames: Vec<_> = blocks
+            .iter()
+            .map(|block| block_frame(Network::Bitcoin, block))
+            .collect();
+        let file_path = dir.join("blk00000.dat");
+        std::fs::write(&file_path, &frames[0]).unwrap();
+        let append = |bytes: &[u8]| {
+            let mut file = std::fs::OpenOptions::new()
+                .append(true)
+                .open(&file_path)
+                .unwrap();
+            file.write_all(bytes).unwrap();
+        };
+
+        let mut importer = Importer::new(&dir, 0);
+        let mut poll_cnt = 0;
+        let block_cnt = importer
+            .follow(Duration::ZERO, || {
+                poll_cnt += 1;
+                match poll_cnt {
+                    // the node is writing the third block
+                    1 => append(&[frames[1].as_slice(), &frames[2][..100]].concat()),
+                    2 => append(&frames[2][100..]),
+                    3 => std::fs::write(dir.join("blk00001.dat"), &frames[3]).unwrap(),
+                    _ => return false,
+                }
+                true
+            })
+            .unwrap();
+        assert_eq!(block_cnt, 4);
+        assert_eq!(importer.block_cache.pending_cnt(), 4);
+        assert_eq!(importer.block_cache.tree_depth(), 4);
+        assert_eq!(importer.file_offsets[&0], 3 * frames[0].len());
+        assert_eq!(importer.file_offsets[&1], frames[3].len());
+        assert_eq!(importer.file_num(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_follow_events() {
+        let dir = temp_dir("follow-events");
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut next_block = genesis.clone();
+        next_block.header.prev_blockhash = genesis.block_hash();
+
+        let (sender, events) = mpsc::channel();
+        let writer_dir = dir.clone();
+        let writer = thread::spawn(move || {
+            std::fs::write(
+                writer_dir.join("blk00000.dat"),
+                block_frame(Network::Bitcoin, &genesis),
+            )
+            .unwrap();
+            sender.send(()).unwrap();
+            std::fs::write(
+                writer_dir.join("blk00001.dat"),
+                block_frame(Network::Bitcoin, &next_block),
+            )
+            .unwrap();
+            // coalesced into a single read
+            sender.send(()).unwrap();
+            sender.send(()).unwrap();
+        });
+        writer.join().unwrap();
+
+        let mut importer = Importer::new(&dir, 0);
+        assert_eq!(importer.follow_events(&events).unwrap(), 2);
+        assert_eq!(importer.block_cache.tree_depth(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_height_range() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut file_bytes = block_frame(Network::Bitcoin, &genesis);
+        let mut prev_hash = genesis.block_hash();
+        for _ in 0..5 {
+            let mut block = genesis.clone();
+            block.header.prev_blockhash = prev_hash;
+            prev_hash = block.block_hash();
+            file_bytes.extend(block_frame(Network::Bitcoin, &block));
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        let mut importer = Importer::new_with_sink("", 0, sender);
+        importer.set_reorg_depth(1);
+        importer.set_min_height(1);
+        importer.set_max_height(2);
+        let read_count = importer.read_blocks(file_bytes).unwrap();
+        // every block migrates right after it is read, reading stopped at the block at height 2
+        assert_eq!(read_count.blocks, 3);
+        importer.drain_ready(0).unwrap();
+        let heights: Vec<_> = receiver
+            .try_iter()
+            .map(|imported| imported.height)
+            .collect();
+        assert_eq!(heights, vec![1, 2]);
+        assert_eq!(importer.tip().unwrap().1, 2);
+    }
+
+    #[test]
+    fn test_time_range() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut file_bytes = block_frame(Network::Bitcoin, &genesis);
+        let mut prev_hash = genesis.block_hash();
+        // the block at height 2 is timestamped before its parent
+        for time_offset in [600, 300, 1200, 1800] {
+            let mut block = genesis.clone();
+            block.header.prev_blockhash = prev_hash;
+            block.header.time += time_offset;
+            prev_hash = block.block_hash();
+            file_bytes.extend(block_frame(Network::Bitcoin, &block));
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        let mut importer = Importer::new_with_sink("", 0, sender);
+        importer.set_time_range(
+            Some(genesis.header.time + 1),
+            Some(genesis.header.time + 1200),
+        );
+        importer.read_blocks(file_bytes).unwrap();
+        importer.drain_ready(0).unwrap();
+        let heights: Vec<_> = receiver
+            .try_iter()
+            .map(|imported| imported.height)
+            .collect();
+        assert_eq!(heights, vec![1, 2]);
+        assert_eq!(importer.tip().unwrap().1, 4);
+    }
+
+    #[test]
+    fn test_headers_only() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut next_block = genesis.clone();
+        next_block.header.prev_blockhash = genesis.block_hash();
+        let mut file_bytes = block_frame(Network::Bitcoin, &genesis);
+        file_bytes.extend(block_frame(Network::Bitcoin, &next_block));
+
+        let (sender, receiver) = mpsc::channel();
+        let mut importer = Importer::builder()
+            .headers_only(true)
+            .validate(true)
+            .sink(ChannelSink::new(sender))
+            .build();
+        assert_eq!(importer.read_blocks(file_bytes).unwrap().blocks, 2);
+        importer.drain_ready(0).unwrap();
+        drop(importer);
+        let imported: Vec<_> = receiver.iter().collect();
+        assert_eq!(imported.len(), 2);
+        assert_eq!(imported[0].block.header, genesis.header);
+        assert_eq!(imported[1].block.header, next_block.header);
+        assert!(imported
+            .iter()
+            .all(|imported| imported.block.txdata.is_empty()));
+        assert_eq!(imported[1].height, 1);
+    }
+
+    // Mutated copies of a short blk file, read with every framing the importer has, may fail to read
+    // but must not panic or allocate a corrupt frame length. A fixed seed regression check of what
+    // fuzz/fuzz_targets/read_frames.rs explores with cargo fuzz
+    #[test]
+    fn test_read_blocks_fuzz() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut seed_file = block_frame(Network::Bitcoin, &genesis);
+        let mut block = genesis.clone();
+        for _ in 0..3 {
+            block.header.prev_blockhash = block.block_hash();
+            seed_file.extend(block_frame(Network::Bitcoin, &block));
+        }
+        seed_file.extend_from_slice(&[0; 16]);
+
+        // xorshift, so that a failing iteration can be replayed
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut random = |bound: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % bound as u64) as usize
+        };
+        for _ in 0..1000 {
+            let mut file_bytes = seed_file.clone();
+            for _ in 0..1 + random(4) {
+                let pos = random(file_bytes.len());
+                match random(5) {
+                    0 => file_bytes[pos] ^= 1 << random(8),
+                    1 => {
+                        // a random length or tx count, the file is at least 8 bytes long
+                        let pos = pos.min(file_bytes.len() - 4);
+                        let value = random(1 << 32) as u32;
+                        file_bytes[pos..pos + 4].copy_from_slice(&value.to_le_bytes());
+                    }
+                    2 => file_bytes.truncate(pos),
+                    3 => {
+                        let magic = Network::Bitcoin.magic().to_bytes();
+                        file_bytes.splice(pos..pos, magic);
+                    }
+                    _ => {
+                        // the length of the first frame
+                        file_bytes[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+                    }
+                }
+                if file_bytes.len() < 8 {
+                    break;
+                }
+            }
+            for (skip_corrupt, lazy_decode) in [(false, false), (true, false), (true, true)] {
+                let mut importer = Importer::new("", 0);
+                importer.set_reorg_depth(1);
+                importer.set_skip_corrupt(skip_corrupt);
+                importer.set_lazy_decode(lazy_decode);
+                importer.set_summaries(true);
+                let _ = importer.read_blocks_slice(&file_bytes);
+                let _ = importer.drain_ready(0);
+
+                let mut importer = Importer::new("", 0);
+                importer.set_network(Network::Bitcoin);
+                importer.set_skip_corrupt(skip_corrupt);
+                importer.set_lazy_decode(lazy_decode);
+                importer.set_summaries(true);
+                let _ = importer.read_blocks_slice(&file_bytes);
+                let _ = importer.read_blocks_streaming(file_bytes.as_slice());
+                let _ = importer.drain_ready(0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_duplicate_file_blocks() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut block1 = genesis.clone();
+        block1.header.prev_blockhash = genesis.block_hash();
+        let mut block2 = genesis.clone();
+        block2.header.prev_blockhash = block1.block_hash();
+
+        let mut importer = Importer::new("", 0);
+        let mut file_bytes = block_frame(Network::Bitcoin, &genesis);
+        file_bytes.extend(block_frame(Network::Bitcoin, &block1));
+        importer.read_blocks(file_bytes).unwrap();
+        // block1 again in the next file
+        importer.set_start_file(1);
+        let mut file_bytes = block_frame(Network::Bitcoin, &block1);
+        file_bytes.extend(block_frame(Network::Bitcoin, &block2));
+        importer.read_blocks(file_bytes).unwrap();
+        assert_eq!(importer.duplicate_file_blocks(), 1);
+        assert_eq!(importer.block_cache().pending_cnt(), 3);
+
+        // once genesis is imported only the index knows it was read
+        importer.import_block_if_ready(0).unwrap();
+        let genesis_frame = block_frame(Network::Bitcoin, &genesis);
+        importer.read_blocks(genesis_frame.clone()).unwrap();
+        assert_eq!(importer.duplicate_file_blocks(), 1);
+        let mut importer = Importer::new("", 0);
+        importer.set_build_index(true);
+        importer.read_blocks(genesis_frame.clone()).unwrap();
+        importer.import_block_if_ready(0).unwrap();
+        importer.read_blocks(genesis_frame).unwrap();
+        assert_eq!(importer.duplicate_file_blocks(), 1);
+    }
+
+    // the first file of a pruned node starts mid-chain, with a stale block whose parent is unknown
+    #[test]
+    fn test_pruned_start() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let base_hash = genesis_block(Network::Testnet).block_hash();
+        let child_block = |prev_hash: BlockHash, nonce: u32| {
+            let mut block = genesis.clone();
+            block.header.prev_blockhash = prev_hash;
+            block.header.nonce = nonce;
+            block
+        };
+        let stale_block1 = child_block(genesis.block_hash(), 1);
+        let stale_block2 = child_block(stale_block1.block_hash(), 2);
+        let first_block = child_block(base_hash, 3);
+        let mut file_bytes = Vec::new();
+        for block in [&stale_block1, &stale_block2, &first_block] {
+            file_bytes.extend(block_frame(Network::Bitcoin, block));
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        let mut importer = Importer::new_with_sink("", 0, sender);
+        importer.set_base(base_hash, 5000);
+        assert_eq!(importer.tip(), Some((base_hash, 5000)));
+        importer.read_blocks(file_bytes.clone()).unwrap();
+        importer.import_block_if_ready(0).unwrap();
+        let imported: Vec<_> = receiver
+            .try_iter()
+            .map(|imported| (imported.block.block_hash(), imported.height))
+            .collect();
+        assert_eq!(imported, vec![(first_block.block_hash(), 5001)]);
+        assert_eq!(importer.block_cache().out_of_order_cnt(), 2);
+
+        // unanchored, the stale chain has more work and doesn't link to the base
+        let mut importer = Importer::new("", 0);
+        importer.read_blocks(file_bytes).unwrap();
+        importer.import_block_if_ready(0).unwrap();
+        assert_eq!(importer.tip(), Some((stale_block1.block_hash(), 0)));
+    }
+
+    // the entries as regular files, the way tar writes them
+    #[cfg(feature = "tar")]
+    fn tar_archive(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, path, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[cfg(feature = "tar")]
+    #[test]
+    fn test_read_tar() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut next_block = genesis.clone();
+        next_block.header.prev_blockhash = genesis.block_hash();
+        let first_file = block_frame(Network::Bitcoin, &genesis);
+        let mut second_file = block_frame(Network::Bitcoin, &next_block);
+        second_file.resize(second_file.len() + 100, 0);
+        // longer than a header's name field, written as a GNU long name entry
+        let long_path = format!("{}/blocks/blk00000.dat", "backup".repeat(20));
+        let archive = tar_archive(&[
+            (&long_path, &first_file),
+            ("blocks/rev00000.dat", &[1; 100]),
+            ("blocks/blk00001.dat", &second_file),
+            ("blocks/index/000001.ldb", &first_file),
+        ]);
+
+        let mut importer = Importer::new("", 0);
+        assert_eq!(importer.read_tar(&archive[..]).unwrap(), 2);
+        importer.drain_ready(0).unwrap();
+        assert_eq!(importer.tip(), Some((next_block.block_hash(), 1)));
+        assert_eq!(importer.file_num(), 1);
+
+        let mut importer = Importer::new("", 0);
+        importer.set_start_file(1);
+        assert_eq!(importer.read_tar(&archive[..]).unwrap(), 1);
+    }
+
+    #[cfg(feature = "reqwest")]
+    #[test]
+    fn test_read_url_dir() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut next_block = genesis.clone();
+        next_block.header.prev_blockhash = genesis.block_hash();
+        let files = HashMap::from([
+            (
+                "/blocks/blk00000.dat".to_string(),
+                block_frame(Network::Bitcoin, &genesis),
+            ),
+            (
+                "/blocks/blk00001.dat".to_string(),
+                block_frame(Network::Bitcoin, &next_block),
+            ),
+        ]);
+        let base_url = crate::http::tests::serve(files.clone());
+
+        let mut importer = Importer::new("", 0);
+        assert_eq!(
+            importer
+                .read_url_dir(&format!("{}/blocks/", base_url))
+                .unwrap(),
+            2
+        );
+        importer.drain_ready(0).unwrap();
+        assert_eq!(importer.tip(), Some((next_block.block_hash(), 1)));
+        assert_eq!(importer.file_num(), 2);
+
+        let (base_url, client) = crate::http::tests::serve_tls(files);
+        let mut importer = Importer::new("", 0);
+        assert_eq!(
+            importer
+                .read_url_dir_with_client(&format!("{}/blocks/", base_url), client)
+                .unwrap(),
+            2
+        );
+        assert_eq!(importer.file_num(), 2);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let child_block = |parent: &Block, nonce: u32| {
+            let mut block = genesis.clone();
+            block.header.prev_blockhash = parent.block_hash();
+            block.header.nonce = nonce;
+            block
+        };
+        // losing_block is a one block fork off genesis
+        let losing_block = child_block(&genesis, 1);
+        let winning_block = child_block(&genesis, 2);
+        let next_block = child_block(&winning_block, 3);
+        let mut file_bytes = Vec::new();
+        for block in [&genesis, &losing_block, &winning_block, &next_block] {
+            file_bytes.extend(block_frame(Network::Bitcoin, block));
+        }
+
+        let metrics = Arc::new(Metrics::new());
+        let mut importer = Importer::new("", 0);
+        importer.set_metrics(metrics.clone());
+        importer.read_blocks_slice(&file_bytes).unwrap();
+        assert!(metrics.render().contains("\nread_blk_pending_blocks 4\n"));
+        importer.drain_ready(0).unwrap();
+        let text = metrics.render();
+        assert!(text.contains("\nread_blk_blocks_read_total 4\n"));
+        let bytes_read = format!("\nread_blk_bytes_read_total {}\n", file_bytes.len());
+        assert!(text.contains(&bytes_read));
+        assert!(text.contains("\nread_blk_blocks_imported_total 3\n"));
+        assert!(text.contains("\nread_blk_tip_height 2\n"));
+        assert!(text.contains("\nread_blk_pending_blocks 0\n"));
+        assert!(text.contains("\nread_blk_reorgs_total 1\n"));
+        assert!(text.contains("\nread_blk_deepest_reorg_blocks 1\n"));
+    }
+}
@@ -0,0 +1,183 @@
+use crate::error::Result;
+use crate::importer::Importer;
+use bitcoin::hashes::Hash;
+use bitcoin::BlockHash;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/*
+A block index maps every block hash read from the blk files to the frame the block was read from,
+so that a block can be read back without scanning the files again.
+The index file is the 8-byte magic, a u64 entry count, then the entries, all integers little endian:
+    32-byte block hash, u32 file num, u64 frame offset, u32 block length.
+The frame offset is the position of the magic, the block itself starts 8 bytes later. Bitcoin core's blk files
+are at most 128 MiB, but a BlkFileSink file or blk files concatenated into one can be larger than 4 GiB.
+A file of the first format, whose offsets are u32, still loads.
+*/
+
+const INDEX_MAGIC: &[u8; 8] = b"RBLKIDX2";
+const ENTRY_LEN: usize = 48;
+const V1_INDEX_MAGIC: &[u8; 8] = b"RBLKIDX1";
+const V1_ENTRY_LEN: usize = 44;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub file_num: u32,
+    // position of the block's frame within the file
+    pub offset: u64,
+    // length of the serialized block, without the frame header
+    pub len: u32,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Index {
+    entries: HashMap<BlockHash, IndexEntry>,
+}
+
+impl Index {
+    pub fn new() -> Self {
+        Index::default()
+    }
+
+    // a block read twice keeps its first entry
+    pub fn insert(&mut self, hash: BlockHash, entry: IndexEntry) {
+        self.entries.entry(hash).or_insert(entry);
+    }
+
+    pub fn get(&self, hash: &BlockHash) -> Option<IndexEntry> {
+        self.entries.get(hash).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(INDEX_MAGIC)?;
+        writer.write_all(&(self.entries.len() as u64).to_le_bytes())?;
+        for (hash, entry) in self.entries.iter() {
+            writer.write_all(hash.as_byte_array())?;
+            writer.write_all(&entry.file_num.to_le_bytes())?;
+            writer.write_all(&entry.offset.to_le_bytes())?;
+            writer.write_all(&entry.len.to_le_bytes())?;
+        }
+        writer.flush()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        let entry_len = match &magic {
+            INDEX_MAGIC => ENTRY_LEN,
+            V1_INDEX_MAGIC => V1_ENTRY_LEN,
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "not a block index file",
+                ))
+            }
+        };
+        let mut entry_cnt = [0; 8];
+        reader.read_exact(&mut entry_cnt)?;
+        let entry_cnt = u64::from_le_bytes(entry_cnt) as usize;
+        let mut index = Index::new();
+        let mut bytes = [0; ENTRY_LEN];
+        let bytes = &mut bytes[..entry_len];
+        for _ in 0..entry_cnt {
+            reader.read_exact(bytes)?;
+            let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+            let offset = match entry_len {
+                ENTRY_LEN => u64::from_le_bytes(bytes[36..44].try_into().unwrap()),
+                _ => u32_at(36) as u64,
+            };
+            index.insert(
+                BlockHash::from_byte_array(bytes[..32].try_into().unwrap()),
+                IndexEntry {
+                    file_num: u32_at(32),
+                    offset,
+                    len: u32_at(entry_len - 4),
+                },
+            );
+        }
+        Ok(index)
+    }
+}
+
+/// Scans the blk files in dir, decoding only the block headers, see Importer::scan_index()
+pub fn build_index(dir_path: impl AsRef<Path>) -> Result<Index> {
+    Importer::new(dir_path, 0).scan_index()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_load() {
+        let mut index = Index::new();
+        index.insert(
+            BlockHash::from_byte_array([1; 32]),
+            IndexEntry {
+                file_num: 0,
+                offset: 0,
+                len: 285,
+            },
+        );
+        index.insert(
+            BlockHash::from_byte_array([2; 32]),
+            IndexEntry {
+                file_num: 7,
+                // past 4 GiB, e.g. in a BlkFileSink file
+                offset: 5_000_000_000,
+                len: 1_000_000,
+            },
+        );
+        let path = std::env::temp_dir().join(format!("read-blk-index-{}.idx", std::process::id()));
+        index.save(&path).unwrap();
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            16 + 2 * ENTRY_LEN as u64
+        );
+        let loaded = Index::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, index);
+        assert_eq!(
+            loaded
+                .get(&BlockHash::from_byte_array([2; 32]))
+                .unwrap()
+                .file_num,
+            7
+        );
+    }
+
+    #[test]
+    fn test_load_v1() {
+        let mut bytes = V1_INDEX_MAGIC.to_vec();
+        bytes.extend_from_slice(&1u64.to_le_bytes());
+        bytes.extend_from_slice(&[1; 32]);
+        for value in [7u32, 134_000_000, 285] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        let path =
+            std::env::temp_dir().join(format!("read-blk-index-v1-{}.idx", std::process::id()));
+        std::fs::write(&path, bytes).unwrap();
+        let loaded = Index::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            loaded.get(&BlockHash::from_byte_array([1; 32])),
+            Some(IndexEntry {
+                file_num: 7,
+                offset: 134_000_000,
+                len: 285,
+            })
+        );
+    }
+}
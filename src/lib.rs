@@ -0,0 +1,48 @@
+//! Reads the blocks of bitcoin core's blk files and imports them in chain order: an [`Importer`] reads the frames,
+//! stages the blocks in a [`BlockCache`] until they are deep enough to survive a reorg, and passes the ones that
+//! migrate out of it to a [`BlockSink`]. The read-blk binary is a command line front end to the [`Importer`].
+//! The [`prelude`] has the types most uses need. The bitcoin types in the API are those of the re-exported
+//! `read_blk::bitcoin`, a dependent uses it instead of a bitcoin dependency of its own, which could be another
+//! version whose types don't match.
+
+/// The bitcoin crate this crate is built with. Its types, e.g. `Block` and `BlockHash`, are in this crate's API,
+/// so a dependent uses `read_blk::bitcoin` rather than a bitcoin of its own, whose types would be a different
+/// version's and not interchangeable with these.
+pub use bitcoin;
+
+pub mod blk_sink;
+pub mod block_cache;
+pub mod core_index;
+pub mod csv_sink;
+pub mod error;
+#[cfg(feature = "reqwest")]
+pub mod http;
+pub mod importer;
+pub mod index;
+mod logging;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod sink;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_sink;
+#[cfg(feature = "async")]
+pub mod stream;
+pub mod summary;
+pub mod sync_block_cache;
+pub mod utxo;
+pub mod watch;
+
+pub use block_cache::{AddResult, BlockCache, BlockInfo, BlockPayload, RemovalPolicy};
+pub use error::{Error, LinkageError, Result};
+pub use importer::{CompletionReport, Importer};
+pub use sink::{BlockMeta, BlockSink};
+pub use sync_block_cache::SyncBlockCache;
+
+/// `use read_blk::prelude::*` for the importer, the cache, the sink and the bitcoin types they take.
+pub mod prelude {
+    pub use crate::bitcoin::{Block, BlockHash, Network};
+    pub use crate::block_cache::{BlockCache, BlockInfo};
+    pub use crate::error::Error;
+    pub use crate::importer::Importer;
+    pub use crate::sink::{BlockMeta, BlockSink};
+}
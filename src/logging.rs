@@ -0,0 +1,21 @@
+/*
+The log macros the library logs with: those of the log crate with the log feature, without it macros that log
+nothing, so the core builds without the log crate. The disabled macros still type check their format arguments.
+*/
+
+#[cfg(feature = "log")]
+pub(crate) use log::{debug, error, info, trace, warn};
+
+#[cfg(not(feature = "log"))]
+macro_rules! disabled {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
+
+#[cfg(not(feature = "log"))]
+pub(crate) use {
+    disabled as debug, disabled as error, disabled as info, disabled as trace, disabled as warn,
+};
@@ -1,134 +1,366 @@
-mod block_cache;
+use bitcoin::{BlockHash, Network};
+use clap::Parser;
+use log::{error, info, warn};
+use read_blk::{sink, utxo, watch, Importer, RemovalPolicy};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use bitcoin::block::Block;
-use bitcoin::consensus::Decodable;
-use std::fs::File;
-use std::io::Read;
-use std::path::Path;
-
-struct Importer {
-    block_cache: block_cache::BlockCache,
-    prev_block_hash: Option<bitcoin::BlockHash>,
-    prev_block_height: u64,
+/// Imports the blocks of a bitcoin core data directory in chain order.
+/// Set RUST_LOG=debug to log the imported blocks, RUST_LOG=trace to also log every block read.
+/// Exits with 1 if the import stopped on an error, 3 if blocks were left in the cache without --max-height.
+#[derive(Parser)]
+struct Args {
+    /// bitcoin core blocks directory containing blk?????.dat files, or blk?????.dat.zst files with the zstd feature
+    #[arg(long, required_unless_present_any = ["file", "url"])]
+    dir: Option<PathBuf>,
+    /// single blk file to read instead of a blocks directory, zstd compressed if it ends with .zst (zstd feature),
+    /// or a tar archive of blk files read without extracting it if it ends with .tar (tar feature);
+    /// - reads the blocks from stdin
+    #[arg(long, conflicts_with_all = ["dir", "index", "checkpoint", "url"])]
+    file: Option<PathBuf>,
+    /// http:// or https:// URL of a blocks directory to read the blk files from with range requests,
+    /// e.g. https://host/blocks/; needs the http feature
+    #[arg(long, conflicts_with_all = ["dir", "index", "follow"])]
+    url: Option<String>,
+    /// number of the first blk file to read
+    #[arg(long, default_value_t = 0)]
+    start_file: u32,
+    /// how many blocks deep the chain has to be on top of a block before it is imported
+    #[arg(long, default_value_t = 100)]
+    reorg_depth: u32,
+    /// don't pass the blocks below this height to the sink
+    #[arg(long)]
+    min_height: Option<u64>,
+    /// stop once the block at this height is imported
+    #[arg(long)]
+    max_height: Option<u64>,
+    /// don't pass the blocks timestamped before this unix time to the sink
+    #[arg(long)]
+    after_time: Option<u32>,
+    /// don't pass the blocks timestamped at or after this unix time to the sink
+    #[arg(long)]
+    before_time: Option<u32>,
+    /// depth: import a block once any branch on top of it is reorg-depth deep;
+    /// best-work: once the most work chain on top of it is, which waits longer but never imports a fork point
+    /// on the strength of a deep branch with less work
+    #[arg(long, default_value = "depth", value_parser = parse_removal_policy)]
+    removal_policy: RemovalPolicy,
+    /// warn about reorgs whose losing chain ran more than this many blocks past the fork point
+    #[arg(long)]
+    max_reorg_depth: Option<u32>,
+    /// skip blocks whose BIP34 height is more than this many blocks below the oldest block not yet imported
+    #[arg(long)]
+    stale_margin: Option<u64>,
+    /// with this many blocks in the cache, import all the ready blocks after every block read instead of one
+    #[arg(long)]
+    max_pending: Option<usize>,
+    /// height of the block before the first one read, e.g. the last block a pruned node deleted
+    #[arg(long)]
+    base_height: Option<u64>,
+    /// hash of the block at --base-height: the import has to continue from it
+    #[arg(long, requires = "base_height")]
+    base_hash: Option<BlockHash>,
+    /// mainnet, testnet, testnet4, signet or regtest; detected from the blk files if omitted
+    #[arg(long, value_parser = parse_network)]
+    network: Option<Network>,
+    /// bitcoin core blocks/index LevelDB directory; blocks are read in its height order.
+    /// Bitcoin core must not be running. Needs the leveldb feature
+    #[arg(long)]
+    index: Option<PathBuf>,
+    /// log and skip blocks that fail to decode instead of stopping
+    #[arg(long)]
+    skip_corrupt: bool,
+    /// skip blocks whose merkle root doesn't match their transactions
+    #[arg(long)]
+    validate: bool,
+    /// skip blocks whose header hash doesn't meet the target in their bits;
+    /// difficulty adjustments are not checked
+    #[arg(long)]
+    validate_pow: bool,
+    /// keep pending blocks raw and decode them only when they are imported; ignored with --validate
+    #[arg(long)]
+    lazy_decode: bool,
+    /// decode only the block headers, the sink gets the blocks without transactions
+    #[arg(long, conflicts_with_all = ["utxo", "validate"])]
+    headers_only: bool,
+    /// log and import a block that doesn't extend the last imported block, instead of stopping
+    #[arg(long)]
+    lenient_linkage: bool,
+    /// write an index of where every block read is in the blk files to this file
+    #[arg(long)]
+    save_index: Option<PathBuf>,
+    /// after the last blk file, keep reading the blocks a running node appends, until killed
+    #[arg(long, conflicts_with_all = ["file", "index"])]
+    follow: bool,
+    /// progress is saved to this file after every blk file, and resumed from it if it exists
+    #[arg(long)]
+    checkpoint: Option<PathBuf>,
+    /// where imported blocks go: log (at debug level), stdout, csv:/path/out.csv,
+    /// blk:/path/dir to write them back to blk files, de-forked and in chain order, which needs --network,
+    /// or sqlite:///path/chain.db if built with the sqlite feature
+    #[arg(long, default_value = "log")]
+    sink: String,
+    /// pass the OP_RETURN outputs with a payload of at least this many bytes to the sink, with their txid and index
+    #[arg(long, value_name = "MIN_LEN", conflicts_with = "headers_only")]
+    op_returns: Option<usize>,
+    /// serve Prometheus metrics of the import at http://ADDR/metrics, e.g. 127.0.0.1:9332;
+    /// needs the metrics feature
+    #[arg(long, value_name = "ADDR")]
+    metrics: Option<String>,
+    /// build the UTXO set from the imported blocks and print its size at the end
+    #[arg(long)]
+    utxo: bool,
 }
 
-fn main() {
-    let _dir_path = "/home/ghost/dat/bitcoin/blocks/"; //bitcoin core leveldb
-    let dir_path = "/fusionio0/btccore/dat/blocks/";
-    let mut file_num = 0; //1328;
-    let mut importer = Importer::new();
-    loop {
-        let file_name = format!("blk{:05}.dat", file_num);
-        let file_path = Path::new(dir_path).join(&file_name);
-        match File::open(&file_path) {
-            Ok(mut file) => {
-                let mut contents = Vec::new();
-                file.read_to_end(&mut contents).unwrap();
-                println!("File {}: {} bytes", file_name, contents.len());
-                importer.read_blocks(contents);
-            }
-            Err(err) => {
-                // file not found, assume it's the last file
-                println!("err {}", err);
-                break;
-            }
-        }
-        file_num += 1;
-
-        /*if file_num == 2 {
-            break;
-        }*/
-    }
-
-    while importer.block_cache.staged_cnt() > 0 {
-        importer.import_block_if_ready(0);
+fn parse_network(s: &str) -> Result<Network, String> {
+    match s {
+        "mainnet" => Ok(Network::Bitcoin),
+        _ => s.parse().map_err(|err| format!("{}", err)),
     }
+}
 
-    if importer.block_cache.out_of_order_cnt() > 0 {
-        println!(
-            "!!! WARNING: {} out of order blocks remained",
-            importer.block_cache.out_of_order_cnt()
-        );
-        assert_eq!(
-            importer.block_cache.pending_cnt(),
-            importer.block_cache.out_of_order_cnt()
-        );
-    } else {
-        assert_eq!(importer.block_cache.pending_cnt(), 0);
+fn parse_removal_policy(s: &str) -> Result<RemovalPolicy, String> {
+    match s {
+        "depth" => Ok(RemovalPolicy::Depth),
+        "best-work" => Ok(RemovalPolicy::BestWork),
+        _ => Err(format!(
+            "unknown removal policy {}, depth or best-work expected",
+            s
+        )),
     }
 }
 
-impl Importer {
-    fn new() -> Self {
-        Importer {
-            block_cache: block_cache::BlockCache::new(),
-            prev_block_hash: None,
-            prev_block_height: 0,
+#[cfg(feature = "tar")]
+fn read_tar(importer: &mut Importer, file: &Path) -> read_blk::Result<usize> {
+    match std::fs::File::open(file) {
+        Ok(archive) => importer.read_tar(std::io::BufReader::new(archive)),
+        Err(err) => {
+            eprintln!("error: can't open {}: {}", file.display(), err);
+            std::process::exit(2);
         }
     }
+}
 
-    fn read_blocks(&mut self, file_bytes: Vec<u8>) {
-        let mut i = 0;
-        loop {
-            if i >= file_bytes.len() {
-                break;
-            }
+#[cfg(not(feature = "tar"))]
+fn read_tar(_importer: &mut Importer, _file: &Path) -> read_blk::Result<usize> {
+    eprintln!("error: a .tar file needs read-blk built with the tar feature");
+    std::process::exit(2);
+}
 
-            let len = u32::from_le_bytes(file_bytes[i + 4..i + 8].try_into().unwrap()) as usize;
-            //println!("read {} {}", i, len);
-            if len > 0 {
-                let bytes = &file_bytes[i + 8..i + 8 + len];
-                assert_eq!(
-                    &file_bytes[i..i + 4],
-                    &[0xf9, 0xbe, 0xb4, 0xd9],
-                    "{}, {}, {}",
-                    i,
-                    len,
-                    hex::encode(&bytes),
-                );
-                let block = Block::consensus_decode(&mut bytes.to_vec().as_slice()).unwrap();
-                println!(
-                    "...read block {:?} {} header: work {} prev_hash {:?}",
-                    block.block_hash(),
-                    block.bip34_block_height().unwrap_or(0),
-                    block.header.work(),
-                    block.header.prev_blockhash
-                );
-                self.block_cache.add_block(block);
-            }
+#[cfg(feature = "reqwest")]
+fn read_url_dir(importer: &mut Importer, url: &str) -> read_blk::Result<usize> {
+    importer.read_url_dir(url)
+}
 
-            i += 8 + len;
+#[cfg(not(feature = "reqwest"))]
+fn read_url_dir(_importer: &mut Importer, _url: &str) -> read_blk::Result<usize> {
+    eprintln!("error: --url needs read-blk built with the http feature");
+    std::process::exit(2);
+}
 
-            self.import_block_if_ready(100);
+#[cfg(feature = "metrics")]
+fn serve_metrics(importer: &mut Importer, addr: &str) {
+    let metrics = std::sync::Arc::new(read_blk::metrics::Metrics::new());
+    match metrics.serve(addr) {
+        Ok(local_addr) => info!("serving metrics at http://{}/metrics", local_addr),
+        Err(err) => {
+            eprintln!("error: can't serve metrics at {}: {}", addr, err);
+            std::process::exit(2);
         }
     }
+    importer.set_metrics(metrics);
+}
+
+#[cfg(not(feature = "metrics"))]
+fn serve_metrics(_importer: &mut Importer, _addr: &str) {
+    eprintln!("error: --metrics needs read-blk built with the metrics feature");
+    std::process::exit(2);
+}
+
+fn main() {
+    let args = Args::parse();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let dir = args.dir.clone().unwrap_or_default();
+    if args.file.is_none() && args.url.is_none() && !dir.is_dir() {
+        eprintln!("error: blocks directory {} does not exist", dir.display());
+        std::process::exit(2);
+    }
 
-    fn import_block_if_ready(&mut self, cache_threshold: u32) {
-        // check if the top (FIFO) block in the cache is ready for import
-        if let Some(block) = self.block_cache.remove_block_if_ready(cache_threshold) {
-            let block_hash = block.block_hash();
-            let block_height = block.bip34_block_height().unwrap_or(0);
-            println!(
-                "*** ready to import block {:?} {} header: work {} prev_hash {:?}",
-                block_hash,
-                block_height,
-                block.header.work(),
-                block.header.prev_blockhash
+    let mut importer = match &args.index {
+        #[cfg(feature = "leveldb")]
+        Some(index) => Importer::new_with_index(&dir, index).unwrap_or_else(|err| {
+            eprintln!("error: can't read block index {}: {}", index.display(), err);
+            std::process::exit(2);
+        }),
+        #[cfg(not(feature = "leveldb"))]
+        Some(_) => {
+            eprintln!("error: --index needs read-blk built with the leveldb feature");
+            std::process::exit(2);
+        }
+        None => Importer::new(&dir, 0),
+    };
+    importer.set_start_file(args.start_file);
+    importer.set_reorg_depth(args.reorg_depth);
+    importer.set_removal_policy(args.removal_policy);
+    if let Some(min_height) = args.min_height {
+        importer.set_min_height(min_height);
+    }
+    if let Some(max_height) = args.max_height {
+        importer.set_max_height(max_height);
+    }
+    importer.set_time_range(args.after_time, args.before_time);
+    match (args.base_hash, args.base_height) {
+        (Some(base_hash), Some(base_height)) => importer.set_base(base_hash, base_height),
+        // --base-height is the height of the base block, set_base_height() takes the first block's
+        (None, Some(base_height)) => importer.set_base_height(base_height + 1),
+        _ => {}
+    }
+    if let Some(max_reorg_depth) = args.max_reorg_depth {
+        importer.set_max_reorg_depth(max_reorg_depth);
+    }
+    if let Some(max_pending) = args.max_pending {
+        importer.set_max_pending(max_pending);
+    }
+    if let Some(stale_margin) = args.stale_margin {
+        importer.set_stale_margin(stale_margin);
+    }
+    importer.set_skip_corrupt(args.skip_corrupt);
+    importer.set_validate(args.validate);
+    importer.set_validate_pow(args.validate_pow);
+    importer.set_strict_linkage(!args.lenient_linkage);
+    importer.set_lazy_decode(args.lazy_decode);
+    importer.set_headers_only(args.headers_only);
+    if args.save_index.is_some() {
+        importer.set_build_index(true);
+    }
+    if let Some(min_len) = args.op_returns {
+        importer.set_op_return_min_len(min_len);
+    }
+    if args.utxo {
+        importer.set_utxo_tracker(utxo::UtxoTracker::new());
+    }
+    match sink::open_sink(&args.sink, args.network) {
+        Ok(sink) => importer.set_sink(sink),
+        Err(err) => {
+            eprintln!("error: can't open sink {}: {}", args.sink, err);
+            std::process::exit(2);
+        }
+    }
+    importer.set_progress(1000, |progress| {
+        info!(
+            "### blocks read {}, blk{:05}.dat offset {}, {:.0} blocks/s",
+            progress.blocks_read, progress.file_num, progress.offset, progress.blocks_per_sec
+        );
+    });
+    if let Some(addr) = &args.metrics {
+        serve_metrics(&mut importer, addr);
+    }
+    if let Some(network) = args.network {
+        importer.set_network(network);
+    }
+    if let Some(checkpoint) = &args.checkpoint {
+        if checkpoint.exists() {
+            if let Err(err) = importer.load_checkpoint(checkpoint) {
+                eprintln!(
+                    "error: can't load checkpoint {}: {}",
+                    checkpoint.display(),
+                    err
+                );
+                std::process::exit(2);
+            }
+            info!(
+                "resuming from checkpoint {}, file blk{:05}.dat",
+                checkpoint.display(),
+                importer.file_num()
             );
-            if let Some(prev_block_hash) = self.prev_block_hash {
-                if block_height > 0 && self.prev_block_height > 0 {
-                    if self.prev_block_height + 1 != block_height {
-                        println!(
-                            "!!! WARNING: prev imported block {:?} {}, current block {:?} {} prev_hash {:?}",
-                            prev_block_hash, self.prev_block_height,
-                            block_hash, block_height, block.header.prev_blockhash
-                        );
-                    }
-                }
-                assert_eq!(prev_block_hash, block.header.prev_blockhash);
+        }
+        importer.set_checkpoint_path(checkpoint);
+    }
+    let read_result = match &args.file {
+        Some(file) if file.as_os_str() == "-" => importer
+            .read_blocks_streaming(std::io::stdin().lock())
+            .map(|read_count| read_count.blocks),
+        Some(file) if file.extension().is_some_and(|ext| ext == "tar") => {
+            read_tar(&mut importer, file)
+        }
+        Some(file) => importer.read_file(file).map(|read_count| read_count.blocks),
+        None if args.url.is_some() => read_url_dir(&mut importer, args.url.as_deref().unwrap()),
+        None if args.follow => match watch::dir_events(&dir) {
+            Ok(events) => importer.follow_events(&events),
+            Err(err) => {
+                warn!("can't watch {}: {}, polling instead", dir.display(), err);
+                importer.follow(Duration::from_secs(1), || true)
             }
-            self.prev_block_hash = Some(block_hash);
-            self.prev_block_height = block_height;
+        },
+        None => importer.read_files(),
+    };
+    // any read error leaves the import incomplete, only --skip-corrupt continues past corrupt blocks
+    if let Err(err) = read_result {
+        error!(
+            "blk{:05}.dat: {}, stopped reading",
+            importer.file_num(),
+            err
+        );
+        std::process::exit(1);
+    }
+    if let Some(network) = importer.network() {
+        info!("network {}", network);
+    }
+
+    if let Err(err) = importer.drain_ready(0) {
+        error!("{}, stopped importing", err);
+        std::process::exit(1);
+    }
+    if let Some((tip_hash, tip_height)) = importer.tip() {
+        info!(
+            "tip {} height {}, chainwork {:x}",
+            tip_hash,
+            tip_height,
+            importer.total_chainwork()
+        );
+    }
+    if let Some(checkpoint) = &args.checkpoint {
+        if let Err(err) = importer.save_checkpoint(checkpoint) {
+            error!("can't save checkpoint: {}", err);
+            std::process::exit(1);
+        }
+    }
+    if let (Some(save_index), Some(index)) = (&args.save_index, importer.index()) {
+        match index.save(save_index) {
+            Ok(()) => info!(
+                "saved index of {} blocks to {}",
+                index.len(),
+                save_index.display()
+            ),
+            Err(err) => error!("can't save index {}: {}", save_index.display(), err),
         }
     }
+
+    if let Some(utxo_tracker) = importer.utxo_tracker() {
+        info!(
+            "utxo set: {} outputs, {} total",
+            utxo_tracker.len(),
+            utxo_tracker.total_value()
+        );
+    }
+    let block_cache = importer.block_cache();
+    if block_cache.deepest_reorg_seen() > 0 {
+        info!("deepest reorg {} blocks", block_cache.deepest_reorg_seen());
+    }
+    if importer.invalid_count() > 0 {
+        warn!("{} invalid blocks skipped", importer.invalid_count());
+    }
+    let report = importer.completion_report();
+    info!("{}", report);
+    if report.duplicates > 0 {
+        warn!("duplicate blocks were read, left over from reorgs or rescans");
+    }
+    // the blocks past max height are left in the cache
+    if args.max_height.is_none() && !report.is_complete() {
+        warn!(
+            "{} blocks remained in the cache, {} of them out of order",
+            report.pending, report.out_of_order
+        );
+        std::process::exit(3);
+    }
 }
@@ -0,0 +1,286 @@
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/*
+Prometheus metrics of an Importer, for running it as a service, e.g. in follow mode. The importer updates them
+as it reads, imports and purges blocks, see Importer::set_metrics(), and render() formats them in the Prometheus
+text exposition format. serve() answers GET /metrics with it on a thread of its own; a program with an HTTP
+server of its own can call render() from its handler instead. All the values are atomics, so the importer
+and the thread serving them don't share a lock.
+*/
+
+// the import rate is averaged over at least this long
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+pub struct Metrics {
+    blocks_read: AtomicU64,
+    // bytes of the block frames read, magic and length included
+    bytes_read: AtomicU64,
+    blocks_imported: AtomicU64,
+    tip_height: AtomicU64,
+    pending: AtomicU64,
+    out_of_order: AtomicU64,
+    reorgs: AtomicU64,
+    // losing blocks purged by the reorgs
+    abandoned_blocks: AtomicU64,
+    deepest_reorg: AtomicU64,
+    rate: Mutex<RateWindow>,
+}
+
+// blocks imported per second over the last window that ended
+struct RateWindow {
+    start: Instant,
+    start_count: u64,
+    blocks_per_sec: f64,
+}
+
+struct Metric {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    value: f64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            blocks_read: AtomicU64::new(0),
+            bytes_read: AtomicU64::new(0),
+            blocks_imported: AtomicU64::new(0),
+            tip_height: AtomicU64::new(0),
+            pending: AtomicU64::new(0),
+            out_of_order: AtomicU64::new(0),
+            reorgs: AtomicU64::new(0),
+            abandoned_blocks: AtomicU64::new(0),
+            deepest_reorg: AtomicU64::new(0),
+            rate: Mutex::new(RateWindow {
+                start: Instant::now(),
+                start_count: 0,
+                blocks_per_sec: 0.0,
+            }),
+        }
+    }
+
+    // frame_len is the length of the block's frame in the blk file
+    pub fn on_block_read(&self, frame_len: usize) {
+        self.blocks_read.fetch_add(1, Ordering::Relaxed);
+        self.bytes_read
+            .fetch_add(frame_len as u64, Ordering::Relaxed);
+    }
+
+    pub fn on_block_imported(&self, height: u64) {
+        let imported = self.blocks_imported.fetch_add(1, Ordering::Relaxed) + 1;
+        self.tip_height.store(height, Ordering::Relaxed);
+        self.update_rate(imported);
+    }
+
+    // a reorg that purged abandoned_cnt losing blocks, depth blocks past the fork point
+    pub fn on_reorg(&self, abandoned_cnt: usize, depth: u32) {
+        self.reorgs.fetch_add(1, Ordering::Relaxed);
+        self.abandoned_blocks
+            .fetch_add(abandoned_cnt as u64, Ordering::Relaxed);
+        self.deepest_reorg
+            .fetch_max(depth as u64, Ordering::Relaxed);
+    }
+
+    // the cache counts after a block was added or removed
+    pub fn set_pending(&self, pending_cnt: usize, out_of_order_cnt: usize) {
+        self.pending.store(pending_cnt as u64, Ordering::Relaxed);
+        self.out_of_order
+            .store(out_of_order_cnt as u64, Ordering::Relaxed);
+    }
+
+    pub fn blocks_imported(&self) -> u64 {
+        self.blocks_imported.load(Ordering::Relaxed)
+    }
+
+    // Blocks imported per second over the last RATE_WINDOW that ended, 0 before the first one.
+    // rate() of read_blk_blocks_imported_total in a query is an alternative over a window of its choice
+    pub fn import_rate(&self) -> f64 {
+        self.update_rate(self.blocks_imported());
+        self.rate.lock().unwrap().blocks_per_sec
+    }
+
+    fn update_rate(&self, imported: u64) {
+        let mut rate = self.rate.lock().unwrap();
+        let elapsed = rate.start.elapsed();
+        if elapsed >= RATE_WINDOW {
+            rate.blocks_per_sec = (imported - rate.start_count) as f64 / elapsed.as_secs_f64();
+            rate.start = Instant::now();
+            rate.start_count = imported;
+        }
+    }
+
+    // the metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed) as f64;
+        let metrics = [
+            Metric {
+                name: "read_blk_blocks_read_total",
+                kind: "counter",
+                help: "Blocks read from the blk files",
+                value: load(&self.blocks_read),
+            },
+            Metric {
+                name: "read_blk_bytes_read_total",
+                kind: "counter",
+                help: "Bytes of the block frames read from the blk files",
+                value: load(&self.bytes_read),
+            },
+            Metric {
+                name: "read_blk_blocks_imported_total",
+                kind: "counter",
+                help: "Blocks that migrated out of the cache to the main chain",
+                value: load(&self.blocks_imported),
+            },
+            Metric {
+                name: "read_blk_tip_height",
+                kind: "gauge",
+                help: "Height of the last imported block",
+                value: load(&self.tip_height),
+            },
+            Metric {
+                name: "read_blk_pending_blocks",
+                kind: "gauge",
+                help: "Blocks in the cache waiting to be imported",
+                value: load(&self.pending),
+            },
+            Metric {
+                name: "read_blk_out_of_order_blocks",
+                kind: "gauge",
+                help: "Pending blocks whose parent hasn't been read",
+                value: load(&self.out_of_order),
+            },
+            Metric {
+                name: "read_blk_reorgs_total",
+                kind: "counter",
+                help: "Reorgs that purged losing blocks from the cache",
+                value: load(&self.reorgs),
+            },
+            Metric {
+                name: "read_blk_abandoned_blocks_total",
+                kind: "counter",
+                help: "Losing blocks purged by reorgs",
+                value: load(&self.abandoned_blocks),
+            },
+            Metric {
+                name: "read_blk_deepest_reorg_blocks",
+                kind: "gauge",
+                help: "Most blocks a losing chain ran past its fork point",
+                value: load(&self.deepest_reorg),
+            },
+            Metric {
+                name: "read_blk_import_rate",
+                kind: "gauge",
+                help: "Blocks imported per second over the last 10 seconds",
+                value: self.import_rate(),
+            },
+        ];
+        let mut text = String::new();
+        for metric in metrics {
+            let _ = write!(
+                text,
+                "# HELP {0} {1}\n# TYPE {0} {2}\n{0} {3}\n",
+                metric.name, metric.help, metric.kind, metric.value
+            );
+        }
+        text
+    }
+
+    // Answers GET /metrics on addr with render(), on a thread for as long as the program runs.
+    // Returns the address bound, e.g. the port picked for port 0
+    pub fn serve(self: &Arc<Self>, addr: impl ToSocketAddrs) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let metrics = Arc::clone(self);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // a client that goes away is its own problem
+                let _ = metrics.respond(stream);
+            }
+        });
+        Ok(local_addr)
+    }
+
+    fn respond(&self, stream: std::net::TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        // the rest of the request is not needed, but is read so the client doesn't see a reset
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+        }
+        let mut parts = request_line.split_whitespace();
+        let response = match (parts.next(), parts.next()) {
+            (Some("GET"), Some("/metrics")) => {
+                let body = self.render();
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            }
+            _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_string(),
+        };
+        let mut stream = reader.into_inner();
+        stream.write_all(response.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpStream;
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.on_block_read(293);
+        metrics.on_block_read(223);
+        metrics.on_block_imported(0);
+        metrics.on_reorg(2, 2);
+        metrics.on_reorg(1, 1);
+        metrics.set_pending(1, 0);
+        let text = metrics.render();
+        assert!(text
+            .contains("# TYPE read_blk_blocks_read_total counter\nread_blk_blocks_read_total 2\n"));
+        assert!(text.contains("\nread_blk_bytes_read_total 516\n"));
+        assert!(text.contains("\nread_blk_blocks_imported_total 1\n"));
+        assert!(text.contains("\nread_blk_pending_blocks 1\n"));
+        assert!(text.contains("\nread_blk_reorgs_total 2\n"));
+        assert!(text.contains("\nread_blk_abandoned_blocks_total 3\n"));
+        assert!(text.contains("\nread_blk_deepest_reorg_blocks 2\n"));
+        // the first window hasn't ended
+        assert!(text.contains("\nread_blk_import_rate 0\n"));
+
+        let addr = metrics.serve("127.0.0.1:0").unwrap();
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(&metrics.render()));
+        assert!(get("/").starts_with("HTTP/1.1 404 "));
+    }
+}
@@ -0,0 +1,139 @@
+use crate::block_cache::ReorgEvent;
+use crate::importer::ImportedBlock;
+use crate::logging::{debug, warn};
+use crate::summary::{BlockSummary, OpReturn};
+use bitcoin::hex::DisplayHex;
+use bitcoin::{Block, Network};
+use std::sync::mpsc;
+
+// What the importer knows about an imported block beyond the block itself
+#[derive(Debug, Clone)]
+pub struct BlockMeta {
+    // counted from the first imported block, whose height is its BIP34 height or the cache base height
+    pub height: u64,
+    // number of the blk file the block was read from
+    pub file_num: u32,
+    // if the importer computes summaries
+    pub summary: Option<BlockSummary>,
+    // empty unless the importer scans for OP_RETURN outputs, see Importer::set_op_return_min_len()
+    pub op_returns: Vec<OpReturn>,
+}
+
+/// Receives the blocks that migrate out of the cache to the main chain, in chain order
+pub trait BlockSink {
+    fn on_block(&mut self, block: &Block, meta: &BlockMeta);
+    // losing blocks purged from the cache, none of them was passed to on_block()
+    fn on_reorg(&mut self, _reorg: &ReorgEvent) {}
+}
+
+impl<S: BlockSink + ?Sized> BlockSink for Box<S> {
+    fn on_block(&mut self, block: &Block, meta: &BlockMeta) {
+        (**self).on_block(block, meta);
+    }
+
+    fn on_reorg(&mut self, reorg: &ReorgEvent) {
+        (**self).on_reorg(reorg);
+    }
+}
+
+/// Opens the sink named by spec: log, stdout, csv:path, blk:dir, or sqlite://path with the sqlite feature.
+/// The blk files written to dir are framed with the magic of network, which has to be known
+pub fn open_sink(spec: &str, network: Option<Network>) -> Result<Box<dyn BlockSink>, String> {
+    if spec == "log" {
+        return Ok(Box::new(LogSink));
+    }
+    if spec == "stdout" {
+        return Ok(Box::new(StdoutSink));
+    }
+    if let Some(path) = spec.strip_prefix("csv:") {
+        let sink = crate::csv_sink::CsvSink::create(path).map_err(|err| err.to_string())?;
+        return Ok(Box::new(sink));
+    }
+    if let Some(dir) = spec.strip_prefix("blk:") {
+        let network = network.ok_or("the blk sink needs the network")?;
+        let sink =
+            crate::blk_sink::BlkFileSink::create(dir, network).map_err(|err| err.to_string())?;
+        return Ok(Box::new(sink));
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = spec.strip_prefix("sqlite://") {
+        let sink = crate::sqlite_sink::SqliteSink::open(path).map_err(|err| err.to_string())?;
+        return Ok(Box::new(sink));
+    }
+    Err(format!("unknown sink {}", spec))
+}
+
+// Logs every imported block at debug level; the cache logs the purged blocks itself
+pub struct LogSink;
+
+impl BlockSink for LogSink {
+    fn on_block(&mut self, block: &Block, meta: &BlockMeta) {
+        debug!(
+            "*** ready to import block {:?} {} header: work {} prev_hash {:?}",
+            block.block_hash(),
+            meta.height,
+            block.header.work(),
+            block.header.prev_blockhash
+        );
+        for op_return in meta.op_returns.iter() {
+            debug!(
+                "op_return {}:{} {}",
+                op_return.txid,
+                op_return.vout,
+                op_return.payload.to_lower_hex_string()
+            );
+        }
+    }
+}
+
+// Prints every imported block to stdout, whatever the log level
+pub struct StdoutSink;
+
+impl BlockSink for StdoutSink {
+    fn on_block(&mut self, block: &Block, meta: &BlockMeta) {
+        println!(
+            "*** ready to import block {:?} {} header: work {} prev_hash {:?}",
+            block.block_hash(),
+            meta.height,
+            block.header.work(),
+            block.header.prev_blockhash
+        );
+        for op_return in meta.op_returns.iter() {
+            println!(
+                "op_return {}:{} {}",
+                op_return.txid,
+                op_return.vout,
+                op_return.payload.to_lower_hex_string()
+            );
+        }
+    }
+}
+
+// Sends a copy of every imported block to a consumer thread
+pub struct ChannelSink {
+    sender: Option<mpsc::Sender<ImportedBlock>>,
+}
+
+impl ChannelSink {
+    pub fn new(sender: mpsc::Sender<ImportedBlock>) -> Self {
+        ChannelSink {
+            sender: Some(sender),
+        }
+    }
+}
+
+impl BlockSink for ChannelSink {
+    fn on_block(&mut self, block: &Block, meta: &BlockMeta) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let imported_block = ImportedBlock {
+            block: block.clone(),
+            height: meta.height,
+        };
+        if sender.send(imported_block).is_err() {
+            warn!("imported block receiver is gone, no more blocks are sent");
+            self.sender = None;
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use crate::block_cache::ReorgEvent;
+use crate::logging::error;
+use crate::sink::{BlockMeta, BlockSink};
+use bitcoin::{Block, BlockHash};
+use rusqlite::{params, Connection};
+use std::path::Path;
+
+// imported blocks inserted per transaction
+const BATCH_SIZE: usize = 1000;
+
+// Inserts a row per imported block into the blocks table
+pub struct SqliteSink {
+    connection: Connection,
+    // rows inserted in the open transaction
+    batch_cnt: usize,
+}
+
+impl SqliteSink {
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Self> {
+        SqliteSink::new(Connection::open(path)?)
+    }
+
+    pub fn new(connection: Connection) -> rusqlite::Result<Self> {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS blocks (
+                height INTEGER NOT NULL,
+                hash TEXT PRIMARY KEY,
+                prev_hash TEXT NOT NULL,
+                time INTEGER NOT NULL,
+                bits INTEGER NOT NULL,
+                work TEXT NOT NULL,
+                tx_count INTEGER NOT NULL,
+                file_num INTEGER NOT NULL
+            )",
+        )?;
+        Ok(SqliteSink {
+            connection,
+            batch_cnt: 0,
+        })
+    }
+
+    // commits the open transaction
+    pub fn flush(&mut self) -> rusqlite::Result<()> {
+        if self.batch_cnt > 0 {
+            self.connection.execute_batch("COMMIT")?;
+            self.batch_cnt = 0;
+        }
+        Ok(())
+    }
+
+    fn insert_block(&mut self, block: &Block, meta: &BlockMeta) -> rusqlite::Result<()> {
+        if self.batch_cnt == 0 {
+            self.connection.execute_batch("BEGIN")?;
+        }
+        self.connection
+            .prepare_cached(
+                "INSERT OR REPLACE INTO blocks
+                (height, hash, prev_hash, time, bits, work, tx_count, file_num)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            )?
+            .execute(params![
+                meta.height as i64,
+                block.block_hash().to_string(),
+                block.header.prev_blockhash.to_string(),
+                block.header.time,
+                block.header.bits.to_consensus(),
+                format!("{:x}", block.header.work()),
+                block.txdata.len() as i64,
+                meta.file_num,
+            ])?;
+        self.batch_cnt += 1;
+        if self.batch_cnt >= BATCH_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn delete_blocks(&mut self, purged: &[BlockHash]) -> rusqlite::Result<()> {
+        let mut statement = self
+            .connection
+            .prepare_cached("DELETE FROM blocks WHERE hash = ?1")?;
+        for hash in purged {
+            statement.execute(params![hash.to_string()])?;
+        }
+        Ok(())
+    }
+}
+
+impl BlockSink for SqliteSink {
+    fn on_block(&mut self, block: &Block, meta: &BlockMeta) {
+        if let Err(err) = self.insert_block(block, meta) {
+            error!(
+                "sqlite: can't insert block {:?}: {}",
+                block.block_hash(),
+                err
+            );
+        }
+    }
+
+    fn on_reorg(&mut self, reorg: &ReorgEvent) {
+        if let Err(err) = self.delete_blocks(&reorg.abandoned_blocks) {
+            error!("sqlite: can't delete purged blocks: {}", err);
+        }
+    }
+}
+
+impl Drop for SqliteSink {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            error!("sqlite: can't commit: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::constants::genesis_block;
+    use bitcoin::Network;
+
+    type BlockRow = (i64, String, String, u32, u32, String, i64, u32);
+
+    #[test]
+    fn test_sqlite_sink() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut next_block = genesis.clone();
+        next_block.header.prev_blockhash = genesis.block_hash();
+
+        let mut sink = SqliteSink::new(Connection::open_in_memory().unwrap()).unwrap();
+        sink.on_block(
+            &genesis,
+            &BlockMeta {
+                height: 0,
+                file_num: 0,
+                summary: None,
+                op_returns: Vec::new(),
+            },
+        );
+        sink.on_block(
+            &next_block,
+            &BlockMeta {
+                height: 1,
+                file_num: 2,
+                summary: None,
+                op_returns: Vec::new(),
+            },
+        );
+        sink.on_reorg(&ReorgEvent {
+            abandoned_tip: genesis.block_hash(),
+            abandoned_blocks: vec![genesis.block_hash()],
+            winning_tip: next_block.block_hash(),
+            fork_height: 0,
+            depth: 1,
+        });
+        sink.flush().unwrap();
+
+        let rows: Vec<BlockRow> = sink
+            .connection
+            .prepare(
+                "SELECT height, hash, prev_hash, time, bits, work, tx_count, file_num FROM blocks",
+            )
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                    row.get(6)?,
+                    row.get(7)?,
+                ))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![(
+                1,
+                next_block.block_hash().to_string(),
+                genesis.block_hash().to_string(),
+                genesis.header.time,
+                0x1d00ffff,
+                format!("{:0>64}", "100010001"),
+                1,
+                2
+            )]
+        );
+    }
+}
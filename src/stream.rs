@@ -0,0 +1,102 @@
+use crate::importer::Importer;
+use crate::logging::{error, warn};
+use crate::sink::{BlockMeta, BlockSink};
+use bitcoin::{Block, Network};
+use futures_core::Stream;
+use std::path::PathBuf;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+// imported blocks buffered ahead of the consumer before the reader waits
+const STREAM_DEPTH: usize = 16;
+
+// Sends a copy of every imported block, waiting while the channel is full
+struct StreamSink {
+    sender: Option<mpsc::Sender<(Block, u64)>>,
+}
+
+impl BlockSink for StreamSink {
+    fn on_block(&mut self, block: &Block, meta: &BlockMeta) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        if sender.blocking_send((block.clone(), meta.height)).is_err() {
+            warn!("imported block stream is dropped, no more blocks are sent");
+            self.sender = None;
+        }
+    }
+}
+
+/// Imports the blk files in dir_path on a blocking task and streams the imported blocks with their heights.
+/// The reader pauses whenever the consumer falls STREAM_DEPTH blocks behind, so the chain is never buffered
+/// in memory. Has to be called from within a tokio runtime.
+pub fn spawn_import(
+    dir_path: impl Into<PathBuf>,
+    network: Network,
+) -> impl Stream<Item = (Block, u64)> {
+    let dir_path = dir_path.into();
+    let (sender, receiver) = mpsc::channel(STREAM_DEPTH);
+    tokio::task::spawn_blocking(move || {
+        let mut importer = Importer::new(&dir_path, 0);
+        importer.set_network(network);
+        importer.set_sink(StreamSink {
+            sender: Some(sender),
+        });
+        if let Err(err) = importer.read_files() {
+            error!(
+                "blk{:05}.dat: {}, stopped reading",
+                importer.file_num(),
+                err
+            );
+        }
+        if let Err(err) = importer.drain_ready(0) {
+            error!("{}, stopped importing", err);
+        }
+    });
+    ReceiverStream::new(receiver)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::consensus::serialize;
+    use bitcoin::constants::genesis_block;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn test_spawn_import() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut file_bytes = Vec::new();
+        let mut prev_block = genesis.clone();
+        let mut blocks = vec![genesis.clone()];
+        // more blocks than the stream buffers
+        for nonce in 0..2 * STREAM_DEPTH as u32 {
+            let mut block = genesis.clone();
+            block.header.prev_blockhash = prev_block.block_hash();
+            block.header.nonce = nonce;
+            blocks.push(block.clone());
+            prev_block = block;
+        }
+        for block in &blocks {
+            let block_bytes = serialize(block);
+            file_bytes.extend_from_slice(&Network::Bitcoin.magic().to_bytes());
+            file_bytes.extend_from_slice(&(block_bytes.len() as u32).to_le_bytes());
+            file_bytes.extend_from_slice(&block_bytes);
+        }
+        let dir = std::env::temp_dir().join(format!("read-blk-stream-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("blk00000.dat"), file_bytes).unwrap();
+
+        let imported: Vec<_> = spawn_import(&dir, Network::Bitcoin)
+            .map(|(block, height)| (block.block_hash(), height))
+            .collect()
+            .await;
+        let expected: Vec<_> = blocks
+            .iter()
+            .enumerate()
+            .map(|(height, block)| (block.block_hash(), height as u64))
+            .collect();
+        assert_eq!(imported, expected);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,256 @@
+use bitcoin::script::Instruction;
+use bitcoin::{Amount, Block, Script, ScriptBuf, TxIn, Txid, Weight};
+
+// printable runs shorter than this are taken for binary data, e.g. extranonces, that happens to be ASCII
+const MIN_TAG_LEN: usize = 4;
+
+// Per block stats passed to the sink along with an imported block
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockSummary {
+    pub tx_count: usize,
+    // sum of all outputs, including the coinbase, Amount::MAX if it overflows, which no valid block's does
+    pub total_output_value: Amount,
+    // serialized size including the witness data, the weight counts the witness bytes once instead of 4 times
+    pub size: usize,
+    pub weight: Weight,
+    // true if any input has witness data
+    pub is_segwit: bool,
+    // needs the spent outputs, so it is only known when the importer tracks the UTXO set
+    pub fee: Option<Amount>,
+    // the coinbase input's scriptSig, where miners put the BIP34 height, extranonces and their pool marker
+    pub coinbase_script_sig: ScriptBuf,
+    // the printable ASCII in the scriptSig past the BIP34 height, e.g. /ViaBTC/, see coinbase_tag()
+    pub coinbase_tag: Option<String>,
+    // the BIP34 height pushed at the front of the scriptSig, the sink can check it against BlockMeta::height
+    pub coinbase_height: Option<u64>,
+}
+
+pub fn block_summary(block: &Block) -> BlockSummary {
+    BlockSummary {
+        tx_count: block.txdata.len(),
+        total_output_value: block
+            .txdata
+            .iter()
+            .flat_map(|tx| tx.output.iter())
+            .try_fold(Amount::ZERO, |total, txout| total.checked_add(txout.value))
+            .unwrap_or(Amount::MAX),
+        size: block.total_size(),
+        weight: block.weight(),
+        is_segwit: block
+            .txdata
+            .iter()
+            .flat_map(|tx| tx.input.iter())
+            .any(|input| !input.witness.is_empty()),
+        fee: None,
+        // a transaction with the segwit flag and no inputs decodes, so a corrupt coinbase may have none
+        coinbase_script_sig: coinbase_input(block)
+            .map(|input| input.script_sig.clone())
+            .unwrap_or_default(),
+        coinbase_tag: coinbase_input(block).and_then(|input| {
+            coinbase_tag(
+                input.script_sig.as_bytes(),
+                block.bip34_block_height().is_ok(),
+            )
+        }),
+        coinbase_height: block.bip34_block_height().ok(),
+    }
+}
+
+fn coinbase_input(block: &Block) -> Option<&TxIn> {
+    block.txdata.first()?.input.first()
+}
+
+// The runs of at least MIN_TAG_LEN printable ASCII characters in script_sig, joined by a space.
+// The scriptSig is split into its pushes for as long as it parses as direct pushes, so that the push lengths
+// don't run into the tag; the bytes from the first one that doesn't are scanned raw. The first push is the
+// BIP34 height if has_height
+pub fn coinbase_tag(script_sig: &[u8], has_height: bool) -> Option<String> {
+    let mut chunks = Vec::new();
+    let mut rest = script_sig;
+    while let Some((&push_len, tail)) = rest.split_first() {
+        match tail.get(..push_len as usize) {
+            Some(data) if (1..=75).contains(&push_len) => {
+                chunks.push(data);
+                rest = &tail[data.len()..];
+            }
+            _ => {
+                chunks.push(rest);
+                break;
+            }
+        }
+    }
+    let tag = chunks
+        .into_iter()
+        .skip(has_height as usize)
+        .flat_map(|chunk| chunk.split(|byte| !(byte.is_ascii_graphic() || *byte == b' ')))
+        .filter(|run| run.len() >= MIN_TAG_LEN)
+        .map(|run| String::from_utf8_lossy(run).trim().to_string())
+        .filter(|run| !run.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    (!tag.is_empty()).then_some(tag)
+}
+
+// The data of an OP_RETURN output, passed to the sink if the importer scans for them
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpReturn {
+    pub txid: Txid,
+    pub vout: u32,
+    // the data pushed after OP_RETURN, or the raw bytes after it if they aren't all pushes
+    pub payload: Vec<u8>,
+}
+
+// The OP_RETURN outputs of block whose payload is at least min_len bytes, in block order
+pub fn op_returns(block: &Block, min_len: usize) -> Vec<OpReturn> {
+    let mut op_returns = Vec::new();
+    for tx in block.txdata.iter() {
+        let mut txid = None;
+        for (vout, txout) in tx.output.iter().enumerate() {
+            if !txout.script_pubkey.is_op_return() {
+                continue;
+            }
+            let payload = op_return_payload(&txout.script_pubkey);
+            if payload.len() < min_len {
+                continue;
+            }
+            op_returns.push(OpReturn {
+                txid: *txid.get_or_insert_with(|| tx.compute_txid()),
+                vout: vout as u32,
+                payload,
+            });
+        }
+    }
+    op_returns
+}
+
+fn op_return_payload(script_pubkey: &Script) -> Vec<u8> {
+    let mut payload = Vec::new();
+    for instruction in script_pubkey.instructions().skip(1) {
+        match instruction {
+            Ok(Instruction::PushBytes(data)) => payload.extend_from_slice(data.as_bytes()),
+            _ => return script_pubkey.as_bytes()[1..].to_vec(),
+        }
+    }
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::constants::genesis_block;
+    use bitcoin::Network;
+
+    #[test]
+    fn test_block_summary() {
+        let genesis = genesis_block(Network::Bitcoin);
+        assert_eq!(
+            block_summary(&genesis),
+            BlockSummary {
+                tx_count: 1,
+                total_output_value: Amount::from_btc(50.0).unwrap(),
+                size: 285,
+                weight: Weight::from_wu(1140),
+                is_segwit: false,
+                fee: None,
+                coinbase_script_sig: genesis.txdata[0].input[0].script_sig.clone(),
+                coinbase_tag: Some(
+                    "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks".into()
+                ),
+                coinbase_height: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_coinbase_tag() {
+        // block 840000: BIP34 height, pool marker, extranonce
+        let script_sig =
+            b"\x03\x40\xd1\x0c\x04\x1b\x3d\x1f\x66/ViaBTC/Mined by longhash/,\xfa\xbe\x6d\x6d\x01";
+        assert_eq!(
+            coinbase_tag(script_sig, true),
+            Some("/ViaBTC/Mined by longhash/,".into())
+        );
+        // the height bytes aren't mistaken for a tag even when printable
+        assert_eq!(coinbase_tag(b"\x04ABCD\x01\x00", true), None);
+        assert_eq!(
+            coinbase_tag(b"\x04ABCD\x01\x00", false),
+            Some("ABCD".into())
+        );
+        assert_eq!(coinbase_tag(b"", true), None);
+    }
+
+    #[test]
+    fn test_op_returns() {
+        use bitcoin::script::{Builder, PushBytesBuf};
+        use bitcoin::{opcodes, TxOut};
+
+        let mut block = genesis_block(Network::Bitcoin);
+        let op_return = |data: &[u8]| TxOut {
+            value: Amount::ZERO,
+            script_pubkey: Builder::new()
+                .push_opcode(opcodes::all::OP_RETURN)
+                .push_slice(PushBytesBuf::try_from(data.to_vec()).unwrap())
+                .into_script(),
+        };
+        let tx = &mut block.txdata[0];
+        tx.output.push(op_return(b"omni"));
+        tx.output.push(op_return(b"a"));
+        // not all pushes, the raw bytes are the payload
+        tx.output.push(TxOut {
+            value: Amount::ZERO,
+            script_pubkey: ScriptBuf::from_bytes(vec![0x6a, 0x51, 0x52]),
+        });
+        let txid = tx.compute_txid();
+        assert_eq!(
+            op_returns(&block, 2),
+            vec![
+                OpReturn {
+                    txid,
+                    vout: 1,
+                    payload: b"omni".to_vec(),
+                },
+                OpReturn {
+                    txid,
+                    vout: 3,
+                    payload: vec![0x51, 0x52],
+                },
+            ]
+        );
+        assert_eq!(op_returns(&block, 0).len(), 3);
+        assert!(op_returns(&genesis_block(Network::Bitcoin), 0).is_empty());
+    }
+
+    #[test]
+    fn test_segwit_weight() {
+        let mut block = genesis_block(Network::Bitcoin);
+        block.txdata[0].input[0].witness.push([0; 32]);
+        let summary = block_summary(&block);
+        assert!(summary.is_segwit);
+        // the segwit marker and flag, the witness item count, length and nonce
+        assert_eq!(summary.size, 285 + 2 + 1 + 1 + 32);
+        assert_eq!(summary.weight, Weight::from_wu(1140 + 2 + 1 + 1 + 32));
+    }
+
+    #[test]
+    fn test_corrupt_coinbase() {
+        use bitcoin::consensus::deserialize;
+        use bitcoin::{Transaction, TxOut};
+
+        // version, the segwit marker and flag, no inputs, a 50 BTC output with an empty script, lock time
+        let tx_bytes = hex::decode("010000000001000100f2052a010000000000000000").unwrap();
+        let coinbase: Transaction = deserialize(&tx_bytes).unwrap();
+        assert!(coinbase.input.is_empty());
+        let mut block = genesis_block(Network::Bitcoin);
+        block.txdata = vec![coinbase];
+        let summary = block_summary(&block);
+        assert_eq!(summary.coinbase_script_sig, ScriptBuf::new());
+        assert_eq!(summary.coinbase_tag, None);
+
+        let max_output = TxOut {
+            value: Amount::MAX,
+            script_pubkey: ScriptBuf::new(),
+        };
+        block.txdata[0].output.push(max_output);
+        assert_eq!(block_summary(&block).total_output_value, Amount::MAX);
+    }
+}
@@ -0,0 +1,197 @@
+use crate::block_cache::{
+    AddResult, BlockCache, BlockInfo, BlockPayload, CacheStats, ReorgEvent, SourceLoc,
+};
+use bitcoin::BlockHash;
+use std::sync::{Mutex, MutexGuard};
+
+/*
+SyncBlockCache shares a BlockCache between threads, e.g. a decoder thread that reads the blk files and adds the blocks,
+and a migrator thread that removes the ready blocks and imports or indexes them, see test_decoder_migrator.
+The whole cache is behind a single Mutex and every method holds the lock for one BlockCache call only:
+adding a block stages it and merges the detached trees waiting for it, and removing a root purges the losing branches,
+under the same lock, so a thread never sees the tree in between. The tree changes on every add and removal,
+so a RwLock would buy little. Methods return owned or cloned values, nothing borrows the cache past the call.
+Calls that have to see the same tree, e.g. peek_ready() and then source_of(), take the lock once with lock().
+*/
+
+pub struct SyncBlockCache<T = bitcoin::Block> {
+    block_cache: Mutex<BlockCache<T>>,
+}
+
+impl<T: BlockPayload> SyncBlockCache<T> {
+    pub fn new(block_cache: BlockCache<T>) -> Self {
+        SyncBlockCache {
+            block_cache: Mutex::new(block_cache),
+        }
+    }
+
+    pub fn new_with_threshold(depth_threshold: u32) -> Self {
+        SyncBlockCache::new(BlockCache::new_with_threshold(depth_threshold))
+    }
+
+    /// The cache, locked until the guard is dropped. A thread that panicked while holding the lock
+    /// may have left the tree half updated, so a poisoned lock panics.
+    pub fn lock(&self) -> MutexGuard<'_, BlockCache<T>> {
+        self.block_cache.lock().expect("block cache lock poisoned")
+    }
+
+    pub fn into_inner(self) -> BlockCache<T> {
+        self.block_cache
+            .into_inner()
+            .expect("block cache lock poisoned")
+    }
+
+    /// see BlockCache::add_block()
+    pub fn add_block(&self, block: T) -> AddResult {
+        self.lock().add_block(block)
+    }
+
+    /// see BlockCache::add_block_from()
+    pub fn add_block_from(&self, block: T, source: SourceLoc) -> AddResult {
+        self.lock().add_block_from(block, source)
+    }
+
+    /// see BlockCache::remove_block_if_ready()
+    pub fn remove_block_if_ready(&self, depth_threshold: u32) -> Option<T> {
+        self.lock().remove_block_if_ready(depth_threshold)
+    }
+
+    /// see BlockCache::remove_block_with_height_if_ready()
+    pub fn remove_block_with_height_if_ready(
+        &self,
+        depth_threshold: u32,
+    ) -> std::io::Result<Option<(T, u64)>> {
+        self.lock()
+            .remove_block_with_height_if_ready(depth_threshold)
+    }
+
+    /// see BlockCache::remove_block_if_ready_default()
+    pub fn remove_block_if_ready_default(&self) -> Option<T> {
+        self.lock().remove_block_if_ready_default()
+    }
+
+    /// see BlockCache::drain_ready(), the blocks are removed under one lock
+    pub fn drain_ready(&self, depth_threshold: u32) -> Vec<T> {
+        self.lock().drain_ready(depth_threshold)
+    }
+
+    /// see BlockCache::peek_ready(), the tree may change as soon as this returns
+    pub fn peek_ready(&self, depth_threshold: u32) -> Option<BlockInfo> {
+        self.lock().peek_ready(depth_threshold).cloned()
+    }
+
+    /// a copy of the pending block, see BlockCache::get_block()
+    pub fn get_block(&self, hash: &BlockHash) -> Option<T> {
+        self.lock().get_block(hash).cloned()
+    }
+
+    pub fn contains_block(&self, hash: &BlockHash) -> bool {
+        self.lock().contains_block(hash)
+    }
+
+    pub fn source_of(&self, hash: &BlockHash) -> Option<SourceLoc> {
+        self.lock().source_of(hash)
+    }
+
+    pub fn height(&self, hash: &BlockHash) -> Option<u64> {
+        self.lock().height(hash)
+    }
+
+    /// see BlockCache::last_reorg()
+    pub fn last_reorg(&self) -> Option<ReorgEvent> {
+        self.lock().last_reorg().cloned()
+    }
+
+    pub fn threshold(&self) -> u32 {
+        self.lock().threshold()
+    }
+
+    pub fn set_threshold(&self, depth_threshold: u32) {
+        self.lock().set_threshold(depth_threshold);
+    }
+
+    /// all the counts under one lock, unlike calling pending_cnt() and staged_cnt() one after the other
+    pub fn stats(&self) -> CacheStats {
+        self.lock().stats()
+    }
+
+    pub fn pending_cnt(&self) -> usize {
+        self.lock().pending_cnt()
+    }
+
+    pub fn staged_cnt(&self) -> usize {
+        self.lock().staged_cnt()
+    }
+
+    pub fn out_of_order_cnt(&self) -> usize {
+        self.lock().out_of_order_cnt()
+    }
+
+    pub fn current_root(&self) -> Option<BlockHash> {
+        self.lock().current_root()
+    }
+
+    pub fn tree_depth(&self) -> u32 {
+        self.lock().tree_depth()
+    }
+
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::constants::genesis_block;
+    use bitcoin::Network;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    #[test]
+    fn test_decoder_migrator() {
+        let mut blocks = vec![genesis_block(Network::Bitcoin)];
+        for _ in 1..50 {
+            let mut block = blocks.last().unwrap().clone();
+            block.header.prev_blockhash = block.block_hash();
+            blocks.push(block);
+        }
+        let block_cache = SyncBlockCache::new_with_threshold(3);
+        let decoding_done = AtomicBool::new(false);
+
+        let migrated = thread::scope(|scope| {
+            // the decoder adds the blocks as it reads them
+            scope.spawn(|| {
+                for block in blocks.iter() {
+                    assert!(block_cache.add_block(block.clone()).is_added());
+                }
+                decoding_done.store(true, Ordering::Release);
+            });
+            // the migrator imports the blocks as they get deep enough, then the rest once decoding is done
+            let migrator = scope.spawn(|| {
+                let mut migrated = Vec::new();
+                while !decoding_done.load(Ordering::Acquire) {
+                    match block_cache.remove_block_with_height_if_ready(3).unwrap() {
+                        Some(block_height) => migrated.push(block_height),
+                        None => thread::yield_now(),
+                    }
+                }
+                while let Some(block_height) =
+                    block_cache.remove_block_with_height_if_ready(0).unwrap()
+                {
+                    migrated.push(block_height);
+                }
+                migrated
+            });
+            migrator.join().unwrap()
+        });
+
+        let heights: Vec<u64> = migrated.iter().map(|(_, height)| *height).collect();
+        assert_eq!(heights, (0..50).collect::<Vec<u64>>());
+        assert!(migrated
+            .iter()
+            .zip(blocks.iter())
+            .all(|((migrated_block, _), block)| migrated_block == block));
+        assert_eq!(block_cache.stats().pending_cnt, 0);
+    }
+}
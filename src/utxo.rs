@@ -0,0 +1,347 @@
+use crate::logging::warn;
+use bitcoin::{Amount, Block, BlockHash, OutPoint, TxOut};
+use std::collections::{HashMap, VecDeque};
+
+// undo data is kept for this many of the last applied blocks
+const MAX_UNDO_BLOCKS: usize = 100;
+
+/*
+UtxoTracker builds the set of unspent transaction outputs from the blocks migrating out of BlockCache in chain order.
+Every applied block removes the outputs its transactions spend and adds the outputs they create.
+Blocks purged as losing are never applied, but the undo data of the last applied blocks lets them be rolled back.
+Like in bitcoin core, the genesis coinbase output and provably unspendable (OP_RETURN) outputs are not added,
+and a duplicate coinbase txid (BIP30, mainnet blocks 91842 and 91880) overwrites the earlier outputs.
+*/
+
+#[derive(Debug, Default)]
+pub struct UtxoTracker {
+    utxos: HashMap<OutPoint, TxOut>,
+    total_value: Amount,
+    // last applied blocks, oldest first
+    undo_blocks: VecDeque<(BlockHash, BlockUndo)>,
+}
+
+// changes to the set in the order a block made them, undone in reverse
+type BlockUndo = Vec<UndoOp>;
+
+#[derive(Debug)]
+enum UndoOp {
+    Created(OutPoint),
+    Spent(OutPoint, TxOut),
+}
+
+impl UtxoTracker {
+    pub fn new() -> Self {
+        UtxoTracker::default()
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&TxOut> {
+        self.utxos.get(outpoint)
+    }
+
+    pub fn len(&self) -> usize {
+        self.utxos.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.utxos.is_empty()
+    }
+
+    pub fn total_value(&self) -> Amount {
+        self.total_value
+    }
+
+    // Spends are looked up in the outputs created so far, including earlier transactions of the same block.
+    // A spend of an unknown output is logged and skipped.
+    // Returns the total fees of the block's transactions, None if they spend an unknown output
+    // or their values overflow.
+    pub fn apply_block(&mut self, block: &Block, height: u64) -> Option<Amount> {
+        let mut undo = BlockUndo::default();
+        // None once a sum overflows, only a block with absurd output values gets there
+        let mut spent_value = Some(Amount::ZERO);
+        let mut output_value = Some(Amount::ZERO);
+        let mut unknown_spend = false;
+        for tx in block.txdata.iter() {
+            if !tx.is_coinbase() {
+                for input in tx.input.iter() {
+                    match self.utxos.remove(&input.previous_output) {
+                        Some(txout) => {
+                            self.sub_value(txout.value);
+                            spent_value =
+                                spent_value.and_then(|spent| spent.checked_add(txout.value));
+                            undo.push(UndoOp::Spent(input.previous_output, txout));
+                        }
+                        None => {
+                            unknown_spend = true;
+                            warn!(
+                                "block {:?} {} spends unknown output {}",
+                                block.block_hash(),
+                                height,
+                                input.previous_output
+                            );
+                        }
+                    }
+                }
+                output_value = tx
+                    .output
+                    .iter()
+                    .fold(output_value, |total, txout| total?.checked_add(txout.value));
+            }
+            if height == 0 {
+                // the genesis coinbase can't be spent
+                continue;
+            }
+            let txid = tx.compute_txid();
+            for (vout, txout) in tx.output.iter().enumerate() {
+                if txout.script_pubkey.is_op_return() {
+                    continue;
+                }
+                let outpoint = OutPoint::new(txid, vout as u32);
+                self.add_value(txout.value);
+                if let Some(old_txout) = self.utxos.insert(outpoint, txout.clone()) {
+                    self.sub_value(old_txout.value);
+                    undo.push(UndoOp::Spent(outpoint, old_txout));
+                }
+                undo.push(UndoOp::Created(outpoint));
+            }
+        }
+        self.undo_blocks.push_back((block.block_hash(), undo));
+        if self.undo_blocks.len() > MAX_UNDO_BLOCKS {
+            self.undo_blocks.pop_front();
+        }
+        if unknown_spend {
+            return None;
+        }
+        let (Some(spent_value), Some(output_value)) = (spent_value, output_value) else {
+            warn!(
+                "block {:?} {} values overflow, its fees are unknown",
+                block.block_hash(),
+                height
+            );
+            return None;
+        };
+        spent_value.checked_sub(output_value)
+    }
+
+    // The total saturates rather than overflow, so it is only a lower bound once the warning was logged
+    fn add_value(&mut self, value: Amount) {
+        self.total_value = self.total_value.checked_add(value).unwrap_or_else(|| {
+            warn!(
+                "the UTXO set value overflows, its total stays at {}",
+                Amount::MAX
+            );
+            Amount::MAX
+        });
+    }
+
+    // the total can only be short of value after add_value() saturated it
+    fn sub_value(&mut self, value: Amount) {
+        self.total_value = self.total_value.checked_sub(value).unwrap_or(Amount::ZERO);
+    }
+
+    // Rolls back the last applied blocks down to and including block_hash.
+    // Returns false, with nothing rolled back, if block_hash is not among the blocks with undo data.
+    pub fn rollback_to_before(&mut self, block_hash: &BlockHash) -> bool {
+        if !self.undo_blocks.iter().any(|(hash, _)| hash == block_hash) {
+            return false;
+        }
+        while let Some((hash, undo)) = self.undo_blocks.pop_back() {
+            for undo_op in undo.into_iter().rev() {
+                match undo_op {
+                    UndoOp::Created(outpoint) => {
+                        let txout = self
+                            .utxos
+                            .remove(&outpoint)
+                            .expect("created output expected");
+                        self.sub_value(txout.value);
+                    }
+                    UndoOp::Spent(outpoint, txout) => {
+                        self.add_value(txout.value);
+                        self.utxos.insert(outpoint, txout);
+                    }
+                }
+            }
+            if &hash == block_hash {
+                break;
+            }
+        }
+        true
+    }
+
+    // Purged blocks are normally never applied; any that were are rolled back together with their descendants
+    pub fn on_reorg(&mut self, purged: &[BlockHash]) {
+        let oldest_applied = self
+            .undo_blocks
+            .iter()
+            .map(|(hash, _)| *hash)
+            .find(|hash| purged.contains(hash));
+        if let Some(hash) = oldest_applied {
+            warn!(
+                "rolling back the UTXO set to before purged block {:?}",
+                hash
+            );
+            self.rollback_to_before(&hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::absolute::LockTime;
+    use bitcoin::constants::genesis_block;
+    use bitcoin::transaction::Version;
+    use bitcoin::{Network, ScriptBuf, Sequence, Transaction, TxIn, Witness};
+
+    fn create_tx(inputs: Vec<OutPoint>, values: &[u64]) -> Transaction {
+        Transaction {
+            version: Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: inputs
+                .into_iter()
+                .map(|previous_output| TxIn {
+                    previous_output,
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: values
+                .iter()
+                .map(|value| TxOut {
+                    value: Amount::from_sat(*value),
+                    script_pubkey: ScriptBuf::new(),
+                })
+                .collect(),
+        }
+    }
+
+    fn create_block(prev_block: &Block, txdata: Vec<Transaction>) -> Block {
+        let mut block = prev_block.clone();
+        block.header.prev_blockhash = prev_block.block_hash();
+        block.txdata = txdata;
+        block
+    }
+
+    #[test]
+    fn test_utxo_tracker() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut tracker = UtxoTracker::new();
+        tracker.apply_block(&genesis, 0);
+        assert!(tracker.is_empty());
+
+        // the coinbase input has a null previous output
+        let coinbase = create_tx(vec![OutPoint::null()], &[50]);
+        let block1 = create_block(&genesis, vec![coinbase.clone()]);
+        tracker.apply_block(&block1, 1);
+        let coinbase_outpoint = OutPoint::new(coinbase.compute_txid(), 0);
+        assert_eq!(tracker.get(&coinbase_outpoint).unwrap().value.to_sat(), 50);
+
+        let coinbase2 = create_tx(vec![OutPoint::null()], &[50, 1]);
+        let spend = create_tx(vec![coinbase_outpoint], &[30, 20]);
+        // spends an output created earlier in the same block
+        let spend2 = create_tx(vec![OutPoint::new(spend.compute_txid(), 0)], &[25]);
+        let block2 = create_block(&block1, vec![coinbase2, spend.clone(), spend2]);
+        // 50 spent, 20 + 25 left
+        assert_eq!(tracker.apply_block(&block2, 2), Some(Amount::from_sat(5)));
+        assert_eq!(tracker.len(), 4);
+        assert_eq!(tracker.total_value().to_sat(), 96);
+        assert!(tracker.get(&coinbase_outpoint).is_none());
+        assert_eq!(
+            tracker
+                .get(&OutPoint::new(spend.compute_txid(), 1))
+                .unwrap()
+                .value
+                .to_sat(),
+            20
+        );
+
+        tracker.on_reorg(&[block2.block_hash()]);
+        assert_eq!(tracker.len(), 1);
+        assert_eq!(tracker.total_value().to_sat(), 50);
+        assert!(tracker.get(&coinbase_outpoint).is_some());
+        assert!(!tracker.rollback_to_before(&block2.block_hash()));
+    }
+
+    // BIP30: a later coinbase with the txid of an earlier one overwrites its outputs, the earlier ones are lost
+    #[test]
+    fn test_duplicate_coinbase() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut tracker = UtxoTracker::new();
+        tracker.apply_block(&genesis, 0);
+        let coinbase = create_tx(vec![OutPoint::null()], &[50]);
+        let block1 = create_block(&genesis, vec![coinbase.clone()]);
+        let block2 = create_block(&block1, vec![create_tx(vec![OutPoint::null()], &[40])]);
+        let block3 = create_block(&block2, vec![coinbase.clone()]);
+        tracker.apply_block(&block1, 1);
+        tracker.apply_block(&block2, 2);
+        assert_eq!(tracker.apply_block(&block3, 3), Some(Amount::ZERO));
+        assert_eq!(tracker.len(), 2);
+        assert_eq!(tracker.total_value().to_sat(), 90);
+
+        // rolling back the duplicate brings the overwritten output back
+        assert!(tracker.rollback_to_before(&block3.block_hash()));
+        assert_eq!(tracker.len(), 2);
+        assert_eq!(tracker.total_value().to_sat(), 90);
+        assert!(tracker
+            .get(&OutPoint::new(coinbase.compute_txid(), 0))
+            .is_some());
+        assert!(tracker.rollback_to_before(&block1.block_hash()));
+        assert_eq!(tracker.len(), 0);
+        assert_eq!(tracker.total_value().to_sat(), 0);
+    }
+
+    // outputs that a decodable block can have and no valid one does
+    #[test]
+    fn test_value_overflow() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let mut tracker = UtxoTracker::new();
+        tracker.apply_block(&genesis, 0);
+        let coinbase = create_tx(vec![OutPoint::null()], &[u64::MAX, u64::MAX]);
+        let block1 = create_block(&genesis, vec![coinbase.clone()]);
+        tracker.apply_block(&block1, 1);
+        assert_eq!(tracker.len(), 2);
+        assert_eq!(tracker.total_value(), Amount::MAX);
+
+        let spend = create_tx(
+            vec![
+                OutPoint::new(coinbase.compute_txid(), 0),
+                OutPoint::new(coinbase.compute_txid(), 1),
+            ],
+            &[1],
+        );
+        let block2 = create_block(
+            &block1,
+            vec![create_tx(vec![OutPoint::null()], &[50]), spend],
+        );
+        assert_eq!(tracker.apply_block(&block2, 2), None);
+        assert_eq!(tracker.len(), 2);
+        assert!(tracker.rollback_to_before(&block2.block_hash()));
+        assert_eq!(tracker.len(), 2);
+    }
+
+    // Blocks 1 to 206 of a regtest chain mined by bitcoin core, with legacy and segwit spends,
+    // from the tests of the bitcoinkernel crate (MIT). The counts were computed with a decoder of its own
+    #[test]
+    fn test_regtest_chain() {
+        use bitcoin::consensus::deserialize;
+
+        let mut tracker = UtxoTracker::new();
+        tracker.apply_block(&genesis_block(Network::Regtest), 0);
+        let mut fees = Amount::ZERO;
+        let mut spend_cnt = 0;
+        for (i, line) in include_str!("../tests/data/regtest_blocks.txt")
+            .lines()
+            .enumerate()
+        {
+            let block: Block = deserialize(&hex::decode(line).unwrap()).unwrap();
+            spend_cnt += block.txdata.len() - 1;
+            // every output spent was created by an earlier block or transaction
+            fees += tracker.apply_block(&block, i as u64 + 1).unwrap();
+        }
+        assert_eq!(spend_cnt, 25);
+        assert_eq!(tracker.len(), 230);
+        assert_eq!(tracker.total_value(), Amount::from_sat(887_500_000_000));
+        assert_eq!(fees, Amount::from_sat(3616));
+    }
+}
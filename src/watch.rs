@@ -0,0 +1,108 @@
+use crate::importer::{ImportedBlock, Importer};
+use crate::logging::error;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+#[cfg(not(feature = "notify"))]
+use std::time::Duration;
+
+// how often the directory is checked where there are no filesystem events
+#[cfg(not(feature = "notify"))]
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Wakes the receiver whenever a file in dir is created or written to, e.g. a blk file a node appends a block to.
+/// Events that arrive before the receiver handles the last wakeup are coalesced into it, so none is lost:
+/// at most one wakeup is queued, and reading the files after it sees all the changes.
+/// With the notify feature the events come from the platform's file watcher, without it the receiver is woken every second.
+pub fn dir_events(dir_path: impl AsRef<Path>) -> std::io::Result<mpsc::Receiver<()>> {
+    let (sender, receiver) = mpsc::sync_channel(1);
+    spawn_watcher(dir_path.as_ref(), sender)?;
+    Ok(receiver)
+}
+
+/// Imports the blocks of dir as in the batch scan, then keeps importing the blocks a running node adds to it,
+/// never finishing unless reading fails. Imported blocks are sent to the returned receiver in chain order.
+pub fn watch_dir(dir_path: impl Into<PathBuf>) -> std::io::Result<mpsc::Receiver<ImportedBlock>> {
+    let dir_path = dir_path.into();
+    // the watch starts before the scan, so the blocks added during the scan are not missed
+    let events = dir_events(&dir_path)?;
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut importer = Importer::new_with_sink(&dir_path, 0, sender);
+        if let Err(err) = importer.follow_events(&events) {
+            error!(
+                "blk{:05}.dat: {}, stopped following",
+                importer.file_num(),
+                err
+            );
+        }
+    });
+    Ok(receiver)
+}
+
+// a full channel already holds a wakeup, which covers this event too
+fn wake(sender: &mpsc::SyncSender<()>) -> bool {
+    !matches!(
+        sender.try_send(()),
+        Err(mpsc::TrySendError::Disconnected(()))
+    )
+}
+
+#[cfg(feature = "notify")]
+fn spawn_watcher(dir_path: &Path, sender: mpsc::SyncSender<()>) -> std::io::Result<()> {
+    use notify::{EventKind, RecursiveMode, Watcher};
+
+    let (event_sender, event_receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(event_sender).map_err(std::io::Error::other)?;
+    watcher
+        .watch(dir_path, RecursiveMode::NonRecursive)
+        .map_err(std::io::Error::other)?;
+    thread::spawn(move || {
+        // the watcher stops when the thread ends, after the receiver is dropped
+        let _watcher = watcher;
+        for event in event_receiver {
+            // only that something changed matters, not which file; an error may be a lost event
+            let changed = event.map_or(true, |event| {
+                matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+            });
+            if changed && !wake(&sender) {
+                break;
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(feature = "notify"))]
+fn spawn_watcher(dir_path: &Path, sender: mpsc::SyncSender<()>) -> std::io::Result<()> {
+    if !dir_path.is_dir() {
+        return Err(std::io::ErrorKind::NotFound.into());
+    }
+    thread::spawn(move || {
+        while wake(&sender) {
+            thread::sleep(POLL_INTERVAL);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_dir_events() {
+        let dir = std::env::temp_dir().join(format!("read-blk-watch-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let events = dir_events(&dir).unwrap();
+        std::fs::write(dir.join("blk00000.dat"), b"block").unwrap();
+        std::fs::write(dir.join("blk00000.dat"), b"another block").unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert!(events.recv_timeout(Duration::from_secs(5)).is_ok());
+        // the events of both writes were coalesced into one wakeup
+        assert!(events.try_recv().is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
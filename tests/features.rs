@@ -0,0 +1,65 @@
+use std::path::Path;
+use std::process::Command;
+
+const FEATURES: [&str; 14] = [
+    "cli",
+    "log",
+    "mmap",
+    "notify",
+    "serde",
+    "tar",
+    "zstd",
+    "leveldb",
+    "fast-hash",
+    "http",
+    "async",
+    "rayon",
+    "sqlite",
+    "metrics",
+];
+
+// the library without features depends on these crates only
+const CORE_DEPENDENCIES: [&str; 2] = ["bitcoin", "hex"];
+
+#[test]
+fn test_core_dependencies() {
+    let output = Command::new(env!("CARGO"))
+        .args(["tree", "--no-default-features", "--edges", "normal"])
+        .args(["--depth", "1", "--prefix", "none"])
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let tree = String::from_utf8(output.stdout).unwrap();
+    // the first line is read-blk itself
+    let dependencies: Vec<_> = tree
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().next())
+        .collect();
+    assert_eq!(dependencies, CORE_DEPENDENCIES);
+}
+
+// cargo check of the library and binary with no features, each feature on its own and all of them.
+// Slow, so it's ignored, CI runs it: cargo test --test features -- --ignored
+#[test]
+#[ignore]
+fn test_feature_matrix() {
+    // a target dir of its own, so the checks don't wait on the lock of the one running the tests
+    let target_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("features");
+    let all_features = FEATURES.join(",");
+    let feature_sets = [""]
+        .into_iter()
+        .chain(FEATURES)
+        .chain([all_features.as_str()]);
+    for features in feature_sets {
+        let status = Command::new(env!("CARGO"))
+            .args(["check", "--all-targets", "--no-default-features"])
+            .args(["--features", features])
+            .env("CARGO_TARGET_DIR", &target_dir)
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .status()
+            .unwrap();
+        assert!(status.success(), "features [{}] don't build", features);
+    }
+}
@@ -0,0 +1,92 @@
+use read_blk::bitcoin::consensus::serialize;
+use read_blk::bitcoin::constants::genesis_block;
+#[cfg(feature = "cli")]
+use read_blk::bitcoin::hashes::Hash;
+use read_blk::prelude::*;
+#[cfg(feature = "cli")]
+use std::path::PathBuf;
+#[cfg(feature = "cli")]
+use std::process::{Command, Output};
+
+fn block_frame(block: &Block) -> Vec<u8> {
+    let bytes = serialize(block);
+    let mut frame = Network::Bitcoin.magic().to_bytes().to_vec();
+    frame.extend((bytes.len() as u32).to_le_bytes());
+    frame.extend(bytes);
+    frame
+}
+
+// a blocks directory of its own with file_bytes as blk00000.dat
+#[cfg(feature = "cli")]
+fn blocks_dir(name: &str, file_bytes: &[u8]) -> PathBuf {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("blk00000.dat"), file_bytes).unwrap();
+    dir
+}
+
+#[cfg(feature = "cli")]
+fn read_blk(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_read-blk"))
+        .args(args)
+        .env("RUST_LOG", "info")
+        .output()
+        .unwrap()
+}
+
+// the library imports blk file bytes the way the binary does
+#[test]
+fn test_import() {
+    let genesis = genesis_block(Network::Bitcoin);
+    let mut next_block = genesis.clone();
+    next_block.header.prev_blockhash = genesis.block_hash();
+    let mut file_bytes = block_frame(&genesis);
+    file_bytes.extend(block_frame(&next_block));
+
+    let mut importer = Importer::new("", 0);
+    importer.read_blocks(file_bytes).unwrap();
+    importer.drain_ready(0).unwrap();
+    assert_eq!(importer.tip(), Some((next_block.block_hash(), 1)));
+    assert!(importer.completion_report().is_complete());
+}
+
+// a read error fails the run, even though the blocks read before it could be imported
+#[cfg(feature = "cli")]
+#[test]
+fn test_read_error_exit_code() {
+    let mut file_bytes = b"garbage!".to_vec();
+    file_bytes.extend(block_frame(&genesis_block(Network::Bitcoin)));
+    let dir = blocks_dir("read-error", &file_bytes);
+
+    let output = read_blk(&["--dir", dir.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("stopped reading"));
+}
+
+// --base-height is the height of the block before the first one read, with or without --base-hash
+#[cfg(feature = "cli")]
+#[test]
+fn test_base_height() {
+    let genesis = genesis_block(Network::Bitcoin);
+    // the first block read is the child of a block not in the files
+    let mut first_block = genesis.clone();
+    first_block.header.prev_blockhash = BlockHash::from_byte_array([7; 32]);
+    let mut next_block = genesis.clone();
+    next_block.header.prev_blockhash = first_block.block_hash();
+    let mut file_bytes = block_frame(&first_block);
+    file_bytes.extend(block_frame(&next_block));
+    let dir = blocks_dir("base-height", &file_bytes);
+    let expected_tip = format!("tip {} height 102", next_block.block_hash());
+
+    let base_hash = first_block.header.prev_blockhash.to_string();
+    for base_args in [
+        vec!["--base-height", "100"],
+        vec!["--base-height", "100", "--base-hash", &base_hash],
+    ] {
+        let mut args = vec!["--dir", dir.to_str().unwrap()];
+        args.extend(base_args);
+        let output = read_blk(&args);
+        assert_eq!(output.status.code(), Some(0));
+        assert!(String::from_utf8_lossy(&output.stderr).contains(&expected_tip));
+    }
+}